@@ -0,0 +1,35 @@
+//! Hashes per second of the proof-of-work nonce loop.
+//!
+//! Run with `cargo bench --bench proof_of_work`.
+
+use std::time::Instant;
+
+use BlockChainRust::{BlockHeader, Decode, HeaderHasher};
+
+const ROUNDS: u32 = 2_000_000;
+
+fn main() {
+    // Version 1 header at height 1 with the easiest target
+    let mut bytes = vec![1u8];
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    bytes.extend_from_slice(&1_600_000_000u64.to_le_bytes());
+    bytes.extend_from_slice(&[0x22; 32]);
+    bytes.extend_from_slice(&[0x33; 32]);
+    bytes.extend_from_slice(&0x1f01_0000u32.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    let header = BlockHeader::from_bytes(&bytes).expect("valid header");
+
+    let mut hasher = HeaderHasher::new(&header);
+    let start = Instant::now();
+    let mut found = 0u32;
+    for nonce in 0..ROUNDS {
+        let hash = hasher.hash(nonce);
+        if hasher.meets_target(&hash) {
+            found += 1;
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!("{} hashes in {:.3}s: {:.0} hashes/s ({} below target)",
+             ROUNDS, elapsed, ROUNDS as f64 / elapsed, found);
+}
@@ -0,0 +1,190 @@
+//! Bech32 (BIP173): a human-readable prefix, the separator `1`, then 5-bit
+//! values and a checksum spelled with an alphabet free of look-alikes. The
+//! checksum is a BCH code, so besides detecting up to four wrong characters
+//! it can point at one or two of them.
+
+use std::collections::HashMap;
+use std::iter;
+
+use crate::utils::AddressError;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+const CHECKSUM_LEN: usize = 6;
+const MAX_LEN: usize = 90;
+
+fn polymod_from<I: IntoIterator<Item = u8>>(chk: u32, values: I) -> u32 {
+    values.into_iter().fold(chk, |chk, value| {
+        let top = chk >> 25;
+        let chk = ((chk & 0x1ffffff) << 5) ^ value as u32;
+        GENERATOR.iter().enumerate()
+            .filter(|(i, _)| (top >> i) & 1 == 1)
+            .fold(chk, |chk, (_, g)| chk ^ g)
+    })
+}
+
+/// Residue of `hrp` and `values` with their checksum, 0 when it matches.
+fn residue(hrp: &str, values: &[u8]) -> u32 {
+    let expanded = hrp.bytes().map(|b| b >> 5)
+        .chain(iter::once(0))
+        .chain(hrp.bytes().map(|b| b & 31));
+    polymod_from(1, expanded.chain(values.iter().copied())) ^ 1
+}
+
+/// `hrp`, the separator and the 5-bit `data` followed by their checksum.
+pub fn encode(hrp: &str, data: &[u8]) -> String {
+    let mut values = data.to_vec();
+    values.extend_from_slice(&[0; CHECKSUM_LEN]);
+    let residue = residue(hrp, &values);
+    for (i, value) in values[data.len()..].iter_mut().enumerate() {
+        *value = ((residue >> (5 * (CHECKSUM_LEN - 1 - i))) & 31) as u8;
+    }
+    let mut text = format!("{}1", hrp);
+    text.extend(values.iter().map(|value| CHARSET[*value as usize] as char));
+    text
+}
+
+/// The lowercase prefix and the 5-bit data of `text`, without the checksum.
+pub fn decode(text: &str) -> Result<(String, Vec<u8>), AddressError> {
+    if text.len() > MAX_LEN {
+        return Err(AddressError::Malformed(format!("longer than {} characters", MAX_LEN)));
+    }
+    if text.chars().any(|c| c.is_ascii_lowercase()) && text.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(AddressError::Malformed("mixes upper and lower case".to_string()));
+    }
+    let separator = text.rfind('1')
+        .ok_or_else(|| AddressError::Malformed("no separator '1'".to_string()))?;
+    if separator == 0 || text.len() - separator - 1 < CHECKSUM_LEN {
+        return Err(AddressError::Malformed("no prefix or no checksum around the separator".to_string()));
+    }
+    if let Some((position, character)) = text[..separator].char_indices().find(|(_, c)| !('!'..='~').contains(c)) {
+        return Err(AddressError::InvalidCharacter { position, character });
+    }
+    let hrp = text[..separator].to_ascii_lowercase();
+
+    let mut values = Vec::with_capacity(text.len() - separator - 1);
+    for (position, character) in text.char_indices().skip(separator + 1) {
+        let value = CHARSET.iter().position(|c| *c as char == character.to_ascii_lowercase())
+            .ok_or(AddressError::InvalidCharacter { position, character })?;
+        values.push(value as u8);
+    }
+    let residue = residue(&hrp, &values);
+    if residue != 0 {
+        let typos = locate_typos(residue, values.len()).into_iter()
+            .map(|i| separator + 1 + i)
+            .collect();
+        return Err(AddressError::BadChecksum { typos });
+    }
+    values.truncate(values.len() - CHECKSUM_LEN);
+    Ok((hrp, values))
+}
+
+/// Positions among `len` data values of one or two wrong values that give
+/// `residue`, empty if there are more.
+///
+/// Changing the value at `i` by `e` adds the same amount to the residue
+/// whatever the other values, so the errors are found by matching the
+/// residue against the amount of every single change, then of every pair.
+fn locate_typos(residue: u32, len: usize) -> Vec<usize> {
+    let changes: Vec<(usize, u32)> = (0..len)
+        .flat_map(|i| (1..32u8).map(move |e| {
+            (i, polymod_from(0, iter::once(e).chain(iter::repeat(0)).take(len - i)))
+        }))
+        .collect();
+    if let Some((i, _)) = changes.iter().find(|(_, change)| *change == residue) {
+        return vec![*i];
+    }
+    let by_change: HashMap<u32, usize> = changes.iter().map(|(i, change)| (*change, *i)).collect();
+    for (i, change) in &changes {
+        match by_change.get(&(residue ^ change)) {
+            Some(j) if j > i => return vec![*i, *j],
+            _ => {},
+        }
+    }
+    vec![]
+}
+
+/// `data` regrouped from `from`-bit into `to`-bit values. Padding the last
+/// value with zero bits is needed to go to a larger group, and refused going
+/// back unless `pad`.
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let mut converted = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+    for value in data {
+        if (*value as u32) >> from != 0 {
+            return None;
+        }
+        acc = (acc << from) | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((acc >> bits) & ((1 << to) - 1)) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            converted.push(((acc << (to - bits)) & ((1 << to) - 1)) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits)) & ((1 << to) - 1) != 0 {
+        return None;
+    }
+    Some(converted)
+}
+
+/// `bytes` as 5-bit values.
+pub fn to_base32(bytes: &[u8]) -> Vec<u8> {
+    convert_bits(bytes, 8, 5, true).unwrap_or_default()
+}
+
+/// The bytes of the 5-bit `values`, if their padding is valid.
+pub fn from_base32(values: &[u8]) -> Option<Vec<u8>> {
+    convert_bits(values, 5, 8, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bip173_vectors() {
+        for valid in &["A12UEL5L", "a12uel5l", "abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw",
+                       "split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w"] {
+            let (hrp, data) = decode(valid).unwrap();
+            assert_eq!(encode(&hrp, &data), valid.to_lowercase());
+        }
+
+        assert!(matches!(decode("pzry9x0s0muk"), Err(AddressError::Malformed(_))));
+        assert!(matches!(decode("1pzry9x0s0muk"), Err(AddressError::Malformed(_))));
+        assert!(matches!(decode("li1dgmt3"), Err(AddressError::Malformed(_))));
+        assert!(matches!(decode("A1G7SGD8"), Err(AddressError::BadChecksum { .. })));
+        assert!(matches!(decode("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sL5k7"),
+                         Err(AddressError::Malformed(_))));
+        assert_eq!(decode("x1b4n0q5v"), Err(AddressError::InvalidCharacter { position: 2, character: 'b' }));
+    }
+
+    #[test]
+    fn test_locate_typos() {
+        let address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        let (_, data) = decode(address).unwrap();
+        assert_eq!(from_base32(&data[1..]).unwrap(),
+                   hex::decode("751e76e8199196d454941c45d1b3a323f1433bd6").unwrap());
+
+        let mut typo = address.to_string();
+        typo.replace_range(10..11, "p");
+        assert_eq!(decode(&typo), Err(AddressError::BadChecksum { typos: vec![10] }));
+        typo.replace_range(30..31, "z");
+        assert_eq!(decode(&typo), Err(AddressError::BadChecksum { typos: vec![10, 30] }));
+        // In the checksum itself
+        typo = address.replace("t4", "tt");
+        assert_eq!(decode(&typo), Err(AddressError::BadChecksum { typos: vec![41] }));
+    }
+
+    #[test]
+    fn test_base32() {
+        let bytes = hex::decode("00ff10").unwrap();
+        assert_eq!(from_base32(&to_base32(&bytes)).unwrap(), bytes);
+        assert_eq!(from_base32(&[31, 31, 31]), None);
+        assert_eq!(from_base32(&[32]), None);
+    }
+}
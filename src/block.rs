@@ -1,52 +1,106 @@
-use openssl::sha;
 use serde::{ Serialize, Deserialize };
 
-use openssl::sha::sha256;
-use openssl::version::version;
-use std::collections::HashMap;
-use std::path::Display;
-use sled::open;
-
 use crate::transaction::*;
-use crate::wallet::Wallets;
-use crate::utils::*;
-use crate::consensus::*;
+use crate::merkle::*;
+use crate::miner::Miner;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Block {
+/// The part of a block that is hashed by `ProofOfWork`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockHeader {
+    pub(crate) height: u64,
     pub(crate) time_stamp: u64,
-    pub(crate) transaction: Vec<Transaction>,
     pub(crate) pre_block_hash: [u8; 32],
-    pub(crate) cur_block_hash: [u8; 32],
-    pub(crate) target_bits: u8,
+    pub(crate) merkle_root: [u8; 32],
+    /// Compact form of the target the header hash must be below.
+    pub(crate) bits: u32,
     pub(crate) nonce: u32,
 }
 
+impl BlockHeader {
+
+    /// Header of a block holding `transaction`, before any proof of work.
+    pub fn new(height: u64, time_stamp: u64, pre_block_hash: [u8; 32], bits: u32,
+               transaction: &[Transaction]) -> Self {
+        BlockHeader {
+            height,
+            time_stamp,
+            pre_block_hash,
+            merkle_root: Block::hash_transactions(transaction),
+            bits,
+            nonce: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    pub(crate) header: BlockHeader,
+    pub(crate) cur_block_hash: [u8; 32],
+    /// What the consensus engine requires on top of the header, such as the
+    /// signature of a signer. Empty under proof of work.
+    pub(crate) seal: Vec<u8>,
+    pub(crate) transaction: Vec<Transaction>,
+}
+
 impl Block {
 
-    pub fn genesis_block(coinbase: Transaction) -> Self {
-        Block::new_block(vec![coinbase], [0u8;32])
+    pub fn genesis_block(coinbase: Transaction, bits: u32, time_stamp: u64) -> Self {
+        Block::new_block(vec![coinbase], [0u8;32], 0, bits, time_stamp)
     }
 
-    pub fn new_block(transaction: Vec<Transaction>, pre_block_hash: [u8; 32]) -> Self {
-        let mut block = Block {
-            time_stamp: std::time::SystemTime::now().elapsed().unwrap().as_secs(),
+    /// Mines a block dated `time_stamp`, in seconds since the Unix epoch, on the current thread.
+    pub fn new_block(transaction: Vec<Transaction>, pre_block_hash: [u8; 32], height: u64,
+                     bits: u32, time_stamp: u64) -> Self {
+        let header = BlockHeader::new(height, time_stamp, pre_block_hash, bits, &transaction);
+        Block::mine(header, transaction, &Miner::default())
+            .expect("a miner nobody else holds is never cancelled")
+    }
+
+    /// Mines `header` with `miner`, or returns `None` if the miner is cancelled first.
+    pub fn mine(mut header: BlockHeader, mut transaction: Vec<Transaction>, miner: &Miner) -> Option<Self> {
+        let cur_block_hash = miner.mine(&mut header, &mut transaction)?;
+        Some(Block {
+            header,
+            cur_block_hash,
+            seal: vec![],
             transaction,
-            pre_block_hash,
-            cur_block_hash: [0;32],
-            target_bits: 16,
-            nonce: 0,
-        };
-        block.cur_block_hash = block.proof_of_work();
-        block
+        })
+    }
+
+    pub fn hash_transactions(transaction: &[Transaction]) -> [u8; 32] {
+        let tx_ids: Vec<&[u8]> = transaction.iter().map(|tx| &tx.id[..]).collect();
+        merkle_root(&tx_ids)
+    }
+
+    /// Proves that the transaction `tx_id` is committed to by the header of this block.
+    pub fn merkle_proof(&self, tx_id: &[u8]) -> Option<MerkleProof> {
+        let tx_ids: Vec<&[u8]> = self.transaction.iter().map(|tx| &tx.id[..]).collect();
+        let index = tx_ids.iter().position(|id| *id == tx_id)?;
+        MerkleProof::new(&tx_ids, index)
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
     }
 
     pub fn cur_block_hash(&self) -> String {
-        hex::encode(self.cur_block_hash.to_vec())
+        hex::encode(self.cur_block_hash)
+    }
+
+    pub fn height(&self) -> u64 {
+        self.header.height
+    }
+
+    pub fn time_stamp(&self) -> u64 {
+        self.header.time_stamp
     }
 
     pub fn pre_block_hash(&self) -> String {
-        hex::encode(self.pre_block_hash.to_vec())
+        hex::encode(self.header.pre_block_hash)
+    }
+
+    pub fn merkle_root(&self) -> String {
+        hex::encode(self.header.merkle_root)
     }
 
     pub fn transaction(&self) -> &Vec<Transaction> {
@@ -54,15 +108,58 @@ impl Block {
     }
 
     pub fn print(&self) {
+        println!("Height: {}", self.header.height);
+        println!("Time: {}", self.header.time_stamp);
         println!("Prev Hash: {:?}", self.pre_block_hash());
         println!("Curr Hash: {:?}", self.cur_block_hash());
+        println!("Merkle Root: {:?}", self.merkle_root());
         println!("Data: {:?}\n", self.transaction);
     }
 }
 
-impl ToString for Block {
-    fn to_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
+/// Gives `header` a fresh nonce space once every nonce has been tried.
+pub(crate) fn roll_extra_nonce(header: &mut BlockHeader, transaction: &mut [Transaction]) {
+    let coinbase = transaction.first_mut()
+        .and_then(|tx| tx.coinbase_extra_nonce().map(|extra_nonce| (tx, extra_nonce)));
+    match coinbase {
+        Some((coinbase, extra_nonce)) => {
+            coinbase.set_extra_nonce(extra_nonce.wrapping_add(1));
+            header.merkle_root = Block::hash_transactions(transaction);
+        },
+        None => header.time_stamp += 1,
     }
+    header.nonce = 0;
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn test_roll_extra_nonce() {
+        let address = Wallet::new().unwrap().get_address().unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&address, String::new(), 10, 3).unwrap();
+        let mut transaction = vec![coinbase];
+        let mut header = BlockHeader {
+            height: 3,
+            time_stamp: 100,
+            pre_block_hash: [0u8; 32],
+            merkle_root: Block::hash_transactions(&transaction),
+            bits: 0x1f010000,
+            nonce: u32::MAX,
+        };
+        let first_root = header.merkle_root;
+
+        roll_extra_nonce(&mut header, &mut transaction);
+        assert_eq!(transaction[0].coinbase_extra_nonce(), Some(1));
+        assert_eq!(transaction[0].coinbase_height(), Some(3));
+        assert_ne!(header.merkle_root, first_root);
+        assert_eq!(header.merkle_root, Block::hash_transactions(&transaction));
+        assert_eq!((header.time_stamp, header.nonce), (100, 0));
+
+        let mut no_coinbase = Vec::new();
+        roll_extra_nonce(&mut header, &mut no_coinbase);
+        assert_eq!(header.time_stamp, 101);
+    }
+}
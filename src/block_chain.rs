@@ -1,84 +1,696 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
-use crate::block::Block;
+use bigint::uint::U256;
+use serde::{ Serialize, Deserialize };
+use sled::Transactional;
+
+use crate::block::{Block, BlockHeader};
+use crate::chain_params::{chain_params, data_path, open_database, ChainParams};
+use crate::consensus::*;
+use crate::encoding::{Decode, Encode, ENCODING_VERSION};
+use crate::engine::{ConsensusEngine, EngineSpec, ProofOfWorkEngine};
+use crate::error::{Error, Result};
+use crate::merkle::MerkleProof;
+use crate::miner::Miner;
 use crate::transaction::*;
+use crate::validation::*;
+
+const BLOCKCHAIN_DB: &str = "block_chain.db";
+const HEIGHT_INDEX: &str = "height";
+const INDEX_TREE: &str = "index";
+const TIPS_TREE: &str = "tips";
+/// Key of the encoding version the blocks are stored in.
+const FORMAT_KEY: &str = "format";
 
-const blockchain_db: &str = "block_chain.db";
-const genesis_coinbase_data: &str = "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+/// Position and accumulated work of a stored block, on the main chain or not.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockIndex {
+    pub height: u64,
+    /// Big-endian total work of the chain ending at this block.
+    pub chain_work: [u8; 32],
+    /// Set when the transactions of the block failed to connect.
+    pub invalid: bool,
+}
+
+impl BlockIndex {
+    pub fn chain_work(&self) -> U256 {
+        U256::from(&self.chain_work[..])
+    }
+}
+
+/// How a block changed the chain when it was added.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainEvent {
+    /// The block was already stored.
+    Known { hash: [u8; 32] },
+    /// The block extended the main chain.
+    Connected { hash: [u8; 32], height: u64 },
+    /// The block was stored on a branch with less work than the main chain.
+    SideChain { hash: [u8; 32], height: u64 },
+    /// A branch with more work replaced the top of the main chain. Both lists
+    /// are in the order the blocks were disconnected or connected.
+    Reorganized { fork_height: Option<u64>, disconnected: Vec<[u8; 32]>, connected: Vec<[u8; 32]> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TipStatus {
+    /// The tip of the main chain.
+    Active,
+    /// A branch that could become the main chain if it gains the most work.
+    Fork,
+    /// A branch containing a block that failed to connect.
+    Invalid,
+}
+
+impl std::fmt::Display for TipStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TipStatus::Active => write!(f, "active"),
+            TipStatus::Fork => write!(f, "fork"),
+            TipStatus::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
+/// The last block of a known branch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainTip {
+    pub hash: [u8; 32],
+    pub height: u64,
+    /// Number of blocks of the branch that are not on the main chain.
+    pub branch_len: u64,
+    pub status: TipStatus,
+}
 
 pub struct BlockChain {
     pub(crate) tip: [u8; 32],
     pub(crate) db: sled::Db,
+    /// Block hash of every height of the main chain.
+    heights: sled::Tree,
+    /// `BlockIndex` of every stored block.
+    index: sled::Tree,
+    /// Stored blocks without children.
+    tips: sled::Tree,
+    params: ChainParams,
+    coinbase_maturity: u64,
+    clock: Arc<dyn Clock>,
+    miner: Miner,
+    /// Engine recorded in the genesis block, proof of work for an empty chain.
+    engine: Arc<dyn ConsensusEngine>,
 }
 
 impl BlockChain {
-    pub fn create_blockchain(address: &str) -> Option<Self> {
-        if std::path::Path::new(blockchain_db).exists() {
-            println!("BlockChain already exists.");
-            return None;
+    /// Creates a chain run by the engine `spec`, paying the genesis reward to `address`.
+    pub fn create_blockchain(address: &str, spec: &EngineSpec) -> Result<Self> {
+        if data_path(BLOCKCHAIN_DB).exists() {
+            return Err(Error::BlockChainExists);
         }
 
-        let genesis_tx = Transaction::new_coinbase_tx(address, genesis_coinbase_data.to_string());
-        let genesis_block = Block::genesis_block(genesis_tx);
+        let mut bc = BlockChain::open()?;
+        let genesis_tx = Transaction::new_coinbase_with_data(
+            address, spec.genesis_data(&bc.params.genesis_message), bc.params.block_subsidy(0), 0)?;
+        bc.engine = spec.engine()?;
+        bc.push_block(vec![genesis_tx])?;
+        Ok(bc)
+    }
+
+    pub fn new_block_chain() -> Result<Self> {
+        if !data_path(BLOCKCHAIN_DB).exists() {
+            return Err(Error::BlockChainNotFound);
+        }
+
+        let bc = BlockChain::open()?;
+        if bc.tip == [0u8; 32] {
+            return Err(Error::Corrupted("missing tip of the chain".to_string()));
+        }
+        Ok(bc)
+    }
 
-        let db = sled::open(blockchain_db).unwrap();
-        db.insert(genesis_block.cur_block_hash, &genesis_block.to_string()[..]);
-        db.insert("last", &genesis_block.cur_block_hash);
+    /// Opens the chain database, creating an empty chain when none exists.
+    /// Nodes use this to sync the whole chain, genesis included, from peers.
+    pub fn load() -> Result<Self> {
+        BlockChain::open()
+    }
 
-        let tip = genesis_block.cur_block_hash;
+    fn open() -> Result<Self> {
+        let db = open_database(BLOCKCHAIN_DB)?;
+        BlockChain::check_format(&db)?;
+        let heights = db.open_tree(HEIGHT_INDEX)?;
+        let index = db.open_tree(INDEX_TREE)?;
+        let tips = db.open_tree(TIPS_TREE)?;
+        let tip = match db.get("last")? {
+            Some(_) => BlockChain::last_hash(&db)?,
+            None => [0u8; 32],
+        };
 
-        Some(BlockChain {
+        let mut bc = BlockChain {
             tip,
-            db
-        })
+            db,
+            heights,
+            index,
+            tips,
+            params: chain_params().clone(),
+            coinbase_maturity: DEFAULT_COINBASE_MATURITY,
+            clock: Arc::new(SystemClock),
+            miner: Miner::default(),
+            engine: Arc::new(ProofOfWorkEngine),
+        };
+        if bc.tip != [0u8; 32] && bc.index.is_empty() {
+            bc.reindex_blocks()?;
+        }
+        if let Some(genesis) = bc.get_block_by_height(0)? {
+            bc.engine = EngineSpec::from_genesis(&genesis)?.engine()?;
+        }
+        Ok(bc)
     }
 
-    pub fn new_block_chain() -> Option<Self> {
-        if !std::path::Path::new(blockchain_db).exists() {
-            println!("No existing blockchain found, please create one first!!!");
-            return None;
+    /// Rebuilds the block index of a chain stored without one.
+    fn reindex_blocks(&self) -> Result<()> {
+        let mut chain_work = U256::zero();
+        for bc in self.iter_from(0) {
+            let bc = bc?;
+            chain_work = chain_work + block_work(bc.header.bits);
+            let index = BlockIndex {
+                height: bc.header.height,
+                chain_work: work_bytes(chain_work),
+                invalid: false,
+            };
+            self.index.insert(&bc.cur_block_hash[..], serde_json::to_vec(&index)?)?;
         }
+        self.tips.clear()?;
+        self.tips.insert(&self.tip[..], &[][..])?;
+        self.db.flush()?;
+        Ok(())
+    }
 
-        let db = sled::open(blockchain_db).unwrap();
-        let hash = db.get("last").unwrap().unwrap().to_vec();
-        let last_hash = hash.as_slice();
+    /// Parameters of the network the chain belongs to.
+    pub fn chain_params(&self) -> &ChainParams {
+        &self.params
+    }
 
-        let mut tip = [0u8; 32];
-        tip.copy_from_slice(last_hash);
+    pub fn retarget_params(&self) -> &RetargetParams {
+        &self.params.retarget
+    }
 
-        Some(BlockChain {
-            tip,
-            db,
-        })
+    pub fn set_retarget_params(&mut self, params: RetargetParams) {
+        self.params.retarget = params;
     }
 
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) {
-        //TODO: Verify the transaction before mine block!
+    /// Number of blocks a coinbase needs on top of its own before its outputs
+    /// can be spent.
+    pub fn coinbase_maturity(&self) -> u64 {
+        self.coinbase_maturity
+    }
+
+    pub fn set_coinbase_maturity(&mut self, coinbase_maturity: u64) {
+        self.coinbase_maturity = coinbase_maturity;
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Replaces the clock blocks are dated and checked with.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
 
-        let mut pre_block_hash: [u8; 32] = [0u8;32];
-        pre_block_hash.copy_from_slice(&self.db.get("last").unwrap().unwrap().to_vec()[..]);
-        let new_block = Block::new_block(transactions, pre_block_hash);
-        self.db.insert(new_block.cur_block_hash, &new_block.to_string()[..]);
-        self.db.insert("last", &new_block.cur_block_hash);
-        println!("{}", hex::encode(new_block.cur_block_hash));
+    pub fn miner(&self) -> &Miner {
+        &self.miner
     }
 
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TXOutput> {
-        let mut utxo = Vec::<TXOutput>::new();
-        let unspent_txs = self.find_unspent_transaction(pub_key_hash);
+    /// Replaces the miner new blocks are mined with.
+    pub fn set_miner(&mut self, miner: Miner) {
+        self.miner = miner;
+    }
+
+    pub fn engine(&self) -> Arc<dyn ConsensusEngine> {
+        self.engine.clone()
+    }
+
+    /// Height, target and minimum time of the next block on top of the tip.
+    pub fn next_header(&self) -> Result<NextHeader> {
+        self.next_header_on(&self.tip)
+    }
+
+    /// Height, target and minimum time of a block on top of `parent`, which
+    /// may be on any branch.
+    pub fn next_header_on(&self, parent: &[u8; 32]) -> Result<NextHeader> {
+        let depth = MEDIAN_TIME_SPAN.max(self.params.retarget.interval as usize);
+        let mut recent = Vec::new();
+        let mut cur_hash = *parent;
+        while cur_hash != [0u8; 32] && recent.len() < depth {
+            let header = self.get_block(&cur_hash)?.header;
+            cur_hash = header.pre_block_hash;
+            recent.push(header);
+        }
+        recent.reverse();
+
+        let height = recent.last().map_or(0, |parent| parent.height + 1);
+        let interval = self.params.retarget.interval;
+        let first = if interval > 0 && height > 0 && height.is_multiple_of(interval) {
+            recent.iter().find(|header| header.height == height - interval)
+        } else {
+            None
+        };
+        let start = recent.len().saturating_sub(MEDIAN_TIME_SPAN);
+        Ok(NextHeader::new(self.engine.as_ref(), &self.params.retarget, &recent[start..], first))
+    }
+
+    /// Refuses a database whose blocks are stored in another encoding, and
+    /// records the current one in a new database.
+    fn check_format(db: &sled::Db) -> Result<()> {
+        let stored = db.get(FORMAT_KEY)?;
+        let found = match &stored {
+            Some(format) => format.first().copied(),
+            // Chains stored before the format was recorded are current if their tip decodes
+            None => {
+                let current = match db.get("last")? {
+                    Some(tip) => db.get(&tip)?.is_some_and(|data| Block::from_bytes(&data).is_ok()),
+                    None => true,
+                };
+                current.then_some(ENCODING_VERSION)
+            },
+        };
+        if found != Some(ENCODING_VERSION) {
+            let found = found.map_or("unknown".to_string(), |version| version.to_string());
+            return Err(Error::IncompatibleDatabase {
+                path: data_path(BLOCKCHAIN_DB).display().to_string(),
+                reason: format!("storage format {}, expected {}", found, ENCODING_VERSION),
+            });
+        }
+
+        if stored.is_none() {
+            db.insert(FORMAT_KEY, &[ENCODING_VERSION][..])?;
+            db.flush()?;
+        }
+        Ok(())
+    }
+
+    fn last_hash(db: &sled::Db) -> Result<[u8; 32]> {
+        let hash = db.get("last")?
+            .ok_or_else(|| Error::Corrupted("missing tip of the chain".to_string()))?;
+        if hash.len() != 32 {
+            return Err(Error::Corrupted("invalid tip of the chain".to_string()));
+        }
+
+        let mut last_hash = [0u8; 32];
+        last_hash.copy_from_slice(&hash);
+        Ok(last_hash)
+    }
+
+    /// Mines `transactions` on top of the tip without validating them. Apart
+    /// from the genesis block, blocks must come through
+    /// [`UTXOSet::mine_block`](crate::utxo::UTXOSet::mine_block) or
+    /// [`UTXOSet::add_block`](crate::utxo::UTXOSet::add_block), which check
+    /// the transactions against the unspent outputs first.
+    pub(crate) fn push_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        let header = self.header_template(&transactions)?;
+        let new_block = self.engine.seal(header, transactions, &self.miner)?
+            .ok_or(Error::MiningCancelled)?;
+        self.store_block(&new_block)?;
+        Ok(new_block)
+    }
+
+    /// Header of a block holding `transactions` on top of the tip, ready to be mined.
+    pub fn header_template(&self, transactions: &[Transaction]) -> Result<BlockHeader> {
+        let next = self.next_header()?;
+        Ok(BlockHeader::new(next.height, next.time_stamp(self.clock.now()), self.tip, next.bits,
+                            transactions))
+    }
 
-        for tx in unspent_txs {
-            for out in tx.vout {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxo.push(out);
+    /// Writes `block`, which must extend the tip, and makes it the new tip.
+    pub(crate) fn store_block(&mut self, block: &Block) -> Result<()> {
+        self.insert_block(block)?;
+        self.connect_block(block)
+    }
+
+    /// Writes `block` and its index entry without changing the main chain.
+    pub(crate) fn insert_block(&self, block: &Block) -> Result<BlockIndex> {
+        // Children of an invalid block are invalid too.
+        let (parent_work, invalid) = match block.header.pre_block_hash {
+            hash if hash == [0u8; 32] => (U256::zero(), false),
+            hash => {
+                let parent = self.index_of(&hash)?;
+                (parent.chain_work(), parent.invalid)
+            },
+        };
+        let index = BlockIndex {
+            height: block.header.height,
+            chain_work: work_bytes(parent_work + block_work(block.header.bits)),
+            invalid,
+        };
+
+        let data = block.to_bytes();
+        let entry = serde_json::to_vec(&index)?;
+        let blocks: &sled::Tree = &self.db;
+        let res: sled::transaction::TransactionResult<()> = (blocks, &self.index, &self.tips)
+            .transaction(|(blocks, index, tips)| {
+                blocks.insert(&block.cur_block_hash[..], data.clone())?;
+                index.insert(&block.cur_block_hash[..], entry.clone())?;
+                tips.remove(&block.header.pre_block_hash[..])?;
+                tips.insert(&block.cur_block_hash[..], &[][..])?;
+                Ok(())
+            });
+        res?;
+        self.db.flush()?;
+        Ok(index)
+    }
+
+    /// Makes the stored `block`, a child of the tip, the new tip.
+    pub(crate) fn connect_block(&mut self, block: &Block) -> Result<()> {
+        if block.header.pre_block_hash != self.tip {
+            return Err(Error::Corrupted(format!("block {} does not extend the tip", block.cur_block_hash())));
+        }
+
+        let blocks: &sled::Tree = &self.db;
+        let res: sled::transaction::TransactionResult<()> = (blocks, &self.heights).transaction(|(blocks, heights)| {
+            blocks.insert("last", &block.cur_block_hash[..])?;
+            heights.insert(&block.header.height.to_be_bytes()[..], &block.cur_block_hash[..])?;
+            Ok(())
+        });
+        res?;
+        self.db.flush()?;
+        self.tip = block.cur_block_hash;
+        if block.header.height == 0 {
+            self.engine = EngineSpec::from_genesis(block)?.engine()?;
+        }
+        Ok(())
+    }
+
+    /// Moves the tip back to its parent, keeping the block stored, and returns it.
+    pub(crate) fn disconnect_tip(&mut self) -> Result<Block> {
+        let block = self.get_block(&self.tip)?;
+        let parent = block.header.pre_block_hash;
+
+        let blocks: &sled::Tree = &self.db;
+        let res: sled::transaction::TransactionResult<()> = (blocks, &self.heights).transaction(|(blocks, heights)| {
+            if parent == [0u8; 32] {
+                blocks.remove("last")?;
+            } else {
+                blocks.insert("last", &parent[..])?;
+            }
+            heights.remove(&block.header.height.to_be_bytes()[..])?;
+            Ok(())
+        });
+        res?;
+        self.db.flush()?;
+        self.tip = parent;
+        Ok(block)
+    }
+
+    /// Flags a stored block whose transactions failed to connect, along with
+    /// every stored block built on it.
+    pub(crate) fn mark_invalid(&self, hash: &[u8; 32]) -> Result<()> {
+        let height = self.index_of(hash)?.height;
+        let mut invalid = HashSet::new();
+        invalid.insert(*hash);
+
+        // Walk back from every tip to the height of `hash`, keeping the
+        // branches that pass through it.
+        for kv in self.tips.iter() {
+            let (tip, _) = kv?;
+            let mut cur_hash = [0u8; 32];
+            cur_hash.copy_from_slice(&tip);
+            let mut branch = Vec::new();
+            loop {
+                if invalid.contains(&cur_hash) {
+                    invalid.extend(branch);
+                    break;
                 }
+                match self.block_index(&cur_hash)? {
+                    Some(index) if index.height > height => {},
+                    _ => break,
+                }
+                branch.push(cur_hash);
+                cur_hash = self.get_block(&cur_hash)?.header.pre_block_hash;
+            }
+        }
+
+        for hash in &invalid {
+            let mut index = self.index_of(hash)?;
+            index.invalid = true;
+            self.index.insert(&hash[..], serde_json::to_vec(&index)?)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Checks the header of a block received from elsewhere, against its parent
+    /// on whichever branch that is.
+    pub fn check_block(&self, block: &Block) -> Result<()> {
+        let parent = block.header.pre_block_hash;
+        let invalid = |height, reason| Error::InvalidChain(ChainValidationError::InvalidBlock {
+            height,
+            hash: block.cur_block_hash(),
+            reason,
+        });
+
+        // A genesis block brings the engine that checks it.
+        let mut engine = self.engine.clone();
+        if parent == [0u8; 32] {
+            if let Some(genesis) = self.heights.get(0u64.to_be_bytes())? {
+                return Err(invalid(0, BlockRejectReason::BrokenLink {
+                    expected: hex::encode(genesis),
+                    found: hex::encode(parent),
+                }));
+            }
+            engine = EngineSpec::from_genesis(block)?.engine()?;
+        } else {
+            match self.block_index(&parent)? {
+                None => return Err(Error::OrphanBlock(block.cur_block_hash())),
+                Some(index) if index.invalid =>
+                    return Err(invalid(index.height + 1, BlockRejectReason::InvalidAncestor)),
+                Some(_) => {},
+            }
+        }
+
+        let next = self.next_header_on(&parent)?;
+        check_block(block, &block.cur_block_hash, &parent, &next, engine.as_ref(), self.clock.now())
+            .map_err(|reason| invalid(next.height, reason))?;
+        Ok(())
+    }
+
+    pub fn block_index(&self, hash: &[u8]) -> Result<Option<BlockIndex>> {
+        match self.index.get(hash)? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn index_of(&self, hash: &[u8]) -> Result<BlockIndex> {
+        self.block_index(hash)?
+            .ok_or_else(|| Error::Corrupted(format!("missing index of block {}", hex::encode(hash))))
+    }
+
+    /// Total work of the main chain.
+    pub fn chain_work(&self) -> Result<U256> {
+        if self.tip == [0u8; 32] {
+            return Ok(U256::zero());
+        }
+        Ok(self.index_of(&self.tip)?.chain_work())
+    }
+
+    /// Whether the stored block `hash` is on the main chain. The zero hash,
+    /// parent of every genesis block, always is.
+    pub fn is_main_chain(&self, hash: &[u8; 32]) -> Result<bool> {
+        if *hash == [0u8; 32] {
+            return Ok(true);
+        }
+        match self.block_index(hash)? {
+            Some(index) => Ok(self.heights.get(index.height.to_be_bytes())?.as_deref() == Some(&hash[..])),
+            None => Ok(false),
+        }
+    }
+
+    /// Blocks of the branch ending at `hash` that are not on the main chain,
+    /// oldest first.
+    pub fn branch(&self, hash: &[u8; 32]) -> Result<Vec<Block>> {
+        let mut branch = Vec::new();
+        let mut cur_hash = *hash;
+        while !self.is_main_chain(&cur_hash)? {
+            let block = self.get_block(&cur_hash)?;
+            cur_hash = block.header.pre_block_hash;
+            branch.push(block);
+        }
+        branch.reverse();
+        Ok(branch)
+    }
+
+    /// Every known branch, the main chain first.
+    pub fn chain_tips(&self) -> Result<Vec<ChainTip>> {
+        let mut chain_tips = Vec::new();
+        for kv in self.tips.iter() {
+            let (hash, _) = kv?;
+            let mut tip = [0u8; 32];
+            tip.copy_from_slice(&hash);
+
+            let mut status = if tip == self.tip { TipStatus::Active } else { TipStatus::Fork };
+            let mut branch_len = 0;
+            let mut cur_hash = tip;
+            while !self.is_main_chain(&cur_hash)? {
+                let index = self.index_of(&cur_hash)?;
+                if index.invalid {
+                    status = TipStatus::Invalid;
+                }
+                cur_hash = self.get_block(&cur_hash)?.header.pre_block_hash;
+                branch_len += 1;
+            }
+
+            chain_tips.push(ChainTip {
+                hash: tip,
+                height: self.index_of(&tip)?.height,
+                branch_len,
+                status,
+            });
+        }
+        chain_tips.sort_by_key(|tip| (tip.status != TipStatus::Active, std::cmp::Reverse(tip.height)));
+        Ok(chain_tips)
+    }
+
+    pub fn has_block(&self, hash: &[u8]) -> Result<bool> {
+        Ok(self.db.contains_key(hash)?)
+    }
+
+    /// Height of the tip, counting the genesis block as 0, or `None` for an empty chain.
+    pub fn best_height(&self) -> Result<Option<u64>> {
+        if self.tip == [0u8; 32] {
+            return Ok(None);
+        }
+        Ok(Some(self.get_block(&self.tip)?.header.height))
+    }
+
+    /// Height of the next block on top of the tip.
+    pub fn next_height(&self) -> Result<u64> {
+        Ok(self.best_height()?.map_or(0, |height| height + 1))
+    }
+
+    pub fn get_block_by_height(&self, height: u64) -> Result<Option<Block>> {
+        match self.heights.get(height.to_be_bytes())? {
+            Some(hash) => Ok(Some(self.get_block(&hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Hashes of main chain blocks from the tip back to genesis, one by one
+    /// for the last ten, then doubling the step. A peer answers with the
+    /// blocks after the newest of them on its own main chain.
+    pub fn block_locator(&self) -> Result<Vec<[u8; 32]>> {
+        let mut locator = Vec::new();
+        let mut height = match self.best_height()? {
+            Some(height) => height,
+            None => return Ok(locator),
+        };
+        let mut step = 1;
+        loop {
+            if let Some(hash) = self.heights.get(height.to_be_bytes())? {
+                let mut block_hash = [0u8; 32];
+                block_hash.copy_from_slice(&hash);
+                locator.push(block_hash);
+            }
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
             }
+            height = height.saturating_sub(step);
         }
-        utxo
+        Ok(locator)
     }
 
-    pub fn find_all_utxo(&self) -> HashMap<String, TXOutputVec> {
+    /// Up to `limit` main chain hashes, oldest first, following the newest
+    /// `locator` hash on the main chain, or from genesis when none is.
+    pub fn hashes_after(&self, locator: &[[u8; 32]], limit: usize) -> Result<Vec<[u8; 32]>> {
+        let mut start = 0;
+        for hash in locator {
+            if let Some(index) = self.block_index(hash)? {
+                if self.is_main_chain(hash)? {
+                    start = index.height + 1;
+                    break;
+                }
+            }
+        }
+
+        let mut hashes = Vec::new();
+        for height in (start..).take(limit) {
+            match self.heights.get(height.to_be_bytes())? {
+                Some(hash) => {
+                    let mut block_hash = [0u8; 32];
+                    block_hash.copy_from_slice(&hash);
+                    hashes.push(block_hash);
+                },
+                None => break,
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Re-verifies the stored chain from genesis to tip and returns its length.
+    pub fn validate(&self) -> Result<usize> {
+        let mut blocks = Vec::new();
+        let mut cur_hash = self.tip;
+
+        while cur_hash != [0u8; 32] {
+            let block = self.db.get(cur_hash)?
+                .and_then(|data| Block::from_bytes(&data).ok());
+            let block = block.ok_or_else(|| ChainValidationError::Unreadable {
+                hash: hex::encode(cur_hash)
+            })?;
+            let stored_hash = cur_hash;
+            cur_hash = block.header.pre_block_hash;
+            blocks.push((stored_hash, block));
+        }
+        blocks.reverse();
+
+        let mut utxo = HashMap::<String, TXOutputVec>::new();
+        let mut known_txs = HashSet::<String>::new();
+        let mut pre_block_hash = [0u8; 32];
+        let now = self.clock.now();
+
+        for (height, (stored_hash, block)) in blocks.iter().enumerate() {
+            let height = height as u64;
+            let invalid = |reason| Error::InvalidChain(ChainValidationError::InvalidBlock {
+                height,
+                hash: hex::encode(stored_hash),
+                reason,
+            });
+
+            let start = height.saturating_sub(MEDIAN_TIME_SPAN as u64) as usize;
+            let recent: Vec<BlockHeader> = blocks[start..height as usize].iter()
+                .map(|(_, block)| block.header.clone())
+                .collect();
+            let interval = self.params.retarget.interval;
+            let first = height.checked_sub(interval)
+                .filter(|_| interval > 0 && height.is_multiple_of(interval))
+                .map(|h| &blocks[h as usize].1.header);
+            let next = NextHeader::new(self.engine.as_ref(), &self.params.retarget, &recent, first);
+
+            check_block(block, stored_hash, &pre_block_hash, &next, self.engine.as_ref(), now)
+                .map_err(invalid)?;
+            if self.heights.get(height.to_be_bytes())?.as_deref() != Some(&stored_hash[..]) {
+                return Err(invalid(BlockRejectReason::IndexMismatch));
+            }
+
+            let fees = check_transactions(&block.transaction, &utxo, height, self.coinbase_maturity,
+                                          |tx_id| known_txs.contains(&hex::encode(tx_id)))
+                .map_err(|e| invalid(BlockRejectReason::Transactions(e.clone())))?;
+            check_coinbase(&block.transaction, height, self.params.block_subsidy(height), fees)
+                .map_err(|e| invalid(BlockRejectReason::Transactions(e)))?;
+
+            apply_transactions(&mut utxo, &block.transaction);
+            known_txs.extend(block.transaction.iter().map(|tx| hex::encode(&tx.id)));
+            pre_block_hash = block.cur_block_hash;
+        }
+
+        Ok(blocks.len())
+    }
+
+    /// Every unspent output, found by scanning the whole chain; the chainstate
+    /// rebuilds itself from it.
+    pub(crate) fn find_all_utxo(&self) -> Result<HashMap<String, TXOutputVec>> {
         // 未花费的交易输出
         let mut utxo: HashMap<String, TXOutputVec> = HashMap::new();
 
@@ -86,8 +698,8 @@ impl BlockChain {
         let mut spent_txos: HashMap::<String,Vec<i32>> = HashMap::new();
 
         // 遍历所有区块
-        let mut iter = self.iter();
-        while let Some(bc) = iter.next() {
+        for bc in self.iter()? {
+            let bc = bc?;
 
             // 遍历区块中的所有交易
             for tx in bc.transaction {
@@ -106,10 +718,12 @@ impl BlockChain {
                     }
 
                     if !spent {
-                        let mut outs = utxo.get(&tx_id).unwrap().clone();
-                        outs.outputs.push(out.clone());
-
-                        utxo.insert(tx_id.clone(), outs.clone());
+                        utxo.entry(tx_id.clone())
+                            .or_insert_with(|| TXOutputVec {
+                                coinbase_height: tx.coinbase_height(),
+                                ..TXOutputVec::default()
+                            })
+                            .outputs.insert(out_idx as i32, out.clone());
                     }
                 }
 
@@ -125,133 +739,85 @@ impl BlockChain {
                 }
             }
         }
-        utxo
+        Ok(utxo)
     }
 
-    pub fn find_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32) -> (i32, HashMap<String,Vec<i32>>) {
-        let mut unspent_outputs = HashMap::<String,Vec<i32>>::new();
-        let mut unspent_txs = self.find_unspent_transaction(pub_key_hash);
-        let mut acc = 0;
-
-        for tx in unspent_txs {
-            let tx_id = hex::encode(tx.id);
-            let mut unspent_array = Vec::<i32>::new();
-            for (idx, out) in tx.vout.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && acc < amount {
-                    acc += out.value;
-                    unspent_array.push(idx as i32);
-
-                    if acc >= amount {
-                        break;
-                    }
-                }
-            }
-            unspent_outputs.insert(tx_id, unspent_array);
-            if acc >= amount {
-                break;
+    /// Public key hashes paid by any output of the main chain, spent or not.
+    pub fn used_pub_key_hashes(&self) -> Result<HashSet<Vec<u8>>> {
+        let mut used = HashSet::new();
+        for bc in self.iter_from(0) {
+            for tx in bc?.transaction {
+                used.extend(tx.vout.iter().filter_map(|out| out.script_pubkey.pub_key_hash().map(<[u8]>::to_vec)));
             }
         }
-
-        (acc, unspent_outputs)
+        Ok(used)
     }
 
-    pub fn find_unspent_transaction(&self, pub_key_hash: &[u8]) -> Vec<Transaction> {
-        let mut unspent_txs = Vec::new();
-        let mut spent_txs: HashMap<String, Vec<i32>> = HashMap::new();
+    pub fn iter(&self) -> Result<BlockChainIter> {
+        Ok(BlockChainIter {
+            cur_hash: self.tip,
+            db: self.db.clone()
+        })
+    }
 
-        let mut iter = self.iter();
-        while let Some(bc) = iter.next() {
-            for tx in bc.transaction {
-                let tx_id = hex::encode(tx.id.clone());
-                for (out_idx, out) in tx.vout.iter().enumerate() {
-                    let mut spent = false;
-                    if let Some(spent_outs) = spent_txs.get(&tx_id) {
-                        for spent_out in spent_outs {
-                            if *spent_out == out_idx as i32 {
-                                spent = true;
-                                break;
-                            }
-                        }
-                    }
+    pub fn get_block(&self, hash: &[u8]) -> Result<Block> {
+        let data = self.db.get(hash)?
+            .ok_or_else(|| Error::Corrupted(format!("missing block {}", hex::encode(hash))))?;
+        Ok(Block::from_bytes(&data)?)
+    }
 
-                    if !spent && out.is_locked_with_key(pub_key_hash) {
-                        unspent_txs.push(tx.clone());
-                        break;
-                    }
-                }
-                if !tx.is_coinbase() {
-                    let mut vout_arr = Vec::<i32>::new();
-                    for tx_in in tx.vin {
-                        if tx_in.used_by_key(pub_key_hash) {
-                            let id = hex::encode(tx_in.tx_id);
-                            if let Some(arr) = spent_txs.get_mut(&id) {
-                                arr.push(tx_in.vout);
-                            } else {
-                                spent_txs.insert(id, vec![tx_in.vout]);
-                            }
-                        }
-                    }
-                }
-            }
+    /// Iterates the main chain forwards, starting at block `height`.
+    pub fn iter_from(&self, height: u64) -> BlockChainForwardIter {
+        BlockChainForwardIter {
+            height,
+            db: self.db.clone(),
+            heights: self.heights.clone(),
         }
-
-        unspent_txs
     }
 
-    pub fn iter(&self) -> BlockChainIter {
-        let mut cur_hash = [0u8; 32];
-        cur_hash.copy_from_slice(&self.db.get("last").unwrap().unwrap().to_vec());
-
-        BlockChainIter {
-            cur_hash,
-            db: self.db.clone()
+    pub fn print(&self) -> Result<()> {
+        for bc in self.iter()? {
+            bc?.print();
         }
+        Ok(())
     }
 
-    pub fn get_block(&self, hash: &[u8]) -> Block {
-        serde_json::from_slice(&self.db.get(hash).unwrap().unwrap()).unwrap()
+    /// Main chain blocks from height `from` up to and including `to`, or the tip.
+    pub fn blocks_in_range(&self, from: u64, to: Option<u64>) -> impl Iterator<Item = Result<Block>> {
+        self.iter_from(from)
+            .take_while(move |bc| !matches!(bc, Ok(bc) if to.is_some_and(|to| bc.header.height > to)))
     }
 
-    pub fn print(&self) {
-        let mut iter = self.iter();
-        while let Some(bc) = iter.next() {
-            println!("Prev Hash: {:?}", bc.pre_block_hash());
-            println!("Curr Hash: {:?}", bc.cur_block_hash());
-            println!("Data: {:?}\n", bc.transaction);
+    /// Prints the blocks from height `from` up to and including `to`.
+    pub fn print_range(&self, from: u64, to: Option<u64>) -> Result<()> {
+        for bc in self.blocks_in_range(from, to) {
+            bc?.print();
         }
+        Ok(())
     }
 
-    pub fn find_transaction(&self, id: &[u8]) -> Option<Transaction> {
-        let mut iter = self.iter();
-        while let Some(bc) = iter.next() {
-            for tx in bc.transaction {
+    pub fn find_transaction(&self, id: &[u8]) -> Result<Option<Transaction>> {
+        for bc in self.iter()? {
+            for tx in bc?.transaction {
                 if tx.id == id {
-                    return Some(tx);
+                    return Ok(Some(tx));
                 }
             }
         }
-        None
+        Ok(None)
     }
 
-    pub fn sign_transaction(&self, priv_key: &[u8], tx: &mut Transaction) {
-        let mut prev_txs = HashMap::<String, Transaction>::new();
-        for vin in &tx.vin {
-            if let Some(tx) = self.find_transaction(&vin.tx_id) {
-                prev_txs.insert(hex::encode(tx.id.clone()), tx.clone());
+    /// Finds the block containing `tx_id` and proves the transaction against its header.
+    pub fn find_merkle_proof(&self, tx_id: &[u8]) -> Result<Option<(BlockHeader, MerkleProof)>> {
+        for bc in self.iter()? {
+            let bc = bc?;
+            if let Some(proof) = bc.merkle_proof(tx_id) {
+                return Ok(Some((bc.header, proof)));
             }
         }
-        tx.sign(priv_key, prev_txs);
+        Ok(None)
     }
 
-    pub fn verify_transaction(&self, pub_key: &[u8], tx: &Transaction) -> bool {
-        let mut prev_txs = HashMap::<String,Transaction>::new();
-        for vin in &tx.vin {
-            if let Some(tx) = self.find_transaction(&vin.tx_id) {
-                prev_txs.insert(hex::encode(tx.id.clone()), tx.clone());
-            }
-        }
-        return tx.verify(pub_key, prev_txs);
-    }
 }
 
 pub struct BlockChainIter {
@@ -260,22 +826,305 @@ pub struct BlockChainIter {
 }
 
 impl BlockChainIter {
-    fn next(&mut self) -> Option<Block> {
+    fn next(&mut self) -> Option<Result<Block>> {
         if self.cur_hash == [0u8; 32] {
             return None;
         }
-        if let Ok(block) = serde_json::from_slice::<Block>(&self.db.get(self.cur_hash).unwrap().unwrap()) {
-            self.cur_hash = block.pre_block_hash;
-            Some(block)
-        } else {
-            None
+        let block = self.db.get(self.cur_hash).map_err(Error::from).and_then(|data| {
+            let data = data.ok_or_else(|| Error::Corrupted(
+                format!("missing block {}", hex::encode(self.cur_hash))))?;
+            Ok(Block::from_bytes(&data)?)
+        });
+        match block {
+            Ok(block) => {
+                self.cur_hash = block.header.pre_block_hash;
+                Some(Ok(block))
+            }
+            Err(e) => {
+                self.cur_hash = [0u8; 32];
+                Some(Err(e))
+            }
         }
     }
 }
 
 impl Iterator for BlockChainIter {
-    type Item = Block;
+    type Item = Result<Block>;
     fn next(&mut self) -> Option<Self::Item> {
         self.next()
     }
-}
\ No newline at end of file
+}
+/// Walks the main chain from a height towards the tip through the height index.
+pub struct BlockChainForwardIter {
+    height: u64,
+    db: sled::Db,
+    heights: sled::Tree,
+}
+
+impl BlockChainForwardIter {
+    fn read_block(&self) -> Result<Option<Block>> {
+        let hash = match self.heights.get(self.height.to_be_bytes())? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        let data = self.db.get(&hash)?
+            .ok_or_else(|| Error::Corrupted(format!("missing block {}", hex::encode(&hash))))?;
+        Ok(Some(Block::from_bytes(&data)?))
+    }
+}
+
+impl Iterator for BlockChainForwardIter {
+    type Item = Result<Block>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_block() {
+            Ok(Some(block)) => {
+                self.height += 1;
+                Some(Ok(block))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.height = u64::MAX;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn work_bytes(work: U256) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    work.to_big_endian(&mut bytes);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_params::TestDir;
+    use crate::wallet::Wallet;
+
+    fn new_address() -> String {
+        Wallet::new().unwrap().get_address().unwrap()
+    }
+
+    /// A new chain of `len` blocks on top of the genesis block.
+    fn new_chain(len: usize) -> BlockChain {
+        let mut bc = BlockChain::create_blockchain(&new_address(), &EngineSpec::ProofOfWork).unwrap();
+        for _ in 0..len {
+            let height = bc.next_height().unwrap();
+            let coinbase = Transaction::new_coinbase_tx(&new_address(), String::new(),
+                                                        bc.params.block_subsidy(height), height).unwrap();
+            bc.push_block(vec![coinbase]).unwrap();
+        }
+        bc
+    }
+
+    /// Replaces the block stored under `hash` with `block`.
+    fn overwrite(bc: &BlockChain, hash: &[u8; 32], block: &Block) {
+        bc.db.insert(&hash[..], block.to_bytes()).unwrap();
+    }
+
+    /// Height, hash and reason of the first block `validate` rejects.
+    fn first_invalid(bc: &BlockChain) -> (u64, String, BlockRejectReason) {
+        match bc.validate() {
+            Err(Error::InvalidChain(ChainValidationError::InvalidBlock { height, hash, reason })) =>
+                (height, hash, reason),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    fn hash_at(bc: &BlockChain, height: u64) -> [u8; 32] {
+        bc.get_block_by_height(height).unwrap().unwrap().cur_block_hash
+    }
+
+    /// A block on top of `parent` with as much work as it.
+    fn child_block(parent: &Block) -> Block {
+        let height = parent.header.height + 1;
+        let coinbase = Transaction::new_coinbase_tx(&new_address(), String::new(), 10, height).unwrap();
+        Block::new_block(vec![coinbase], parent.cur_block_hash, height, parent.header.bits,
+                         parent.time_stamp() + 1)
+    }
+
+    fn heights_of<I: Iterator<Item = Result<Block>>>(blocks: I) -> Vec<u64> {
+        blocks.map(|bc| bc.unwrap().header.height).collect()
+    }
+
+    #[test]
+    fn test_height_index_ranges() {
+        let _dir = TestDir::new();
+        let bc = new_chain(4);
+        assert_eq!(heights_of(bc.iter_from(0)), vec![0, 1, 2, 3, 4]);
+        assert_eq!(heights_of(bc.iter_from(3)), vec![3, 4]);
+        assert!(heights_of(bc.iter_from(5)).is_empty());
+        assert!(heights_of(bc.iter_from(u64::MAX)).is_empty());
+
+        assert_eq!(heights_of(bc.blocks_in_range(1, Some(3))), vec![1, 2, 3]);
+        assert_eq!(heights_of(bc.blocks_in_range(2, Some(2))), vec![2]);
+        assert_eq!(heights_of(bc.blocks_in_range(2, None)), vec![2, 3, 4]);
+        assert_eq!(heights_of(bc.blocks_in_range(3, Some(100))), vec![3, 4]);
+        assert!(heights_of(bc.blocks_in_range(3, Some(2))).is_empty());
+        assert!(heights_of(bc.blocks_in_range(7, Some(9))).is_empty());
+
+        assert_eq!(bc.get_block_by_height(4).unwrap().unwrap().cur_block_hash, bc.tip);
+        assert!(bc.get_block_by_height(5).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_height_index_after_reorg() {
+        let _dir = TestDir::new();
+        let mut bc = new_chain(3);
+        let fork = bc.get_block_by_height(1).unwrap().unwrap();
+        let old_tip = bc.tip;
+
+        let side_2 = child_block(&fork);
+        let side_3 = child_block(&side_2);
+        let side_4 = child_block(&side_3);
+        for block in [&side_2, &side_3, &side_4] {
+            bc.insert_block(block).unwrap();
+        }
+        assert_eq!(hash_at(&bc, 3), old_tip);
+
+        bc.disconnect_tip().unwrap();
+        assert!(bc.get_block_by_height(3).unwrap().is_none());
+        bc.disconnect_tip().unwrap();
+        for block in [&side_2, &side_3, &side_4] {
+            bc.connect_block(block).unwrap();
+        }
+
+        assert_eq!(hash_at(&bc, 1), fork.cur_block_hash);
+        assert_eq!(hash_at(&bc, 2), side_2.cur_block_hash);
+        assert_eq!(hash_at(&bc, 4), side_4.cur_block_hash);
+        let hashes: Vec<[u8; 32]> = bc.blocks_in_range(1, Some(3)).map(|bc| bc.unwrap().cur_block_hash).collect();
+        assert_eq!(hashes, vec![fork.cur_block_hash, side_2.cur_block_hash, side_3.cur_block_hash]);
+        assert!(!bc.is_main_chain(&old_tip).unwrap());
+        assert_eq!(bc.validate().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_invalid_descendants() {
+        let _dir = TestDir::new();
+        let bc = new_chain(2);
+        let genesis = bc.get_block_by_height(0).unwrap().unwrap();
+        let bad = child_block(&genesis);
+        let child = child_block(&bad);
+        let grandchild = child_block(&child);
+        let sibling = child_block(&bad);
+        for block in [&bad, &child, &grandchild, &sibling] {
+            bc.insert_block(block).unwrap();
+        }
+
+        bc.mark_invalid(&bad.cur_block_hash).unwrap();
+        for block in [&bad, &child, &grandchild, &sibling] {
+            assert!(bc.index_of(&block.cur_block_hash).unwrap().invalid);
+        }
+        for height in 0..3 {
+            assert!(!bc.index_of(&hash_at(&bc, height)).unwrap().invalid);
+        }
+        let statuses: Vec<TipStatus> = bc.chain_tips().unwrap().iter().map(|tip| tip.status).collect();
+        assert_eq!(statuses, vec![TipStatus::Active, TipStatus::Invalid, TipStatus::Invalid]);
+
+        // Blocks stored later inherit the flag, and are refused up front
+        let late = child_block(&grandchild);
+        assert!(bc.insert_block(&late).unwrap().invalid);
+        let next = child_block(&late);
+        match bc.check_block(&next) {
+            Err(Error::InvalidChain(ChainValidationError::InvalidBlock { height, reason, .. })) =>
+                assert_eq!((height, reason), (5, BlockRejectReason::InvalidAncestor)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_block_locator() {
+        let _dir = TestDir::new();
+        let bc = new_chain(14);
+        let heights: Vec<u64> = bc.block_locator().unwrap().iter()
+            .map(|hash| bc.block_index(hash).unwrap().unwrap().height)
+            .collect();
+        assert_eq!(heights, vec![14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 3, 0]);
+
+        let locator = bc.block_locator().unwrap();
+        assert!(bc.hashes_after(&locator, 10).unwrap().is_empty());
+        assert_eq!(bc.hashes_after(&[hash_at(&bc, 5)], 3).unwrap(),
+                   vec![hash_at(&bc, 6), hash_at(&bc, 7), hash_at(&bc, 8)]);
+        assert_eq!(bc.hashes_after(&[[9u8; 32]], 2).unwrap(), vec![hash_at(&bc, 0), hash_at(&bc, 1)]);
+
+        // A peer on a branch off block 0 is answered from the fork
+        let side = child_block(&bc.get_block_by_height(0).unwrap().unwrap());
+        bc.insert_block(&side).unwrap();
+        assert_eq!(bc.hashes_after(&[side.cur_block_hash, hash_at(&bc, 0)], 1).unwrap(), vec![hash_at(&bc, 1)]);
+    }
+
+    #[test]
+    fn test_storage_format() {
+        let _dir = TestDir::new();
+        let bc = new_chain(1);
+        assert_eq!(bc.db.get(FORMAT_KEY).unwrap().as_deref(), Some(&[ENCODING_VERSION][..]));
+
+        // A chain stored before the format was recorded
+        bc.db.remove(FORMAT_KEY).unwrap();
+        drop(bc);
+        let bc = BlockChain::new_block_chain().unwrap();
+        assert_eq!(bc.db.get(FORMAT_KEY).unwrap().as_deref(), Some(&[ENCODING_VERSION][..]));
+
+        // ... in another encoding
+        bc.db.remove(FORMAT_KEY).unwrap();
+        bc.db.insert(bc.tip, &b"{\"header\":{}}"[..]).unwrap();
+        drop(bc);
+        match BlockChain::new_block_chain() {
+            Err(Error::IncompatibleDatabase { reason, .. }) =>
+                assert_eq!(reason, format!("storage format unknown, expected {}", ENCODING_VERSION)),
+            other => panic!("unexpected result: {:?}", other.map(|bc| bc.tip)),
+        }
+
+        let db = open_database(BLOCKCHAIN_DB).unwrap();
+        db.insert(FORMAT_KEY, &[ENCODING_VERSION + 1][..]).unwrap();
+        drop(db);
+        match BlockChain::new_block_chain() {
+            Err(Error::IncompatibleDatabase { reason, .. }) => assert_eq!(reason, format!(
+                "storage format {}, expected {}", ENCODING_VERSION + 1, ENCODING_VERSION)),
+            other => panic!("unexpected result: {:?}", other.map(|bc| bc.tip)),
+        }
+    }
+
+    #[test]
+    fn test_validate_tampered_chain() {
+        let _dir = TestDir::new();
+        let bc = new_chain(3);
+        assert_eq!(bc.validate().unwrap(), 4);
+        let block = bc.get_block_by_height(2).unwrap().unwrap();
+        let hash = block.cur_block_hash;
+
+        let mut tampered = block.clone();
+        tampered.transaction[0].vout[0].value += 1;
+        overwrite(&bc, &hash, &tampered);
+        assert_eq!(first_invalid(&bc), (2, hex::encode(hash), BlockRejectReason::TransactionIdMismatch {
+            tx_id: hex::encode(&tampered.transaction[0].id),
+        }));
+        tampered.transaction[0].set_id();
+        overwrite(&bc, &hash, &tampered);
+        assert_eq!(first_invalid(&bc), (2, hex::encode(hash), BlockRejectReason::MerkleRootMismatch));
+
+        let mut tampered = block.clone();
+        tampered.header.time_stamp += 1;
+        overwrite(&bc, &hash, &tampered);
+        assert_eq!(first_invalid(&bc), (2, hex::encode(hash), BlockRejectReason::HashMismatch {
+            stored: hex::encode(hash),
+            computed: hex::encode(tampered.header.pow_hash()),
+        }));
+
+        // Linked straight to the genesis block, the walk skips block 1
+        let mut tampered = block.clone();
+        tampered.header.pre_block_hash = hash_at(&bc, 0);
+        overwrite(&bc, &hash, &tampered);
+        assert_eq!(first_invalid(&bc), (1, hex::encode(hash), BlockRejectReason::HashMismatch {
+            stored: hex::encode(hash),
+            computed: hex::encode(tampered.header.pow_hash()),
+        }));
+
+        overwrite(&bc, &hash, &block);
+        assert_eq!(bc.validate().unwrap(), 4);
+        bc.heights.insert(1u64.to_be_bytes(), &hash[..]).unwrap();
+        let (height, _, reason) = first_invalid(&bc);
+        assert_eq!((height, reason), (1, BlockRejectReason::IndexMismatch));
+    }
+}
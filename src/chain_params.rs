@@ -0,0 +1,244 @@
+//! Networks a node can run, and where it keeps the files of each.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::consensus::*;
+use crate::error::{Error, Result};
+
+/// Text of the coinbase of the mainnet genesis block.
+const MAINNET_GENESIS_MESSAGE: &str = "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+
+/// Rules that differ from one network to another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    /// Name given to `--network`. Files of networks other than mainnet live in
+    /// a directory of that name under the data directory.
+    pub network: String,
+    /// First byte of the payload of every address.
+    pub address_version: u8,
+    /// Human-readable prefix of Bech32 addresses.
+    pub bech32_hrp: String,
+    /// BIP44 coin type in the derivation paths of HD wallet keys.
+    pub hd_coin_type: u32,
+    /// Text recorded in the coinbase of a genesis block.
+    pub genesis_message: String,
+    /// Subsidy of the first blocks, before any halving.
+    pub reward: i32,
+    /// Difficulty retargeting, starting from the easiest target `retarget.pow_limit`.
+    pub retarget: RetargetParams,
+}
+
+impl ChainParams {
+
+    pub fn mainnet() -> Self {
+        ChainParams {
+            network: "mainnet".to_string(),
+            address_version: 0x00,
+            bech32_hrp: "bc".to_string(),
+            hd_coin_type: 0,
+            genesis_message: MAINNET_GENESIS_MESSAGE.to_string(),
+            reward: INITIAL_SUBSIDY,
+            retarget: RetargetParams::default(),
+        }
+    }
+
+    /// A public test network with its own addresses and a slightly easier target.
+    pub fn testnet() -> Self {
+        ChainParams {
+            network: "testnet".to_string(),
+            address_version: 0x6f,
+            bech32_hrp: "tb".to_string(),
+            hd_coin_type: 1,
+            genesis_message: "BlockChainRust testnet genesis".to_string(),
+            reward: INITIAL_SUBSIDY,
+            retarget: RetargetParams {
+                pow_limit: target_to_compact(bigint::uint::U256::one() << 244),
+                ..RetargetParams::default()
+            },
+        }
+    }
+
+    /// A local network for tests, mining at the easiest target without retargeting.
+    pub fn regtest() -> Self {
+        ChainParams {
+            network: "regtest".to_string(),
+            address_version: 0x6f,
+            bech32_hrp: "bcrt".to_string(),
+            hd_coin_type: 1,
+            genesis_message: "BlockChainRust regtest genesis".to_string(),
+            reward: INITIAL_SUBSIDY,
+            retarget: RetargetParams {
+                interval: 0,
+                pow_limit: 0x207fffff,
+                ..RetargetParams::default()
+            },
+        }
+    }
+
+    /// Every preset network.
+    pub fn presets() -> Vec<Self> {
+        vec![ChainParams::mainnet(), ChainParams::testnet(), ChainParams::regtest()]
+    }
+
+    /// Preset of the network called `name`.
+    pub fn from_network(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(ChainParams::mainnet()),
+            "testnet" => Some(ChainParams::testnet()),
+            "regtest" => Some(ChainParams::regtest()),
+            _ => None,
+        }
+    }
+
+    /// Newly created coins a block at `height` may claim on top of its fees.
+    pub fn block_subsidy(&self, height: u64) -> i32 {
+        block_subsidy(self.reward, height)
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams::mainnet()
+    }
+}
+
+/// Network, data directory and wallet of the running process.
+#[derive(Debug, PartialEq)]
+struct Selection {
+    params: ChainParams,
+    datadir: PathBuf,
+    wallet: Option<PathBuf>,
+}
+
+static SELECTION: OnceLock<Selection> = OnceLock::new();
+
+/// Runs the process on the network `params` with its files under `datadir`,
+/// creating the directory, and the wallet in `wallet` if given rather than
+/// the default file. The selection must happen before any file is opened and
+/// cannot change afterwards; without one mainnet runs in the current directory.
+pub fn select_network(params: ChainParams, datadir: &Path, wallet: Option<&Path>) -> Result<()> {
+    let mut selection = Some(Selection {
+        params,
+        datadir: datadir.to_path_buf(),
+        wallet: wallet.map(Path::to_path_buf),
+    });
+    let selected = SELECTION.get_or_init(|| selection.take().expect("initialized once"));
+    if let Some(selection) = selection.filter(|selection| selection != selected) {
+        return Err(Error::Config(format!("the process already runs {} in {}, it cannot switch to {} in {}",
+                                         selected.params.network, selected.datadir.display(),
+                                         selection.params.network, selection.datadir.display())));
+    }
+    std::fs::create_dir_all(network_dir(selected))?;
+    Ok(())
+}
+
+fn selected() -> &'static Selection {
+    SELECTION.get_or_init(|| Selection {
+        params: ChainParams::mainnet(),
+        datadir: PathBuf::from("."),
+        wallet: None,
+    })
+}
+
+fn network_dir(selected: &Selection) -> PathBuf {
+    match selected.params.network.as_str() {
+        "mainnet" => selected.datadir.clone(),
+        network => selected.datadir.join(network),
+    }
+}
+
+/// Parameters of the network the process runs.
+pub fn chain_params() -> &'static ChainParams {
+    &selected().params
+}
+
+/// Where the file or database `name` of the running network lives. An
+/// absolute `name` is left as is.
+pub fn data_path<P: AsRef<Path>>(name: P) -> PathBuf {
+    #[cfg(test)]
+    if let Some(dir) = TEST_DATADIR.with(|dir| dir.borrow().clone()) {
+        return dir.join(name);
+    }
+    network_dir(selected()).join(name)
+}
+
+/// Opens the database `name` of the running network, creating it if needed.
+pub fn open_database(name: &str) -> Result<sled::Db> {
+    let path = data_path(name);
+    sled::open(&path).map_err(|e| match e {
+        // Such as files of another sled version
+        sled::Error::Unsupported(reason) => Error::IncompatibleDatabase {
+            path: path.display().to_string(),
+            reason,
+        },
+        e => e.into(),
+    })
+}
+
+/// Wallet file chosen for the running process, if not the default one.
+pub fn selected_wallet() -> Option<&'static Path> {
+    selected().wallet.as_deref()
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_DATADIR: std::cell::RefCell<Option<PathBuf>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Temporary data directory of the test running on the current thread, so
+/// tests opening databases can run side by side. Removed when dropped.
+#[cfg(test)]
+pub(crate) struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new() -> Self {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let dir = std::env::temp_dir().join(format!("BlockChainRust-test-{}-{}", std::process::id(), n));
+        std::fs::create_dir_all(&dir).unwrap();
+        TEST_DATADIR.with(|cur| *cur.borrow_mut() = Some(dir.clone()));
+        TestDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        TEST_DATADIR.with(|cur| *cur.borrow_mut() = None);
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        for name in &["mainnet", "testnet", "regtest"] {
+            let params = ChainParams::from_network(name).unwrap();
+            assert_eq!(params.network, *name);
+            assert_eq!(params.block_subsidy(0), params.reward);
+        }
+        assert_eq!(ChainParams::from_network("devnet"), None);
+        assert_ne!(ChainParams::mainnet().address_version, ChainParams::testnet().address_version);
+
+        // Regtest blocks take a couple of hashes
+        assert_eq!(block_work(ChainParams::regtest().retarget.pow_limit), bigint::uint::U256::from(2u64));
+    }
+
+    #[test]
+    fn test_reselect_network() {
+        // The default the other tests run with
+        select_network(ChainParams::mainnet(), Path::new("."), None).unwrap();
+        select_network(ChainParams::mainnet(), Path::new("."), None).unwrap();
+
+        assert!(matches!(select_network(ChainParams::testnet(), Path::new("."), None), Err(Error::Config(_))));
+        assert!(matches!(select_network(ChainParams::mainnet(), Path::new("data"), None), Err(Error::Config(_))));
+        assert!(matches!(select_network(ChainParams::mainnet(), Path::new("."), Some(Path::new("other.dat"))),
+                         Err(Error::Config(_))));
+        assert_eq!(chain_params(), &ChainParams::mainnet());
+    }
+}
@@ -1,24 +1,70 @@
 
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use structopt::StructOpt;
-use serde::export::Option::Some;
 
-use crate::block::Block;
 use crate::block_chain::BlockChain;
+use crate::chain_params::select_network;
+use crate::config::Config;
+use crate::engine::EngineSpec;
+use crate::error::{Error, Result};
 use crate::transaction::*;
+use crate::mempool::Mempool;
+use crate::miner::Miner;
+use crate::server::Node;
 use crate::utils::Utils;
-use crate::wallet::Wallets;
+use crate::utxo::UTXOSet;
+use crate::wallet::{DEFAULT_GAP_LIMIT, DEFAULT_KDF_COST, Wallets};
+
+/// Read instead of prompting for the wallet passphrase, for scripts.
+const PASSPHRASE_ENV: &str = "WALLET_PASSPHRASE";
+/// Read instead of prompting for the new passphrase of `encrypt-wallet` and `change-passphrase`.
+const NEW_PASSPHRASE_ENV: &str = "WALLET_NEW_PASSPHRASE";
+/// How long a wallet unlocked to sign a single transaction stays unlocked.
+const SEND_UNLOCK_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "bc_cli", about = "An command line interface for BlockChainRust!!!")]
 pub struct Opt {
     #[structopt(short,long, help = "print all block information in the main chain of the blockchain!")]
-    Print: bool,
+    print: bool,
+
+    #[structopt(long, help = "With --print, print blocks starting at height FROM")]
+    from: Option<u64>,
+
+    #[structopt(long, help = "With --print, print blocks up to height TO")]
+    to: Option<u64>,
 
     #[structopt(long, help = "Generates a new key-pair and saves it into the wallet file!")]
-    CreateWallet: bool,
+    create_wallet: bool,
 
     #[structopt(long, help = "Lists all addresses from the wallet file!")]
-    ListAddress: bool,
+    list_address: bool,
+
+    #[structopt(long, help = "With --create-wallet or --list-address, show Bech32 addresses instead of Base58Check")]
+    bech32: bool,
+
+    #[structopt(long, default_value = ".", parse(from_os_str),
+                help = "Directory holding config.toml, the wallet and databases; networks other than mainnet use a subdirectory")]
+    datadir: PathBuf,
+
+    #[structopt(long, possible_values = &["mainnet", "testnet", "regtest"],
+                help = "Network whose chain, addresses and rules to use [default: mainnet]")]
+    network: Option<String>,
+
+    #[structopt(long, parse(from_os_str),
+                help = "Wallet file, relative to the directory of the network [default: wallet.dat]")]
+    wallet: Option<PathBuf>,
+
+    #[structopt(long, possible_values = &["off", "error", "warn", "info", "debug", "trace"],
+                help = "Most verbose level of the messages logged [default: info]")]
+    log_level: Option<String>,
+
+    #[structopt(long, value_name = "SECONDS",
+                help = "Prompt for the passphrase of the encrypted wallet and keep it unlocked for SECONDS")]
+    unlock: Option<u64>,
 
     #[structopt(subcommand)]
     cmd: Option<SubCommand>,
@@ -30,6 +76,14 @@ pub enum SubCommand {
     CreateBlockChain {
         #[structopt(short,long, help = "create-blockchain --address ADDRESS")]
         address: String,
+
+        #[structopt(long, default_value = "pow", possible_values = &["pow", "poa"],
+                    help = "Consensus engine of the chain, proof of work or proof of authority")]
+        consensus: String,
+
+        #[structopt(long, required_if("consensus", "poa"),
+                    help = "With --consensus poa, address of a wallet signing blocks in turn; may be repeated")]
+        signer: Vec<String>,
     },
 
     #[structopt( help = "Get balance of ADDRESS!")]
@@ -47,87 +101,461 @@ pub enum SubCommand {
         to: String,
 
         #[structopt(long, help = "The amount of the send transaction")]
-        amount: i32
+        amount: i32,
+
+        #[structopt(long, default_value = "0", help = "The fee left to the miner of the transaction")]
+        fee: i32,
+
+        #[structopt(long, help = "Mine a block with the transaction right away, rewarding the sender, instead of pooling it")]
+        mine: bool,
+    },
+
+    #[structopt( help = "Mine the pooled transactions into a block rewarding ADDRESS")]
+    Mine {
+        #[structopt(short,long, help = "mine --address ADDRESS")]
+        address: String,
+
+        #[structopt(long, help = "Number of threads searching for the nonce [default: 1]")]
+        threads: Option<usize>,
+    },
+
+    #[structopt( help = "Re-verify every block of the stored chain from genesis to tip")]
+    VerifyChain,
+
+    #[structopt( help = "Rebuild the UTXO set from the blockchain")]
+    Reindex,
+
+    #[structopt( help = "List the tips of every known branch of the blockchain")]
+    ChainTips,
+
+    #[structopt( help = "Start a node listening on PORT and sync with PEERs")]
+    StartNode {
+        #[structopt(long, help = "Host name or IP address to listen on and give peers [default: 127.0.0.1]")]
+        bind: Option<String>,
+
+        #[structopt(long, help = "start-node --port PORT")]
+        port: Option<u16>,
+
+        #[structopt(long, help = "Address of a known node, e.g. 127.0.0.1:3000; may be repeated")]
+        peer: Vec<String>,
+
+        #[structopt(long, help = "Keep mining blocks rewarding ADDRESS on top of the tip")]
+        mining_address: Option<String>,
+
+        #[structopt(long, help = "Number of threads searching for the nonce [default: 1]")]
+        threads: Option<usize>,
+    },
+
+    #[structopt( help = "Encrypt the private keys of the wallet file with a passphrase")]
+    EncryptWallet {
+        #[structopt(long,
+                    help = "Cost of deriving the key from the passphrase, as log2 of the scrypt N, from 10 to 20 [default: 15]")]
+        cost: Option<u8>,
+    },
+
+    #[structopt( help = "Encrypt the wallet file with a new passphrase")]
+    ChangePassphrase,
+
+    #[structopt( help = "Derive the new keys of the wallet from a new mnemonic, printed for backup")]
+    CreateHdWallet {
+        #[structopt(long, default_value = "12", possible_values = &["12", "15", "18", "21", "24"],
+                    help = "Number of words of the mnemonic")]
+        words: usize,
+    },
+
+    #[structopt( help = "Restore the keys derived from MNEMONIC and find their coins in the blockchain")]
+    RestoreWallet {
+        #[structopt(long, help = "restore-wallet --mnemonic \"WORD WORD ...\"")]
+        mnemonic: String,
+
+        #[structopt(long, help = "Number of unused addresses in a row after which to stop looking [default: 20]")]
+        gap: Option<u32>,
+    },
+
+    #[structopt( help = "Inspect the configuration read from config.toml and the flags")]
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    #[structopt( help = "Print the effective configuration, as it would be written in config.toml")]
+    Show,
+}
+
+fn create_blockchain(address: &str, consensus: &str, signers: &[String]) -> Result<()> {
+    Utils::validate_address(address)?;
+    let spec = if consensus == "poa" {
+        let wallets = Wallets::new()?;
+        let mut keys = Vec::new();
+        for signer in signers {
+            let wallet = wallets.get_wallet(signer)
+                .ok_or_else(|| Error::MissingWallet(signer.to_string()))?;
+            keys.push(wallet.public_key());
+        }
+        EngineSpec::ProofOfAuthority { signers: keys }
+    } else {
+        EngineSpec::ProofOfWork
+    };
+    let bc = BlockChain::create_blockchain(address, &spec)?;
+    println!("Block: {:?}", hex::encode(bc.tip));
+    let utxo_set = UTXOSet::new(bc)?;
+    utxo_set.reindex()?;
+    println!("Create BlockChain DONE!!!");
+    Ok(())
+}
+
+/// `address` as shown to the user, in Bech32 if asked for.
+fn display_address(address: &str, bech32: bool) -> Result<String> {
+    if bech32 {
+        Ok(Utils::encode_bech32_address(&Utils::get_pub_key_hash(address)?))
+    } else {
+        Ok(address.to_string())
     }
 }
 
-fn create_blockchain(address: &str) {
-    if !Utils::validate_address(address) {
-        println!("ERROR: Address is not valid!");
-        return;
+fn create_wallet(bech32: bool) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    if wallets.is_locked() {
+        unlock_wallet(&mut wallets, SEND_UNLOCK_TIMEOUT)?;
     }
-    if let Some(bc) = BlockChain::create_blockchain(address) {
-        println!("Block: {:?}", hex::encode(bc.tip));
-        println!("Create BlockChain DONE!!!");
+    let address = wallets.create_wallet()?;
+    wallets.save_to_file()?;
+
+    println!("Your new wallet address: {}", display_address(&address, bech32)?);
+    Ok(())
+}
+
+fn get_balance(address: &str) -> Result<()> {
+    let pub_key_hash = Utils::get_pub_key_hash(address)?;
+    let utxo_set = UTXOSet::new(BlockChain::new_block_chain()?)?;
+    let balance = utxo_set.balance(&pub_key_hash)?;
+    println!("Balance of '{}': {}", address, balance.confirmed);
+    println!("  Immature: {}", balance.immature);
+    println!("  Spendable: {}", balance.spendable);
+    Ok(())
+}
+
+fn list_addresses(bech32: bool) -> Result<()> {
+    let wallets = Wallets::new()?;
+    let addresses = wallets.get_address();
+    for x in addresses.iter() {
+        println!("Address: {}", display_address(x, bech32)?);
     }
+    Ok(())
 }
 
-fn create_wallet() {
-    let mut wallets = Wallets::new();
-    let address = wallets.create_wallet();
-    wallets.save_to_file();
+fn print_blockchain(from: Option<u64>, to: Option<u64>) -> Result<()> {
+    let bc = BlockChain::new_block_chain()?;
+    if from.is_none() && to.is_none() {
+        bc.print()
+    } else {
+        bc.print_range(from.unwrap_or(0), to)
+    }
+}
 
-    println!("Your new wallet address: {}", address);
+fn send(from: &str, to: &str, amount: i32, fee: i32, mine: bool) -> Result<()> {
+    let mut utxo_set = UTXOSet::new(BlockChain::new_block_chain()?)?;
+    let mempool = Mempool::open()?;
+    let mut wallets = Wallets::new()?;
+    if wallets.is_locked() {
+        unlock_wallet(&mut wallets, SEND_UNLOCK_TIMEOUT)?;
+    }
+    let tx = Transaction::new_utxo_transaction(from, to, amount, fee, &utxo_set, &mut wallets)?;
+    if wallets.is_hd() {
+        // Keep the change key the transaction pays to
+        wallets.save_to_file()?;
+    }
+    wallets.lock();
+    if mine {
+        let coinbase = utxo_set.coinbase_tx(from, utxo_set.block_chain().next_height()?, fee)?;
+        let block = utxo_set.mine_block(vec![coinbase, tx])?;
+        mempool.evict(&block, &utxo_set)?;
+        println!("{}", block.cur_block_hash());
+    } else {
+        let tx_id = hex::encode(&tx.id);
+        mempool.add(tx, &utxo_set)?;
+        println!("Transaction {} added to the mempool.", tx_id);
+    }
+    Ok(())
 }
 
-fn get_balance(address: &str) {
-    if !Utils::validate_address(address) {
-        println!("ERROR: Address is not valid!");
-        return;
+fn mine(address: &str, threads: usize) -> Result<()> {
+    let mut bc = BlockChain::new_block_chain()?;
+    bc.set_miner(Miner::new(threads));
+    let mut utxo_set = UTXOSet::new(bc)?;
+    let mempool = Mempool::open()?;
+
+    let transactions = mempool.block_transactions(&utxo_set, address)?;
+    let block = utxo_set.mine_block(transactions)?;
+    mempool.evict(&block, &utxo_set)?;
+    println!("Mined block {} with {} transactions{}.", block.cur_block_hash(),
+             block.transaction.len(), utxo_set.block_chain().miner().hashrate_note());
+    Ok(())
+}
+
+fn verify_chain() -> Result<()> {
+    let bc = BlockChain::new_block_chain()?;
+    let len = bc.validate()?;
+    println!("BlockChain is valid, {} blocks verified.", len);
+    Ok(())
+}
+
+fn chain_tips() -> Result<()> {
+    let bc = BlockChain::new_block_chain()?;
+    for tip in bc.chain_tips()? {
+        println!("Height: {} Hash: {} Branch: {} Status: {}",
+                 tip.height, hex::encode(tip.hash), tip.branch_len, tip.status);
     }
+    Ok(())
+}
+
+fn reindex() -> Result<()> {
+    let utxo_set = UTXOSet::new(BlockChain::new_block_chain()?)?;
+    utxo_set.reindex()?;
+    let count = utxo_set.count_transactions()?;
+    println!("Done! There are {} transactions in the UTXO set.", count);
+    Ok(())
+}
 
-    if let Some(bc) = BlockChain::new_block_chain() {
-        let pub_key_hash = Utils::get_pub_key_hash(address);
-        let utxo = bc.find_utxo(&pub_key_hash);
-        let balance = utxo.iter().fold(0, |acc, x| {
-            acc + x.value
-        });
-        println!("Balance of '{}': {}", address, balance);
+fn start_node(config: &Config) -> Result<()> {
+    let mut node = Node::new(&config.bind, config.port()?, config.peers.clone())?;
+    if let Some(address) = config.mining_address.clone() {
+        Utils::validate_address(&address)?;
+        node = node.with_miner(address, Miner::new(config.threads));
     }
+    node.run()
 }
 
-fn list_addresses() {
-    let wallets = Wallets::new();
-    let addresses = wallets.get_address();
-    addresses.iter().for_each(|x| {
-        println!("Address: {}", x);
-    });
+fn encrypt_wallet(cost: u8) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    if wallets.is_encrypted() {
+        return Err(Error::WalletEncrypted);
+    }
+    let passphrase = read_new_passphrase()?;
+    wallets.encrypt(&passphrase, cost)?;
+    wallets.save_to_file()?;
+    println!("Wallet encrypted, its passphrase is now needed to sign.");
+    Ok(())
 }
 
-fn print_blockchain() {
-    if let Some(bc) = BlockChain::new_block_chain() {
-        bc.print();
+fn change_passphrase() -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    if !wallets.is_encrypted() {
+        return Err(Error::WalletNotEncrypted);
     }
+    let old = read_passphrase("Passphrase: ", PASSPHRASE_ENV)?;
+    let new = read_new_passphrase()?;
+    wallets.change_passphrase(&old, &new)?;
+    wallets.save_to_file()?;
+    println!("Wallet passphrase changed.");
+    Ok(())
+}
+
+fn create_hd_wallet(words: usize) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    if wallets.is_locked() {
+        unlock_wallet(&mut wallets, SEND_UNLOCK_TIMEOUT)?;
+    }
+    let (mnemonic, address) = wallets.create_hd(words)?;
+    wallets.save_to_file()?;
+    wallets.lock();
+
+    println!("Mnemonic: {}", *mnemonic);
+    println!("Write these words down and keep them safe, they restore every address of the wallet.");
+    println!("Your new wallet address: {}", address);
+    Ok(())
 }
 
-fn send(from: &str, to: &str, amount: i32) {
-    if let Some(mut bc) = BlockChain::new_block_chain() {
-        if let Some(tx) = Transaction::new_utxo_transaction(from, to, amount, &bc) {
-            bc.mine_block(vec![tx])
-        } else {
-            println!("The balance of {} is not enough!", from);
+fn restore_wallet(mnemonic: &str, gap: u32) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    if wallets.is_locked() {
+        unlock_wallet(&mut wallets, SEND_UNLOCK_TIMEOUT)?;
+    }
+    let utxo_set = match BlockChain::new_block_chain() {
+        Ok(bc) => Some(UTXOSet::new(bc)?),
+        Err(Error::BlockChainNotFound) => None,
+        Err(e) => return Err(e),
+    };
+    let used = match &utxo_set {
+        Some(utxo_set) => utxo_set.block_chain().used_pub_key_hashes()?,
+        None => HashSet::new(),
+    };
+    let addresses = wallets.restore_hd(mnemonic, gap, |pub_key_hash| used.contains(pub_key_hash))?;
+    wallets.save_to_file()?;
+    wallets.lock();
+
+    println!("Restored {} addresses.", addresses.len());
+    let mut total = 0i32;
+    for address in &addresses {
+        match &utxo_set {
+            Some(utxo_set) => {
+                let balance = utxo_set.balance(&Utils::get_pub_key_hash(address)?)?.confirmed;
+                total = total.checked_add(balance).ok_or(Error::ValueOverflow)?;
+                println!("Address: {} Balance: {}", address, balance);
+            },
+            None => println!("Address: {}", address),
         }
     }
+    if utxo_set.is_some() {
+        println!("Total balance: {}", total);
+    }
+    Ok(())
+}
+
+/// Unlocks `wallets` for `timeout` with a passphrase taken from the
+/// environment or typed at the terminal.
+fn unlock_wallet(wallets: &mut Wallets, timeout: Duration) -> Result<()> {
+    let passphrase = read_passphrase("Wallet passphrase: ", PASSPHRASE_ENV)?;
+    wallets.unlock(&passphrase, timeout)
+}
+
+/// The passphrase in the environment variable `env`, or else typed at the
+/// terminal after `prompt`. Without a terminal the wallet stays locked.
+fn read_passphrase(prompt: &str, env: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(env) {
+        return Ok(passphrase);
+    }
+    rpassword::read_password_from_tty(Some(prompt)).map_err(|_| Error::WalletLocked)
 }
 
-pub fn run(opt: Opt) {
-    if opt.Print {
-        print_blockchain();
-    } else if opt.CreateWallet {
-        create_wallet();
-    } else if opt.ListAddress {
-        list_addresses();
+fn read_new_passphrase() -> Result<String> {
+    let passphrase = match std::env::var(NEW_PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let passphrase = read_passphrase("New passphrase: ", NEW_PASSPHRASE_ENV)?;
+            if read_passphrase("Repeat the new passphrase: ", NEW_PASSPHRASE_ENV)? != passphrase {
+                return Err(Error::BadPassphrase("the passphrases do not match".to_string()));
+            }
+            passphrase
+        },
+    };
+    if passphrase.is_empty() {
+        return Err(Error::BadPassphrase("the passphrase is empty".to_string()));
+    }
+    Ok(passphrase)
+}
+
+fn show_config(config: &Config) -> Result<()> {
+    print!("{}", config.to_toml()?);
+    Ok(())
+}
+
+/// Reads `config.toml` of the data directory and applies the flags of `opt` over it.
+fn load_config(opt: &Opt) -> Result<Config> {
+    let mut config = Config::load(&opt.datadir)?;
+    if let Some(network) = &opt.network {
+        config.network = network.clone();
+    }
+    if let Some(wallet) = &opt.wallet {
+        config.wallet = Some(wallet.clone());
+    }
+    if let Some(log_level) = &opt.log_level {
+        config.log_level = log_level.clone();
+    }
+    match &opt.cmd {
+        Some(SubCommand::Mine { threads: Some(threads), .. }) => config.threads = *threads,
+        Some(SubCommand::StartNode { bind, port, peer, mining_address, threads }) => {
+            if let Some(bind) = bind {
+                config.bind = bind.clone();
+            }
+            if port.is_some() {
+                config.port = *port;
+            }
+            if !peer.is_empty() {
+                config.peers = peer.clone();
+            }
+            if mining_address.is_some() {
+                config.mining_address = mining_address.clone();
+            }
+            if let Some(threads) = threads {
+                config.threads = *threads;
+            }
+        },
+        _ => {},
+    }
+    Ok(config)
+}
+
+/// Process exit code reported for `err` by the command line interface.
+pub fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::InvalidAddress { .. } | Error::InvalidAmount(_) | Error::MissingWallet(_) | Error::NotSigner { .. }
+        | Error::Config(_) | Error::WalletLocked | Error::WrongPassphrase | Error::BadPassphrase(_)
+        | Error::WalletEncrypted | Error::WalletNotEncrypted | Error::InvalidMnemonic(_)
+        | Error::InvalidDerivation(_) | Error::HdWalletExists => 2,
+        Error::InsufficientFunds { .. } => 3,
+        Error::BlockChainExists | Error::BlockChainNotFound => 4,
+        Error::InvalidBlock(_) | Error::InvalidTransaction(_) | Error::InvalidChain(_) | Error::TransactionNotFound(_)
+        | Error::OrphanBlock(_) | Error::TransactionConflict { .. } | Error::MessageTooLarge(_) => 5,
+        Error::Storage(_) | Error::Io(_) | Error::Corrupted(_) | Error::IncompatibleDatabase { .. }
+        | Error::Decode(_) => 6,
+        Error::Serialization(_) | Error::Crypto(_) | Error::MiningCancelled | Error::ValueOverflow => 1,
+    }
+}
+
+pub fn run(opt: Opt) -> Result<()> {
+    let config = load_config(&opt)?;
+    let _ = env_logger::Builder::new()
+        .filter_level(config.log_level()?)
+        .target(env_logger::Target::Stdout)
+        .try_init();
+    select_network(config.chain_params()?, &opt.datadir, config.wallet.as_deref())?;
+    if let Some(seconds) = opt.unlock {
+        unlock_wallet(&mut Wallets::new()?, Duration::from_secs(seconds))?;
+    }
+
+    if opt.print {
+        print_blockchain(opt.from, opt.to)
+    } else if opt.create_wallet {
+        create_wallet(opt.bech32)
+    } else if opt.list_address {
+        list_addresses(opt.bech32)
     } else if let Some(cmd) = opt.cmd {
         match cmd {
-            SubCommand::CreateBlockChain { address } => {
-                create_blockchain(&address);
+            SubCommand::CreateBlockChain { address, consensus, signer } => {
+                create_blockchain(&address, &consensus, &signer)
             },
             SubCommand::GetBalance{ address } => {
-                get_balance(&address);
+                get_balance(&address)
+            },
+            SubCommand::Send { from, to, amount, fee, mine: mine_now } => {
+                send(&from, &to, amount, fee, mine_now)
+            },
+            SubCommand::Mine { address, .. } => {
+                mine(&address, config.threads)
+            },
+            SubCommand::VerifyChain => {
+                verify_chain()
+            },
+            SubCommand::Reindex => {
+                reindex()
             },
-            SubCommand::Send { from, to, amount} => {
-                send(&from, &to, amount);
+            SubCommand::ChainTips => {
+                chain_tips()
+            },
+            SubCommand::StartNode { .. } => {
+                start_node(&config)
+            },
+            SubCommand::EncryptWallet { cost } => {
+                encrypt_wallet(cost.unwrap_or(DEFAULT_KDF_COST))
+            },
+            SubCommand::ChangePassphrase => {
+                change_passphrase()
+            },
+            SubCommand::CreateHdWallet { words } => {
+                create_hd_wallet(words)
+            },
+            SubCommand::RestoreWallet { mnemonic, gap } => {
+                restore_wallet(&mnemonic, gap.unwrap_or(DEFAULT_GAP_LIMIT))
+            },
+            SubCommand::Config { cmd: ConfigCommand::Show } => {
+                show_config(&config)
             }
         }
+    } else {
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,128 @@
+//! Settings of the node and the wallet, read from `config.toml` in the data
+//! directory. Flags given on the command line override the file.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{ Serialize, Deserialize };
+
+use crate::chain_params::ChainParams;
+use crate::error::{Error, Result};
+
+pub const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// One of mainnet, testnet or regtest.
+    pub network: String,
+    /// Wallet file, relative to the directory of the network. Defaults to `wallet.dat`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wallet: Option<PathBuf>,
+    /// Most verbose level of the messages logged: off, error, warn, info, debug or trace.
+    pub log_level: String,
+    /// Host name or IP address the node listens on and gives its peers.
+    pub bind: String,
+    /// Port the node listens on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Nodes to sync with on start, e.g. 127.0.0.1:3000.
+    pub peers: Vec<String>,
+    /// Address rewarded by the blocks the node mines; the node does not mine without one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mining_address: Option<String>,
+    /// Number of threads searching for the nonce.
+    pub threads: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            network: "mainnet".to_string(),
+            wallet: None,
+            log_level: "info".to_string(),
+            bind: "127.0.0.1".to_string(),
+            port: None,
+            peers: vec![],
+            mining_address: None,
+            threads: 1,
+        }
+    }
+}
+
+impl Config {
+
+    /// Reads `config.toml` in `datadir`, or the defaults when there is none.
+    pub fn load(datadir: &Path) -> Result<Self> {
+        let path = datadir.join(CONFIG_FILE);
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let text = std::fs::read_to_string(&path)?;
+        Config::parse(&text).map_err(|e| match e {
+            Error::Config(what) => Error::Config(format!("{}: {}", path.display(), what)),
+            e => e,
+        })
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| Error::Config(e.to_string()))
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|e| Error::Config(e.to_string()))
+    }
+
+    /// Parameters of the configured network.
+    pub fn chain_params(&self) -> Result<ChainParams> {
+        ChainParams::from_network(&self.network)
+            .ok_or_else(|| Error::Config(format!("unknown network '{}'", self.network)))
+    }
+
+    pub fn log_level(&self) -> Result<log::LevelFilter> {
+        log::LevelFilter::from_str(&self.log_level)
+            .map_err(|_| Error::Config(format!("unknown log level '{}'", self.log_level)))
+    }
+
+    /// Port the node listens on, which must be configured.
+    pub fn port(&self) -> Result<u16> {
+        self.port.ok_or_else(|| Error::Config(
+            format!("no port to listen on, pass --port or set port in {}", CONFIG_FILE)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(r#"
+            network = "regtest"
+            bind = "0.0.0.0"
+            port = 3001
+            peers = ["127.0.0.1:3000"]
+            threads = 4
+        "#).unwrap();
+        assert_eq!(config, Config {
+            network: "regtest".to_string(),
+            bind: "0.0.0.0".to_string(),
+            port: Some(3001),
+            peers: vec!["127.0.0.1:3000".to_string()],
+            threads: 4,
+            ..Config::default()
+        });
+        assert_eq!(config.chain_params().unwrap(), ChainParams::regtest());
+        assert_eq!(config.log_level().unwrap(), log::LevelFilter::Info);
+        assert_eq!(Config::parse(&config.to_toml().unwrap()).unwrap(), config);
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::default().port().is_err());
+        assert!(matches!(Config::parse("prot = 3001"), Err(Error::Config(_))));
+        assert!(matches!(Config::parse("port = \"3001\""), Err(Error::Config(_))));
+
+        let config = Config { network: "devnet".to_string(), log_level: "loud".to_string(), ..Config::default() };
+        assert!(config.chain_params().is_err());
+        assert!(config.log_level().is_err());
+    }
+}
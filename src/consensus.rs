@@ -1,27 +1,383 @@
+use std::ops::RangeInclusive;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use bigint::uint;
+use openssl::sha::{sha256, Sha256};
 
-use crate::block::Block;
+use crate::block::BlockHeader;
+use crate::engine::ConsensusEngine;
+use crate::encoding::Encode;
 
-pub trait ProofOfWork {
-    fn proof_of_work(&mut self) -> [u8;32];
+/// Number of previous blocks whose median time a new block must exceed.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How many seconds a block may be dated ahead of the local clock.
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60;
+
+/// Reward of the first mainnet blocks, before any halving.
+pub const INITIAL_SUBSIDY: i32 = 10;
+/// Number of blocks after which the subsidy is halved.
+pub const HALVING_INTERVAL: u64 = 1000;
+
+/// Newly created coins a block at `height` may claim on top of its fees,
+/// `reward` being the subsidy before any halving.
+pub fn block_subsidy(reward: i32, height: u64) -> i32 {
+    let halvings = height / HALVING_INTERVAL;
+    if halvings >= 31 {
+        return 0;
+    }
+    reward >> halvings
+}
+
+/// Default number of blocks a coinbase needs on top of its own before its
+/// outputs can be spent.
+pub const DEFAULT_COINBASE_MATURITY: u64 = 10;
+
+/// Source of the current Unix time, in seconds.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// What the chain requires of the header of its next block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NextHeader {
+    pub height: u64,
+    pub bits: u32,
+    /// The block must be dated strictly after this time.
+    pub median_time_past: u64,
+}
+
+impl NextHeader {
+    /// Derives the requirements `engine` has for the block after `recent`, the
+    /// last headers of the chain oldest first, `first` being the header
+    /// `params.interval` blocks below the new one when that is a retarget height.
+    pub fn new(engine: &dyn ConsensusEngine, params: &RetargetParams, recent: &[BlockHeader],
+               first: Option<&BlockHeader>) -> Self {
+        let parent = recent.last();
+        let times: Vec<u64> = recent.iter().rev()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|header| header.time_stamp)
+            .collect();
+        NextHeader {
+            height: parent.map_or(0, |parent| parent.height + 1),
+            bits: engine.next_bits(params, parent, first),
+            median_time_past: median_time_past(&times),
+        }
+    }
+
+    /// Time a miner should stamp the block with, given the time `now`.
+    pub fn time_stamp(&self, now: u64) -> u64 {
+        now.max(self.median_time_past + 1)
+    }
+}
+
+/// Median of the timestamps of the last `MEDIAN_TIME_SPAN` blocks, or 0 for none.
+pub fn median_time_past(times: &[u64]) -> u64 {
+    let mut times: Vec<u64> = times.iter().take(MEDIAN_TIME_SPAN).cloned().collect();
+    if times.is_empty() {
+        return 0;
+    }
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
+/// Latest time a block may be dated with when the local clock reads `now`.
+pub fn max_time_stamp(now: u64) -> u64 {
+    now.saturating_add(MAX_FUTURE_DRIFT)
+}
+
+/// Difficulty retargeting rule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetargetParams {
+    /// Number of blocks between two difficulty adjustments.
+    pub interval: u64,
+    /// Wanted number of seconds between two blocks.
+    pub target_spacing: u64,
+    /// Compact form of the easiest allowed target, used by the genesis block.
+    pub pow_limit: u32,
+}
+
+impl Default for RetargetParams {
+    fn default() -> Self {
+        RetargetParams {
+            interval: 10,
+            target_spacing: 10,
+            pow_limit: target_to_compact(uint::U256::one() << 240),
+        }
+    }
+}
+
+/// Expands a compact "nBits" target, 1 byte size and 3 bytes mantissa.
+pub fn compact_to_target(bits: u32) -> uint::U256 {
+    let size = (bits >> 24) as usize;
+    let mantissa = uint::U256::from((bits & 0x007f_ffff) as u64);
+    if bits & 0x0080_0000 != 0 || size > 32 {
+        return uint::U256::zero();
+    }
+    if size <= 3 {
+        mantissa >> (8 * (3 - size))
+    } else {
+        mantissa << (8 * (size - 3))
+    }
+}
+
+pub fn target_to_compact(target: uint::U256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut mantissa = if size <= 3 {
+        target.low_u64() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u64()
+    };
+    // The mantissa is signed, keep its top bit clear.
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    ((size as u32) << 24) | mantissa as u32
+}
+
+/// Expected number of hashes needed to meet the target `bits`, 2^256 / (target + 1).
+pub fn block_work(bits: u32) -> uint::U256 {
+    let target = compact_to_target(bits);
+    if target.is_zero() {
+        return uint::U256::zero();
+    }
+    // 2^256 does not fit, use (2^256 - target - 1) / (target + 1) + 1 instead.
+    (!target / (target + uint::U256::one())) + uint::U256::one()
+}
+
+/// Target of the block following `parent`. `first` is the block `params.interval`
+/// blocks below the new one, needed only when the new height is a retarget height.
+pub fn next_target_bits(params: &RetargetParams, parent: Option<&BlockHeader>,
+                        first: Option<&BlockHeader>) -> u32 {
+    let parent = match parent {
+        Some(parent) => parent,
+        None => return params.pow_limit,
+    };
+    let height = parent.height + 1;
+    if params.interval == 0 || height % params.interval != 0 {
+        return parent.bits;
+    }
+    let first = match first {
+        Some(first) => first,
+        None => return parent.bits,
+    };
+
+    let expected = params.interval * params.target_spacing;
+    let actual = parent.time_stamp.saturating_sub(first.time_stamp)
+        .max(expected / 4)
+        .min(expected * 4);
+
+    let limit = uint::U512::from(compact_to_target(params.pow_limit));
+    let target = uint::U512::from(compact_to_target(parent.bits))
+        * uint::U512::from(actual) / uint::U512::from(expected.max(1));
+    let target = if target > limit { limit } else { target };
+    target_to_compact(uint::U256::from(target))
 }
 
-impl ProofOfWork for Block {
+/// Bytes SHA-256 consumes per compression round.
+const SHA256_CHUNK_LEN: usize = 64;
+/// Size of the nonce that ends a serialized header.
+const NONCE_LEN: usize = 4;
 
-    fn proof_of_work(&mut self) -> [u8; 32] {
-        let one = uint::U256::one();
-        let target = one << ( 256 - self.target_bits as usize );
+/// Double SHA-256 of serialized headers that differ only in their nonce.
+///
+/// The nonce is the last field of a header, so the leading 64-byte chunk is
+/// hashed once and only the tail is rehashed for each nonce tried.
+#[derive(Clone)]
+pub struct HeaderHasher {
+    midstate: Sha256,
+    tail: Vec<u8>,
+    target: [u8; 32],
+}
+
+impl HeaderHasher {
+
+    pub fn new(header: &BlockHeader) -> Self {
+        let bytes = header.to_bytes();
+        let split = SHA256_CHUNK_LEN * ((bytes.len() - NONCE_LEN) / SHA256_CHUNK_LEN);
+        let mut midstate = Sha256::new();
+        midstate.update(&bytes[..split]);
+        let mut target = [0u8; 32];
+        compact_to_target(header.bits).to_big_endian(&mut target);
+        HeaderHasher {
+            midstate,
+            tail: bytes[split..].to_vec(),
+            target,
+        }
+    }
+
+    pub fn hash(&mut self, nonce: u32) -> [u8; 32] {
+        let offset = self.tail.len() - NONCE_LEN;
+        self.tail[offset..].copy_from_slice(&nonce.to_le_bytes());
+        let mut hasher = self.midstate.clone();
+        hasher.update(&self.tail);
+        sha256(&hasher.finish())
+    }
+
+    /// Whether `hash`, read as a big-endian number, is below the target.
+    pub fn meets_target(&self, hash: &[u8; 32]) -> bool {
+        hash < &self.target
+    }
 
-        while self.nonce < std::u32::MAX {
-            let value = serde_json::to_string(&self).unwrap_or("".to_string());
-            let hash = openssl::sha::sha256(value.as_bytes());
-            let hashInt = uint::U256::from(hash);
-            if hashInt < target {
-                return hash;
-            } else {
-                self.nonce += 1;
+    /// First nonce in `nonces` whose hash meets the target, with that hash.
+    pub fn search(&mut self, nonces: RangeInclusive<u32>) -> Option<(u32, [u8; 32])> {
+        for nonce in nonces {
+            let hash = self.hash(nonce);
+            if self.meets_target(&hash) {
+                return Some((nonce, hash));
             }
         }
-        [0;32]
+        None
+    }
+}
+
+pub trait ProofOfWork {
+    /// Tries every nonce, returning the hash of the first one meeting the
+    /// target or `None` once the nonce space is exhausted.
+    fn proof_of_work(&mut self) -> Option<[u8;32]>;
+
+    /// Recomputes the hash the header was sealed with.
+    fn pow_hash(&self) -> [u8;32];
+
+    fn is_valid_pow(&self) -> bool;
+}
+
+impl ProofOfWork for BlockHeader {
+
+    fn proof_of_work(&mut self) -> Option<[u8; 32]> {
+        let (nonce, hash) = HeaderHasher::new(self).search(0..=u32::MAX)?;
+        self.nonce = nonce;
+        Some(hash)
+    }
+
+    fn pow_hash(&self) -> [u8; 32] {
+        sha256(&sha256(&self.to_bytes()))
+    }
+
+    fn is_valid_pow(&self) -> bool {
+        uint::U256::from(self.pow_hash()) < compact_to_target(self.bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ProofOfWorkEngine;
+
+    fn header(height: u64, time_stamp: u64, bits: u32) -> BlockHeader {
+        BlockHeader {
+            height,
+            time_stamp,
+            pre_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            bits,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(INITIAL_SUBSIDY, 0), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(INITIAL_SUBSIDY, HALVING_INTERVAL - 1), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(INITIAL_SUBSIDY, HALVING_INTERVAL), INITIAL_SUBSIDY / 2);
+        assert_eq!(block_subsidy(INITIAL_SUBSIDY, HALVING_INTERVAL * 3), INITIAL_SUBSIDY / 8);
+        assert_eq!(block_subsidy(INITIAL_SUBSIDY, HALVING_INTERVAL * 4), 0);
+        assert_eq!(block_subsidy(INITIAL_SUBSIDY, u64::MAX), 0);
+        assert_eq!(block_subsidy(50, HALVING_INTERVAL), 25);
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        assert_eq!(median_time_past(&[]), 0);
+        assert_eq!(median_time_past(&[5]), 5);
+        assert_eq!(median_time_past(&[30, 10, 20]), 20);
+        // Only the last `MEDIAN_TIME_SPAN` times count
+        let times: Vec<u64> = (0..20).rev().collect();
+        assert_eq!(median_time_past(&times), 14);
+    }
+
+    #[test]
+    fn test_next_header() {
+        let params = RetargetParams::default();
+        let genesis = NextHeader::new(&ProofOfWorkEngine, &params, &[], None);
+        assert_eq!(genesis, NextHeader { height: 0, bits: params.pow_limit, median_time_past: 0 });
+
+        let recent: Vec<BlockHeader> = (0..3).map(|h| header(h, 1000 + h * 10, params.pow_limit)).collect();
+        let next = NextHeader::new(&ProofOfWorkEngine, &params, &recent, None);
+        assert_eq!(next.height, 3);
+        assert_eq!(next.median_time_past, 1010);
+        assert_eq!(next.time_stamp(2000), 2000);
+        // A lagging clock still moves time forward
+        assert_eq!(next.time_stamp(900), 1011);
+        assert_eq!(max_time_stamp(1000), 1000 + MAX_FUTURE_DRIFT);
+    }
+
+    #[test]
+    fn test_compact_round_trip() {
+        assert_eq!(compact_to_target(0x1d00ffff), uint::U256::from(0xffffu64) << 208);
+        assert_eq!(target_to_compact(uint::U256::from(0xffffu64) << 208), 0x1d00ffff);
+        assert_eq!(target_to_compact(uint::U256::one() << 240), 0x1f010000);
+        assert_eq!(target_to_compact(uint::U256::from(0x80u64)), 0x02008000);
+        assert_eq!(compact_to_target(0x02008000), uint::U256::from(0x80u64));
+    }
+
+    #[test]
+    fn test_block_work() {
+        let params = RetargetParams::default();
+        assert_eq!(block_work(params.pow_limit), uint::U256::from(65535u64));
+        assert_eq!(block_work(target_to_compact(uint::U256::one() << 230)), uint::U256::from(67108863u64));
+        assert_eq!(block_work(0), uint::U256::zero());
+    }
+
+    #[test]
+    fn test_retarget() {
+        let params = RetargetParams { interval: 10, target_spacing: 10, ..Default::default() };
+        let bits = target_to_compact(uint::U256::one() << 230);
+        let first = header(0, 1000, bits);
+
+        // Not a retarget height
+        assert_eq!(next_target_bits(&params, Some(&header(4, 1000, bits)), None), bits);
+        // On schedule
+        assert_eq!(next_target_bits(&params, Some(&header(9, 1100, bits)), Some(&first)), bits);
+        // Twice too fast halves the target
+        assert_eq!(next_target_bits(&params, Some(&header(9, 1050, bits)), Some(&first)),
+                   target_to_compact(uint::U256::one() << 229));
+        // Adjustment is clamped to a factor of 4
+        assert_eq!(next_target_bits(&params, Some(&header(9, 1000, bits)), Some(&first)),
+                   target_to_compact(uint::U256::one() << 228));
+        // Never easier than the limit
+        assert_eq!(next_target_bits(&params, Some(&header(9, 9000, params.pow_limit)), Some(&first)),
+                   params.pow_limit);
+        assert_eq!(next_target_bits(&params, None, None), params.pow_limit);
+    }
+
+    #[test]
+    fn test_header_hasher() {
+        let mut block_header = header(7, 1_600_000_000, 0x1f010000);
+        let mut hasher = HeaderHasher::new(&block_header);
+        for nonce in &[0, 1, 0xdead_beef, u32::MAX] {
+            block_header.nonce = *nonce;
+            assert_eq!(hasher.hash(*nonce), block_header.pow_hash());
+        }
+
+        block_header.nonce = 0;
+        let hash = block_header.proof_of_work().unwrap();
+        assert_eq!(hash, block_header.pow_hash());
+        assert!(block_header.is_valid_pow());
+        assert_eq!(hasher.search(0..=block_header.nonce), Some((block_header.nonce, hash)));
+        if block_header.nonce > 0 {
+            assert_eq!(hasher.search(0..=block_header.nonce - 1), None);
+        }
     }
 }
@@ -0,0 +1,494 @@
+//! Canonical binary encoding of blocks and transactions, used to hash, store
+//! and send them.
+//!
+//! Fixed-width integers are little-endian and hashes are 32 raw bytes. Lengths,
+//! counts and heights are unsigned LEB128 varints in their shortest form and
+//! signed values are zigzag varints. Block headers and transactions start with
+//! a version byte.
+
+use std::fmt;
+
+use crate::block::{Block, BlockHeader};
+use crate::engine::EngineSpec;
+use crate::script::Script;
+use crate::transaction::{TXInput, TXOutput, Transaction};
+
+/// Version byte of the encodings below.
+pub const ENCODING_VERSION: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    /// A varint longer than needed, or with more than 64 bits.
+    InvalidVarInt,
+    UnsupportedVersion(u8),
+    /// Bytes left over after the value.
+    TrailingBytes(usize),
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of data"),
+            DecodeError::InvalidVarInt => write!(f, "invalid varint"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported encoding version {}", version),
+            DecodeError::TrailingBytes(len) => write!(f, "{} trailing bytes", len),
+            DecodeError::InvalidValue(what) => write!(f, "invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError>;
+
+    /// Decodes a value that takes up all of `bytes`.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let value = Self::decode(&mut reader)?;
+        reader.finish()?;
+        Ok(value)
+    }
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn write_zigzag(buf: &mut Vec<u8>, value: i64) {
+    write_varint(buf, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+/// Writes `bytes` after their length.
+pub fn write_var_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub fn write_string(buf: &mut Vec<u8>, value: &str) {
+    write_var_bytes(buf, value.as_bytes());
+}
+
+/// Cursor over encoded data.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader {
+            data,
+            pos: 0,
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.remaining() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_hash(&mut self) -> Result<[u8; 32], DecodeError> {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(self.read_bytes(32)?);
+        Ok(hash)
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift == 63 && byte > 1 {
+                return Err(DecodeError::InvalidVarInt);
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                // Only the shortest encoding is canonical.
+                if byte == 0 && shift > 0 {
+                    return Err(DecodeError::InvalidVarInt);
+                }
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn read_zigzag(&mut self) -> Result<i64, DecodeError> {
+        let value = self.read_varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, DecodeError> {
+        let value = self.read_zigzag()?;
+        if value < i32::MIN as i64 || value > i32::MAX as i64 {
+            return Err(DecodeError::InvalidValue("32-bit integer"));
+        }
+        Ok(value as i32)
+    }
+
+    /// Reads a length or count, which cannot exceed the data left.
+    pub fn read_len(&mut self) -> Result<usize, DecodeError> {
+        let len = self.read_varint()?;
+        if len > self.remaining() as u64 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        Ok(len as usize)
+    }
+
+    pub fn read_var_bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.read_len()?;
+        Ok(self.read_bytes(len)?.to_vec())
+    }
+
+    pub fn read_string(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.read_var_bytes()?)
+            .map_err(|_| DecodeError::InvalidValue("string"))
+    }
+
+    pub fn read_version(&mut self) -> Result<u8, DecodeError> {
+        let version = self.read_u8()?;
+        if version != ENCODING_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        Ok(version)
+    }
+
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        match self.remaining() {
+            0 => Ok(()),
+            len => Err(DecodeError::TrailingBytes(len)),
+        }
+    }
+}
+
+/// Writes the number of `items` followed by each of them.
+pub fn write_vec<T: Encode>(buf: &mut Vec<u8>, items: &[T]) {
+    write_varint(buf, items.len() as u64);
+    for item in items {
+        item.encode(buf);
+    }
+}
+
+pub fn read_vec<T: Decode>(reader: &mut Reader<'_>) -> Result<Vec<T>, DecodeError> {
+    let count = reader.read_len()?;
+    let mut items = Vec::new();
+    for _ in 0..count {
+        items.push(T::decode(reader)?);
+    }
+    Ok(items)
+}
+
+impl Encode for TXInput {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.tx_id);
+        write_zigzag(buf, self.vout as i64);
+        write_var_bytes(buf, self.script_sig.as_bytes());
+    }
+}
+
+impl Decode for TXInput {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(TXInput {
+            tx_id: reader.read_hash()?,
+            vout: reader.read_i32()?,
+            script_sig: Script::from_bytes(reader.read_var_bytes()?),
+        })
+    }
+}
+
+impl Encode for TXOutput {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_zigzag(buf, self.value as i64);
+        write_var_bytes(buf, self.script_pubkey.as_bytes());
+    }
+}
+
+impl Decode for TXOutput {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(TXOutput {
+            value: reader.read_i32()?,
+            script_pubkey: Script::from_bytes(reader.read_var_bytes()?),
+        })
+    }
+}
+
+impl Encode for Transaction {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(ENCODING_VERSION);
+        write_var_bytes(buf, &self.id);
+        write_vec(buf, &self.vin);
+        write_vec(buf, &self.vout);
+    }
+}
+
+impl Transaction {
+    /// Encoding of everything but the id, which is the hash of it.
+    pub fn contents_to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![ENCODING_VERSION];
+        write_vec(&mut buf, &self.vin);
+        write_vec(&mut buf, &self.vout);
+        buf
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        reader.read_version()?;
+        Ok(Transaction {
+            id: reader.read_var_bytes()?,
+            vin: read_vec(reader)?,
+            vout: read_vec(reader)?,
+        })
+    }
+}
+
+/// Headers have a fixed size of `BlockHeader::encoded_len` bytes.
+impl Encode for BlockHeader {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(ENCODING_VERSION);
+        buf.extend_from_slice(&self.height.to_le_bytes());
+        buf.extend_from_slice(&self.time_stamp.to_le_bytes());
+        buf.extend_from_slice(&self.pre_block_hash);
+        buf.extend_from_slice(&self.merkle_root);
+        buf.extend_from_slice(&self.bits.to_le_bytes());
+        buf.extend_from_slice(&self.nonce.to_le_bytes());
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        reader.read_version()?;
+        Ok(BlockHeader {
+            height: reader.read_u64()?,
+            time_stamp: reader.read_u64()?,
+            pre_block_hash: reader.read_hash()?,
+            merkle_root: reader.read_hash()?,
+            bits: reader.read_u32()?,
+            nonce: reader.read_u32()?,
+        })
+    }
+}
+
+impl Encode for Block {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.header.encode(buf);
+        buf.extend_from_slice(&self.cur_block_hash);
+        write_var_bytes(buf, &self.seal);
+        write_vec(buf, &self.transaction);
+    }
+}
+
+impl Decode for Block {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(Block {
+            header: BlockHeader::decode(reader)?,
+            cur_block_hash: reader.read_hash()?,
+            seal: reader.read_var_bytes()?,
+            transaction: read_vec(reader)?,
+        })
+    }
+}
+
+/// A tag byte for the engine, then its parameters.
+impl Encode for EngineSpec {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(ENCODING_VERSION);
+        match self {
+            EngineSpec::ProofOfWork => buf.push(0),
+            EngineSpec::ProofOfAuthority { signers } => {
+                buf.push(1);
+                write_varint(buf, signers.len() as u64);
+                for signer in signers {
+                    write_var_bytes(buf, signer);
+                }
+            },
+        }
+    }
+}
+
+impl Decode for EngineSpec {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        reader.read_version()?;
+        match reader.read_u8()? {
+            0 => Ok(EngineSpec::ProofOfWork),
+            1 => {
+                let count = reader.read_len()?;
+                let mut signers = Vec::new();
+                for _ in 0..count {
+                    signers.push(reader.read_var_bytes()?);
+                }
+                Ok(EngineSpec::ProofOfAuthority { signers })
+            },
+            _ => Err(DecodeError::InvalidValue("consensus engine")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction() -> Transaction {
+        Transaction {
+            id: vec![0xaa; 32],
+            vin: vec![TXInput {
+                tx_id: [0x11; 32],
+                vout: -1,
+                script_sig: Script::from_bytes(vec![2, 0xab, 0xcd]),
+            }],
+            vout: vec![TXOutput {
+                value: 10,
+                script_pubkey: Script::pay_to_pub_key_hash(&[0xbb; 20]),
+            }],
+        }
+    }
+
+    fn header() -> BlockHeader {
+        BlockHeader {
+            height: 1,
+            time_stamp: 0x5f5e1000,
+            pre_block_hash: [0x22; 32],
+            merkle_root: [0x33; 32],
+            bits: 0x1f010000,
+            nonce: 7,
+        }
+    }
+
+    #[test]
+    fn test_varint_vectors() {
+        let vectors: &[(u64, &[u8])] = &[
+            (0, &[0x00]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (300, &[0xac, 0x02]),
+            (u64::MAX, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]),
+        ];
+        for (value, bytes) in vectors {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, *value);
+            assert_eq!(&buf[..], *bytes);
+            assert_eq!(Reader::new(bytes).read_varint(), Ok(*value));
+        }
+
+        for (value, byte) in &[(0i64, 0u8), (-1, 1), (1, 2), (-2, 3)] {
+            let mut buf = Vec::new();
+            write_zigzag(&mut buf, *value);
+            assert_eq!(buf, vec![*byte]);
+            assert_eq!(Reader::new(&buf).read_zigzag(), Ok(*value));
+        }
+    }
+
+    #[test]
+    fn test_varint_rejects_non_canonical() {
+        assert_eq!(Reader::new(&[0x80, 0x00]).read_varint(), Err(DecodeError::InvalidVarInt));
+        assert_eq!(Reader::new(&[0xff; 10]).read_varint(), Err(DecodeError::InvalidVarInt));
+        assert_eq!(Reader::new(&[0x80]).read_varint(), Err(DecodeError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn test_transaction_vector() {
+        let tx = transaction();
+        let expected = format!("{}{}{}{}{}{}{}{}{}",
+                               "0220", "aa".repeat(32),
+                               "01", "11".repeat(32), "01", "0302abcd",
+                               "01141976a914", "bb".repeat(20), "88ac");
+        assert_eq!(hex::encode(tx.to_bytes()), expected);
+
+        let decoded = Transaction::from_bytes(&tx.to_bytes()).unwrap();
+        assert_eq!(decoded.to_bytes(), tx.to_bytes());
+
+        // The id is the hash of the rest, whatever it held before
+        assert_eq!(tx.contents_to_bytes(), [&[ENCODING_VERSION][..], &tx.to_bytes()[34..]].concat());
+        let mut first = tx.clone();
+        first.set_id();
+        let mut second = tx;
+        second.id = vec![];
+        second.set_id();
+        assert_eq!(first.id, second.id);
+        assert!(first.has_valid_id());
+    }
+
+    #[test]
+    fn test_header_vector() {
+        let bytes = header().to_bytes();
+        let expected = format!("{}{}{}{}{}{}{}",
+                               "02", "0100000000000000", "00105e5f00000000",
+                               "22".repeat(32), "33".repeat(32), "0000011f", "07000000");
+        assert_eq!(hex::encode(&bytes), expected);
+        assert_eq!(BlockHeader::from_bytes(&bytes).unwrap().to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_block_round_trip() {
+        let block = Block {
+            header: header(),
+            cur_block_hash: [0x44; 32],
+            seal: vec![0x55; 3],
+            transaction: vec![transaction(), transaction()],
+        };
+        let bytes = block.to_bytes();
+        let decoded = Block::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.cur_block_hash, block.cur_block_hash);
+        assert_eq!(decoded.seal, block.seal);
+        assert_eq!(decoded.transaction.len(), 2);
+        assert_eq!(decoded.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut bytes = transaction().to_bytes();
+        bytes.push(0);
+        assert_eq!(Transaction::from_bytes(&bytes).err(), Some(DecodeError::TrailingBytes(1)));
+
+        bytes.pop();
+        bytes[0] = 1;
+        assert_eq!(Transaction::from_bytes(&bytes).err(), Some(DecodeError::UnsupportedVersion(1)));
+
+        let bytes = transaction().to_bytes();
+        assert_eq!(Transaction::from_bytes(&bytes[..bytes.len() - 1]).err(), Some(DecodeError::UnexpectedEnd));
+        // A count larger than the data left
+        assert_eq!(read_vec::<TXOutput>(&mut Reader::new(&[0x05, 0x00])).err(), Some(DecodeError::UnexpectedEnd));
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use openssl::bn::BigNumContext;
+use openssl::ec::*;
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+
+use crate::block::{Block, BlockHeader};
+use crate::consensus::*;
+use crate::encoding::{Decode, Encode};
+use crate::error::{Error, Result};
+use crate::miner::Miner;
+use crate::transaction::Transaction;
+use crate::validation::BlockRejectReason;
+use crate::wallet::{Wallet, Wallets};
+
+/// Decides how blocks are sealed, which seals the chain accepts and how hard
+/// the next block is to make.
+pub trait ConsensusEngine: Send + Sync {
+    /// Target of the block following `parent`. `first` is the block
+    /// `params.interval` blocks below the new one at a retarget height.
+    fn next_bits(&self, params: &RetargetParams, parent: Option<&BlockHeader>,
+                 first: Option<&BlockHeader>) -> u32;
+
+    /// Seals `header`, which commits to `transaction`, into a block, or returns
+    /// `None` if `miner` is cancelled first.
+    fn seal(&self, header: BlockHeader, transaction: Vec<Transaction>, miner: &Miner) -> Result<Option<Block>>;
+
+    /// Checks the seal of `block`, whose hash is already known to match its header.
+    fn verify_seal(&self, block: &Block) -> std::result::Result<(), BlockRejectReason>;
+}
+
+/// Engine a chain runs, chosen when it is created and recorded in the
+/// coinbase of its genesis block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineSpec {
+    ProofOfWork,
+    /// Blocks are signed in turn by the holders of these compressed public keys.
+    ProofOfAuthority { signers: Vec<Vec<u8>> },
+}
+
+impl EngineSpec {
+
+    /// Data for the genesis coinbase: `message`, a zero byte, then the spec.
+    pub fn genesis_data(&self, message: &str) -> Vec<u8> {
+        let mut data = message.as_bytes().to_vec();
+        data.push(0);
+        self.encode(&mut data);
+        data
+    }
+
+    /// Reads the spec recorded in `genesis`. Chains created before engines
+    /// were recorded run proof of work.
+    pub fn from_genesis(genesis: &Block) -> Result<Self> {
+        let data = match genesis.transaction.first().and_then(|tx| tx.coinbase_data()) {
+            Some(data) => data,
+            None => return Ok(EngineSpec::ProofOfWork),
+        };
+        match data.iter().position(|byte| *byte == 0) {
+            Some(end) => Ok(EngineSpec::from_bytes(&data[end + 1..])?),
+            None => Ok(EngineSpec::ProofOfWork),
+        }
+    }
+
+    /// Builds the engine. A proof-of-authority engine signs with the keys of
+    /// the local wallets, read when sealing so that a locked wallet is respected.
+    pub fn engine(&self) -> Result<Arc<dyn ConsensusEngine>> {
+        match self {
+            EngineSpec::ProofOfWork => Ok(Arc::new(ProofOfWorkEngine)),
+            EngineSpec::ProofOfAuthority { signers } => Ok(Arc::new(ProofOfAuthorityEngine::new(signers.clone()))),
+        }
+    }
+}
+
+/// Blocks whose header hash meets a target retargeted every `params.interval` blocks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProofOfWorkEngine;
+
+impl ConsensusEngine for ProofOfWorkEngine {
+
+    fn next_bits(&self, params: &RetargetParams, parent: Option<&BlockHeader>,
+                 first: Option<&BlockHeader>) -> u32 {
+        next_target_bits(params, parent, first)
+    }
+
+    fn seal(&self, header: BlockHeader, transaction: Vec<Transaction>, miner: &Miner) -> Result<Option<Block>> {
+        Ok(Block::mine(header, transaction, miner))
+    }
+
+    fn verify_seal(&self, block: &Block) -> std::result::Result<(), BlockRejectReason> {
+        if !block.seal.is_empty() {
+            return Err(BlockRejectReason::BadSeal);
+        }
+        if !block.header.is_valid_pow() {
+            return Err(BlockRejectReason::InsufficientWork {
+                bits: block.header.bits
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Blocks signed by a fixed set of signers taking turns by height, every
+/// block carrying the same work. The genesis block, which names the
+/// signers, is not signed.
+pub struct ProofOfAuthorityEngine {
+    signers: Vec<Vec<u8>>,
+    /// Private keys of signers given to the engine, by public key. Others are
+    /// looked up in the wallet file.
+    keys: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl ProofOfAuthorityEngine {
+
+    pub fn new(signers: Vec<Vec<u8>>) -> Self {
+        ProofOfAuthorityEngine {
+            signers,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Lets the engine seal the blocks in the turns of `wallet`, if it is a signer.
+    pub fn add_signing_key(&mut self, wallet: &Wallet) {
+        if self.signers.contains(&wallet.public_key) {
+            self.keys.insert(wallet.public_key(), wallet.private_key.clone());
+        }
+    }
+
+    /// Public key of the signer whose turn it is at `height`.
+    pub fn signer(&self, height: u64) -> Option<&[u8]> {
+        if self.signers.is_empty() {
+            return None;
+        }
+        Some(&self.signers[(height % self.signers.len() as u64) as usize])
+    }
+}
+
+impl ConsensusEngine for ProofOfAuthorityEngine {
+
+    fn next_bits(&self, params: &RetargetParams, _parent: Option<&BlockHeader>,
+                 _first: Option<&BlockHeader>) -> u32 {
+        params.pow_limit
+    }
+
+    fn seal(&self, mut header: BlockHeader, transaction: Vec<Transaction>, miner: &Miner) -> Result<Option<Block>> {
+        if miner.is_cancelled() {
+            return Ok(None);
+        }
+        header.nonce = 0;
+        let cur_block_hash = header.pow_hash();
+        let seal = if header.height == 0 {
+            vec![]
+        } else {
+            let signer = self.signer(header.height).unwrap_or(&[]);
+            let key = match self.keys.get(signer) {
+                Some(key) => EcKey::private_key_from_der(key)?,
+                None => {
+                    let wallets = Wallets::new()?;
+                    let key = wallets.signing_key_of(signer)?.ok_or_else(|| Error::NotSigner {
+                        height: header.height,
+                        signer: hex::encode(signer),
+                    })?;
+                    EcKey::private_key_from_der(key)?
+                },
+            };
+            EcdsaSig::sign(&cur_block_hash, &*key)?.to_der()?
+        };
+
+        Ok(Some(Block {
+            header,
+            cur_block_hash,
+            seal,
+            transaction,
+        }))
+    }
+
+    fn verify_seal(&self, block: &Block) -> std::result::Result<(), BlockRejectReason> {
+        let valid = match self.signer(block.header.height) {
+            _ if block.header.height == 0 => block.seal.is_empty(),
+            Some(signer) => verify_signature(signer, &block.cur_block_hash, &block.seal),
+            None => false,
+        };
+        if !valid {
+            return Err(BlockRejectReason::BadSeal);
+        }
+        Ok(())
+    }
+}
+
+fn verify_signature(pub_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let curve = match EcGroup::from_curve_name(Nid::SECP256K1) {
+        Ok(curve) => curve,
+        Err(_) => return false,
+    };
+    let mut ctx = match BigNumContext::new() {
+        Ok(ctx) => ctx,
+        Err(_) => return false,
+    };
+    EcPoint::from_bytes(&curve, pub_key, &mut ctx)
+        .and_then(|point| EcKey::from_public_key(&curve, &point))
+        .and_then(|key| EcdsaSig::from_der(signature)?.verify(message, &key))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_block(engine: &ProofOfAuthorityEngine, height: u64) -> Result<Option<Block>> {
+        let header = BlockHeader::new(height, 1_600_000_000, [0x11; 32], 0x1f010000, &[]);
+        engine.seal(header, vec![], &Miner::default())
+    }
+
+    #[test]
+    fn test_proof_of_authority_turns() {
+        let (alice, bob) = (Wallet::new().unwrap(), Wallet::new().unwrap());
+        let mut engine = ProofOfAuthorityEngine::new(vec![alice.public_key(), bob.public_key()]);
+        engine.add_signing_key(&alice);
+
+        let block = signed_block(&engine, 2).unwrap().unwrap();
+        assert_eq!(engine.verify_seal(&block), Ok(()));
+        match signed_block(&engine, 3) {
+            Err(Error::NotSigner { height: 3, signer }) => assert_eq!(signer, hex::encode(bob.public_key())),
+            other => panic!("unexpected seal result {:?}", other.map(|_| ())),
+        }
+
+        // Signed by alice out of her turn
+        let mut forged = block;
+        forged.header.height = 3;
+        forged.cur_block_hash = forged.header.pow_hash();
+        assert_eq!(engine.verify_seal(&forged), Err(BlockRejectReason::BadSeal));
+    }
+
+    #[test]
+    fn test_genesis_records_engine() {
+        let signer = Wallet::new().unwrap();
+        let spec = EngineSpec::ProofOfAuthority { signers: vec![signer.public_key()] };
+        let address = signer.get_address().unwrap();
+
+        let coinbase = Transaction::new_coinbase_with_data(&address, spec.genesis_data("genesis"), 10, 0).unwrap();
+        let genesis = Block::new_block(vec![coinbase], [0u8; 32], 0, 0x1f010000, 1_600_000_000);
+        assert_eq!(EngineSpec::from_genesis(&genesis).unwrap(), spec);
+
+        let coinbase = Transaction::new_coinbase_tx(&address, "genesis".to_string(), 10, 0).unwrap();
+        let genesis = Block::new_block(vec![coinbase], [0u8; 32], 0, 0x1f010000, 1_600_000_000);
+        assert_eq!(EngineSpec::from_genesis(&genesis).unwrap(), EngineSpec::ProofOfWork);
+    }
+}
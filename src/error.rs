@@ -0,0 +1,172 @@
+use std::fmt;
+
+use crate::encoding::DecodeError;
+use crate::utils::AddressError;
+use crate::validation::*;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Storage(sled::Error),
+    Io(std::io::Error),
+    Serialization(serde_json::Error),
+    /// Bytes that are not a valid encoding of a block, transaction or message.
+    Decode(DecodeError),
+    Crypto(openssl::error::ErrorStack),
+    InvalidAddress { address: String, error: AddressError },
+    InsufficientFunds { address: String, balance: i32, amount: i32 },
+    /// An amount or fee that cannot be sent.
+    InvalidAmount(String),
+    /// Coin values that add up to more than an `i32` holds.
+    ValueOverflow,
+    MissingWallet(String),
+    TransactionNotFound(String),
+    /// A transaction spends an output already spent by a pooled transaction.
+    TransactionConflict { tx_id: String, pooled: String },
+    BlockChainExists,
+    BlockChainNotFound,
+    /// The database is readable but its content is not what we wrote.
+    Corrupted(String),
+    /// A database written by a version that stores things differently.
+    IncompatibleDatabase { path: String, reason: String },
+    /// A block that does not extend the current tip.
+    OrphanBlock(String),
+    /// A peer sent more than a message may take.
+    MessageTooLarge(u64),
+    InvalidBlock(BlockValidationError),
+    /// A transaction offered on its own, to the pool, that is not valid.
+    InvalidTransaction(RejectedTransaction),
+    InvalidChain(ChainValidationError),
+    /// Mining was stopped before a block was found.
+    MiningCancelled,
+    /// No local wallet holds the key of the signer whose turn it is.
+    NotSigner { height: u64, signer: String },
+    /// A configuration file or setting that cannot be used.
+    Config(String),
+    /// The wallet is encrypted and no passphrase unlocked it.
+    WalletLocked,
+    WrongPassphrase,
+    /// A new passphrase that was refused, such as one typed differently twice.
+    BadPassphrase(String),
+    WalletEncrypted,
+    WalletNotEncrypted,
+    InvalidMnemonic(String),
+    /// A derivation path that cannot be followed.
+    InvalidDerivation(String),
+    /// The wallet already derives its keys from a seed.
+    HdWalletExists,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Storage(e) => write!(f, "storage error: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Serialization(e) => write!(f, "serialization error: {}", e),
+            Error::Decode(e) => write!(f, "decoding error: {}", e),
+            Error::Crypto(e) => write!(f, "crypto error: {}", e),
+            Error::InvalidAddress { address, error } => write!(f, "address '{}' is not valid: {}", address, error),
+            Error::InsufficientFunds { address, balance, amount } =>
+                write!(f, "the balance of '{}' is {}, not enough to send {}", address, balance, amount),
+            Error::InvalidAmount(why) => write!(f, "invalid amount: {}", why),
+            Error::ValueOverflow => write!(f, "values add up to more than {}", i32::MAX),
+            Error::MissingWallet(address) => write!(f, "no wallet found for address '{}'", address),
+            Error::TransactionNotFound(id) => write!(f, "transaction {} not found", id),
+            Error::TransactionConflict { tx_id, pooled } =>
+                write!(f, "transaction {} conflicts with pooled transaction {}", tx_id, pooled),
+            Error::BlockChainExists => write!(f, "blockchain already exists"),
+            Error::BlockChainNotFound =>
+                write!(f, "no existing blockchain found, please create one first"),
+            Error::Corrupted(what) => write!(f, "database is corrupted: {}", what),
+            Error::IncompatibleDatabase { path, reason } =>
+                write!(f, "database {} was written by an incompatible version ({}), move it away to start over",
+                       path, reason),
+            Error::OrphanBlock(hash) => write!(f, "block {} does not extend the tip", hash),
+            Error::MessageTooLarge(max) => write!(f, "message is longer than {} bytes", max),
+            Error::InvalidBlock(e) => write!(f, "{}", e),
+            Error::InvalidTransaction(rejected) =>
+                write!(f, "transaction {} is invalid: {}", rejected.tx_id, rejected.reason),
+            Error::InvalidChain(e) => write!(f, "blockchain is invalid, {}", e),
+            Error::MiningCancelled => write!(f, "mining was cancelled"),
+            Error::NotSigner { height, signer } =>
+                write!(f, "block {} must be signed by {}, whose key no wallet holds", height, signer),
+            Error::Config(what) => write!(f, "invalid configuration: {}", what),
+            Error::WalletLocked => write!(f, "the wallet is locked, its passphrase is needed"),
+            Error::WrongPassphrase => write!(f, "the wallet passphrase is incorrect"),
+            Error::BadPassphrase(why) => write!(f, "passphrase refused: {}", why),
+            Error::WalletEncrypted => write!(f, "the wallet is already encrypted"),
+            Error::WalletNotEncrypted => write!(f, "the wallet is not encrypted"),
+            Error::InvalidMnemonic(why) => write!(f, "invalid mnemonic: {}", why),
+            Error::InvalidDerivation(why) => write!(f, "invalid key derivation: {}", why),
+            Error::HdWalletExists => write!(f, "the wallet already has a seed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::Crypto(e) => Some(e),
+            Error::InvalidAddress { error, .. } => Some(error),
+            Error::InvalidBlock(e) => Some(e),
+            Error::InvalidChain(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<sled::transaction::TransactionError<()>> for Error {
+    fn from(e: sled::transaction::TransactionError<()>) -> Self {
+        match e {
+            sled::transaction::TransactionError::Storage(e) => Error::Storage(e),
+            sled::transaction::TransactionError::Abort(()) => Error::Corrupted("transaction aborted".to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        Error::Crypto(e)
+    }
+}
+
+impl From<BlockValidationError> for Error {
+    fn from(e: BlockValidationError) -> Self {
+        Error::InvalidBlock(e)
+    }
+}
+
+impl From<ChainValidationError> for Error {
+    fn from(e: ChainValidationError) -> Self {
+        Error::InvalidChain(e)
+    }
+}
@@ -0,0 +1,186 @@
+//! Hierarchical deterministic keys: a BIP39 mnemonic encodes a seed from
+//! which BIP32 derives any number of secp256k1 keys along paths such as
+//! `m/44'/0'/0'/0/5`.
+
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcPoint, PointConversionForm};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use zeroize::Zeroizing;
+
+use crate::error::{Error, Result};
+
+/// Added to an index to derive a hardened child, whose public key cannot be
+/// derived from the parent public key.
+pub const HARDENED: u32 = 1 << 31;
+/// BIP44 purpose, the first level of the paths of wallet keys.
+pub const BIP44_PURPOSE: u32 = 44;
+
+/// Word counts a mnemonic may have.
+pub const MNEMONIC_WORDS: [usize; 5] = [12, 15, 18, 21, 24];
+
+/// A private key along with the chain code needed to derive its children.
+#[derive(Clone)]
+pub struct ExtendedKey {
+    secret: Zeroizing<Vec<u8>>,
+    chain_code: Zeroizing<Vec<u8>>,
+}
+
+impl ExtendedKey {
+
+    /// The root key `m` of `seed`.
+    pub fn master(seed: &[u8]) -> Result<Self> {
+        let digest = hmac_sha512(b"Bitcoin seed", seed)?;
+        let key = ExtendedKey {
+            secret: Zeroizing::new(digest[..32].to_vec()),
+            chain_code: Zeroizing::new(digest[32..].to_vec()),
+        };
+        let group = EcGroup::from_curve_name(Nid::SECP256K1)?;
+        let mut ctx = BigNumContext::new()?;
+        let mut order = BigNum::new()?;
+        group.order(&mut order, &mut ctx)?;
+        let secret = BigNum::from_slice(&key.secret)?;
+        if secret >= order || secret.num_bits() == 0 {
+            return Err(Error::InvalidDerivation("the seed gives an invalid master key".to_string()));
+        }
+        Ok(key)
+    }
+
+    /// The child at `index`, hardened from `HARDENED` on.
+    pub fn child(&self, index: u32) -> Result<Self> {
+        let group = EcGroup::from_curve_name(Nid::SECP256K1)?;
+        let mut ctx = BigNumContext::new()?;
+        let secret = BigNum::from_slice(&self.secret)?;
+
+        let mut data = Zeroizing::new(Vec::with_capacity(37));
+        if index >= HARDENED {
+            data.push(0);
+            data.extend_from_slice(&self.secret);
+        } else {
+            data.extend_from_slice(&self.public_key()?);
+        }
+        data.extend_from_slice(&index.to_be_bytes());
+        let digest = hmac_sha512(&self.chain_code, &data)?;
+
+        let mut order = BigNum::new()?;
+        group.order(&mut order, &mut ctx)?;
+        let tweak = BigNum::from_slice(&digest[..32])?;
+        let mut child = BigNum::new()?;
+        child.mod_add(&tweak, &secret, &order, &mut ctx)?;
+        if tweak >= order || child.num_bits() == 0 {
+            return Err(Error::InvalidDerivation(format!("the key at index {} is invalid", index)));
+        }
+        Ok(ExtendedKey {
+            secret: Zeroizing::new(child.to_vec_padded(32)?),
+            chain_code: Zeroizing::new(digest[32..].to_vec()),
+        })
+    }
+
+    /// The descendant along `path`, such as `m/44'/0'/0'/0/5`, where `'` or
+    /// `h` marks a hardened index.
+    pub fn derive_path(&self, path: &str) -> Result<Self> {
+        let invalid = || Error::InvalidDerivation(format!("'{}' is not a derivation path", path));
+        let mut levels = path.split('/');
+        if levels.next() != Some("m") {
+            return Err(invalid());
+        }
+        let mut key = self.clone();
+        for level in levels {
+            let (digits, offset) = match level.strip_suffix('\'').or_else(|| level.strip_suffix('h')) {
+                Some(digits) => (digits, HARDENED),
+                None => (level, 0),
+            };
+            let index: u32 = digits.parse().ok().filter(|index| *index < HARDENED).ok_or_else(invalid)?;
+            key = key.child(index + offset)?;
+        }
+        Ok(key)
+    }
+
+    /// The 32 bytes of the private key.
+    pub fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+
+    /// Compressed public key.
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        let group = EcGroup::from_curve_name(Nid::SECP256K1)?;
+        let mut ctx = BigNumContext::new()?;
+        let secret = BigNum::from_slice(&self.secret)?;
+        let mut point = EcPoint::new(&group)?;
+        point.mul_generator2(&group, &secret, &mut ctx)?;
+        Ok(point.to_bytes(&group, PointConversionForm::COMPRESSED, &mut ctx)?)
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha512(), &key)?;
+    signer.update(data)?;
+    Ok(Zeroizing::new(signer.sign_to_vec()?))
+}
+
+/// A new random mnemonic of `words` English words.
+pub fn generate_mnemonic(words: usize) -> Result<Zeroizing<String>> {
+    if !MNEMONIC_WORDS.contains(&words) {
+        return Err(Error::InvalidMnemonic(format!("a mnemonic has 12, 15, 18, 21 or 24 words, not {}", words)));
+    }
+    let mut entropy = Zeroizing::new(vec![0u8; words / 3 * 4]);
+    openssl::rand::rand_bytes(&mut entropy)?;
+    let mnemonic = bip39::Mnemonic::from_entropy(&entropy)
+        .map_err(|e| Error::InvalidMnemonic(e.to_string()))?;
+    Ok(Zeroizing::new(mnemonic.to_string()))
+}
+
+/// The mnemonic `phrase` with its words separated by single spaces, after
+/// checking its words and checksum.
+pub fn parse_mnemonic(phrase: &str) -> Result<Zeroizing<String>> {
+    let mnemonic = bip39::Mnemonic::parse(phrase)
+        .map_err(|e| Error::InvalidMnemonic(e.to_string()))?;
+    Ok(Zeroizing::new(mnemonic.to_string()))
+}
+
+/// The seed encoded by `phrase`, without a BIP39 passphrase.
+pub fn mnemonic_seed(phrase: &str) -> Result<Zeroizing<[u8; 64]>> {
+    let mnemonic = bip39::Mnemonic::parse(phrase)
+        .map_err(|e| Error::InvalidMnemonic(e.to_string()))?;
+    Ok(Zeroizing::new(mnemonic.to_seed("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bip32_vector() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::master(&seed).unwrap();
+        assert_eq!(hex::encode(master.secret()), "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35");
+        assert_eq!(hex::encode(&*master.chain_code), "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508");
+        assert_eq!(hex::encode(master.public_key().unwrap()),
+                   "0339a36013301597daef41fbe593a02cc513d0b55527ec2df1050e2e8ff49c85c2");
+
+        let key = master.derive_path("m/0'/1").unwrap();
+        assert_eq!(hex::encode(key.secret()), "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368");
+        let key = master.derive_path("m/0h/1/2'").unwrap();
+        assert_eq!(hex::encode(key.secret()), "cbce0d719ecf7431d88e6a89fa1483e02e35092af60c042b1df2ff59fa424dca");
+
+        assert!(matches!(master.derive_path("0/1"), Err(Error::InvalidDerivation(_))));
+        assert!(matches!(master.derive_path("m/2147483648"), Err(Error::InvalidDerivation(_))));
+    }
+
+    #[test]
+    fn test_mnemonic() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        assert_eq!(hex::encode(&mnemonic_seed(phrase).unwrap()[..]),
+                   "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4");
+        assert_eq!(*parse_mnemonic(&phrase.replace(' ', "  ")).unwrap(), phrase);
+        assert!(matches!(parse_mnemonic(&phrase.replace("about", "abandon")), Err(Error::InvalidMnemonic(_))));
+
+        let generated = generate_mnemonic(24).unwrap();
+        assert_eq!(generated.split(' ').count(), 24);
+        assert!(parse_mnemonic(&generated).is_ok());
+        assert!(generate_mnemonic(13).is_err());
+    }
+}
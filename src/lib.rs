@@ -1,13 +1,73 @@
+// The crate name is not snake case and cannot be changed without renaming
+// the binary.
+#![allow(non_snake_case)]
 
+mod bech32;
 mod block;
 mod block_chain;
+mod chain_params;
 mod command;
+mod config;
 mod consensus;
+mod encoding;
+mod engine;
+mod error;
+mod hd;
+mod mempool;
+mod merkle;
+mod miner;
+mod script;
+mod server;
 mod transaction;
 mod wallet;
 mod utils;
 mod utxo;
+mod validation;
 
 pub use command::{
-    Opt, run
+    Opt, run, exit_code
+};
+pub use config::Config;
+pub use encoding::{
+    Decode, DecodeError, Encode
+};
+pub use error::{
+    Error, Result
+};
+pub use block::{
+    Block, BlockHeader
+};
+pub use consensus::{
+    HeaderHasher, ProofOfWork
+};
+pub use engine::{
+    ConsensusEngine, EngineSpec, ProofOfAuthorityEngine, ProofOfWorkEngine
+};
+pub use chain_params::{
+    chain_params, data_path, select_network, ChainParams
+};
+pub use block_chain::{
+    BlockChain, BlockIndex, ChainEvent, ChainTip, TipStatus
+};
+pub use transaction::{
+    Transaction, TXInput, TXOutput, TXOutputVec
+};
+pub use utxo::UTXOSet;
+pub use mempool::Mempool;
+pub use wallet::{
+    Wallet, Wallets
+};
+pub use validation::{
+    BlockRejectReason, BlockValidationError, ChainValidationError, RejectedTransaction, TxRejectReason
+};
+pub use utils::AddressError;
+pub use script::{
+    verify_script, Script, ScriptContext, ScriptError
+};
+pub use merkle::{
+    merkle_root, MerkleProof
+};
+pub use miner::Miner;
+pub use server::{
+    send_message, InvKind, Message, Node
 };
\ No newline at end of file
@@ -1,110 +1,114 @@
+// The crate name is not snake case, see lib.rs.
+#![allow(non_snake_case)]
 
 use structopt::StructOpt;
-use BlockChainRust::{ Opt, run };
-
-use openssl::nid::Nid;
-use openssl::bn::BigNumContext;
-use openssl::ecdsa::EcdsaSig;
-use openssl::ec::*;
-use openssl::pkey::Private;
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, BufReader};
-use std::path::Path;
-
-#[test]
-fn test_serde() {
-    use serde::{Serialize, Deserialize};
-
-    #[derive(Serialize, Deserialize, Debug)]
-    pub struct Wallet {
-        private_key: Vec<u8>,
-        public_key: Vec<u8>,
-    }
+use BlockChainRust::{ Opt, run, exit_code };
 
-    #[derive(Serialize, Deserialize, Debug)]
-    pub struct Wallets {
-        wallets: HashMap<String, Wallet>
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(exit_code(&e));
     }
+}
 
-    impl Wallets {
-        fn serialize(&self) {
-            let res = serde_json::to_value(self).unwrap();
-            println!("res={}", res);
-
-            let path = Path::new("test_serde.json");
-            if path.exists() {
-                std::fs::remove_file(path);
-            }
-
-            let file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open("test_serde.json")
-                .unwrap();
-
-            let buf_writer = BufWriter::new(file);
-            serde_json::to_writer(buf_writer, &res);
+#[cfg(test)]
+mod tests {
+    use openssl::nid::Nid;
+    use openssl::bn::BigNumContext;
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::ec::*;
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+    use std::io::{BufWriter, BufReader};
+    use std::path::Path;
+
+    #[test]
+    fn test_serde() {
+        use serde::{Serialize, Deserialize};
+
+        #[derive(Serialize, Deserialize, Debug)]
+        pub struct Wallet {
+            private_key: Vec<u8>,
+            public_key: Vec<u8>,
         }
 
-        fn load_from_file(&self) -> Self {
-            let file = OpenOptions::new().read(true).open("test_serde.json").unwrap();
-            let buf_reader = BufReader::new(file);
-            serde_json::from_reader(buf_reader).unwrap()
+        #[derive(Serialize, Deserialize, Debug)]
+        pub struct Wallets {
+            wallets: HashMap<String, Wallet>
         }
-    }
-
-    let mut map = HashMap::new();
-    map.insert("one".to_string(), Wallet{private_key:vec![1,2,3], public_key: vec![4,5,6]});
-    map.insert("two".to_string(), Wallet{private_key:vec![11,22,33], public_key:vec![44,55,66]});
-    map.insert("six".to_string(), Wallet{private_key:vec![111,222,133], public_key:vec![144,155,155]});
 
-    let test = Wallets{
-        wallets:map
-    };
+        impl Wallets {
+            fn serialize(&self) {
+                let res = serde_json::to_value(self).unwrap();
+                println!("res={}", res);
+
+                let path = Path::new("test_serde.json");
+                if path.exists() {
+                    std::fs::remove_file(path).unwrap();
+                }
+
+                let file = OpenOptions::new()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .open("test_serde.json")
+                    .unwrap();
+
+                let buf_writer = BufWriter::new(file);
+                serde_json::to_writer(buf_writer, &res).unwrap();
+            }
 
-    test.serialize();
-    let res = test.load_from_file();
-    println!("load_from_file: {:?}", res);
-}
+            fn load_from_file(&self) -> Self {
+                let file = OpenOptions::new().read(true).open("test_serde.json").unwrap();
+                let buf_reader = BufReader::new(file);
+                serde_json::from_reader(buf_reader).unwrap()
+            }
+        }
 
-#[test]
-fn test_ecdsa() {
-    let curve = EcGroup::from_curve_name(Nid::SECP256K1).unwrap();
-    let key = EcKey::generate(&*curve).unwrap();
+        let mut map = HashMap::new();
+        map.insert("one".to_string(), Wallet{private_key:vec![1,2,3], public_key: vec![4,5,6]});
+        map.insert("two".to_string(), Wallet{private_key:vec![11,22,33], public_key:vec![44,55,66]});
+        map.insert("six".to_string(), Wallet{private_key:vec![111,222,133], public_key:vec![144,155,155]});
 
-    let pub_key = key.public_key();
-    let pri_key = hex::encode(key.private_key().to_vec());
+        let test = Wallets{
+            wallets:map
+        };
 
-    let mut ctx = BigNumContext::new().unwrap();
+        test.serialize();
+        let res = test.load_from_file();
+        println!("load_from_file: {:?}", res);
+    }
 
-    let pub_bytes = pub_key.to_bytes(&*curve, PointConversionForm::COMPRESSED, &mut ctx).unwrap();
+    #[test]
+    fn test_ecdsa() {
+        let curve = EcGroup::from_curve_name(Nid::SECP256K1).unwrap();
+        let key = EcKey::generate(&curve).unwrap();
 
-    let pubkey = hex::encode(pub_bytes.clone());
+        let pub_key = key.public_key();
+        let pri_key = hex::encode(key.private_key().to_vec());
 
-    println!("private_key: {:?}", pri_key);
-    println!("public_key: {:?}", pubkey);
+        let mut ctx = BigNumContext::new().unwrap();
 
-    let data = "hello rust!";
+        let pub_bytes = pub_key.to_bytes(&curve, PointConversionForm::COMPRESSED, &mut ctx).unwrap();
 
-    let res = EcdsaSig::sign(
-        data.as_bytes(), &*key).unwrap();
+        let pubkey = hex::encode(pub_bytes.clone());
 
-    println!("Original Data: {}", data);
-    println!("signatureData: {}", hex::encode(res.to_der().unwrap()));
+        println!("private_key: {:?}", pri_key);
+        println!("public_key: {:?}", pubkey);
 
-    let pkey = EcKey::from_public_key(
-        &*curve,
-        &EcPoint::from_bytes(&*curve, &pub_bytes, &mut *ctx).unwrap()).unwrap();
-     println!("verify: {}",
-              res.verify(data.as_bytes(), &*pkey).unwrap());
+        let data = "hello rust!";
 
-    // get bytes from somewhere, i.e. this will not produce a valid key
-    let public_key: Vec<u8> = vec![];
+        let res = EcdsaSig::sign(
+            data.as_bytes(), &*key).unwrap();
 
-}
+        println!("Original Data: {}", data);
+        println!("signatureData: {}", hex::encode(res.to_der().unwrap()));
 
-fn main() {
-    let opt = Opt::from_args();
-    run(opt);
+        let pkey = EcKey::from_public_key(
+            &curve,
+            &EcPoint::from_bytes(&curve, &pub_bytes, &mut ctx).unwrap()).unwrap();
+         println!("verify: {}",
+                  res.verify(data.as_bytes(), &*pkey).unwrap());
+    }
 }
@@ -0,0 +1,276 @@
+use crate::block::Block;
+use crate::chain_params::open_database;
+use crate::encoding::{Decode, Encode};
+use crate::error::{Error, Result};
+use crate::transaction::Transaction;
+use crate::utxo::UTXOSet;
+use crate::validation::{BlockValidationError, RejectedTransaction, TxRejectReason};
+
+const MEMPOOL_DB: &str = "mempool.db";
+
+/// Signed transactions waiting to be mined, keyed by transaction id.
+pub struct Mempool {
+    db: sled::Db,
+}
+
+impl Mempool {
+
+    pub fn open() -> Result<Self> {
+        let db = open_database(MEMPOOL_DB)?;
+        Ok(Mempool {
+            db
+        })
+    }
+
+    /// Adds `tx` if it is valid against `utxo_set` and spends nothing already pooled.
+    pub fn add(&self, tx: Transaction, utxo_set: &UTXOSet) -> Result<()> {
+        let tx_id = hex::encode(&tx.id);
+        if self.db.contains_key(&tx_id)? {
+            return Ok(());
+        }
+
+        for pooled in self.transactions()? {
+            let conflict = pooled.vin.iter().any(|pooled_in| tx.vin.iter()
+                .any(|vin| vin.tx_id == pooled_in.tx_id && vin.vout == pooled_in.vout));
+            if conflict {
+                return Err(Error::TransactionConflict {
+                    tx_id,
+                    pooled: hex::encode(&pooled.id),
+                });
+            }
+        }
+        validate(&tx, utxo_set, utxo_set.block_chain().next_height()?)?;
+
+        self.db.insert(tx_id, tx.to_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn get(&self, tx_id: &[u8]) -> Result<Option<Transaction>> {
+        match self.db.get(hex::encode(tx_id))? {
+            Some(data) => Ok(Some(Transaction::from_bytes(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, tx_id: &[u8]) -> Result<bool> {
+        Ok(self.db.contains_key(hex::encode(tx_id))?)
+    }
+
+    pub fn transactions(&self) -> Result<Vec<Transaction>> {
+        let mut txs = Vec::new();
+        for kv in self.db.iter() {
+            let (_, v) = kv?;
+            txs.push(Transaction::from_bytes(&v)?);
+        }
+        Ok(txs)
+    }
+
+    /// A coinbase paying `address` the subsidy and the fees, followed by every
+    /// valid pooled transaction, to be mined on top of the tip of `utxo_set`.
+    pub fn block_transactions(&self, utxo_set: &UTXOSet, address: &str) -> Result<Vec<Transaction>> {
+        self.evict_invalid(utxo_set)?;
+        let pooled = self.transactions()?;
+        let height = utxo_set.block_chain().next_height()?;
+        let fees = utxo_set.validate_transactions(&pooled, height)?;
+
+        let mut transactions = vec![utxo_set.coinbase_tx(address, height, fees)?];
+        transactions.extend(pooled);
+        Ok(transactions)
+    }
+
+    pub fn remove(&self, tx_id: &[u8]) -> Result<()> {
+        self.db.remove(hex::encode(tx_id))?;
+        Ok(())
+    }
+
+    /// Drops the transactions mined in `block` and the ones it made invalid,
+    /// returning how many were evicted.
+    pub fn evict(&self, block: &Block, utxo_set: &UTXOSet) -> Result<usize> {
+        let mut evicted = 0;
+        for tx in &block.transaction {
+            if self.contains(&tx.id)? {
+                self.remove(&tx.id)?;
+                evicted += 1;
+            }
+        }
+        evicted += self.evict_invalid(utxo_set)?;
+        Ok(evicted)
+    }
+
+    /// Drops the pooled transactions among those `rejected` from a block,
+    /// returning how many were evicted.
+    pub fn evict_rejected(&self, rejected: &BlockValidationError) -> Result<usize> {
+        let mut evicted = 0;
+        for tx in &rejected.rejected {
+            if self.db.remove(&tx.tx_id)?.is_some() {
+                evicted += 1;
+            }
+        }
+        self.db.flush()?;
+        Ok(evicted)
+    }
+
+    /// Drops the transactions that no longer validate against `utxo_set`.
+    pub fn evict_invalid(&self, utxo_set: &UTXOSet) -> Result<usize> {
+        let height = utxo_set.block_chain().next_height()?;
+        let mut evicted = 0;
+        for tx in self.transactions()? {
+            match validate(&tx, utxo_set, height) {
+                Ok(_) => {},
+                Err(Error::InvalidTransaction(_)) => {
+                    self.remove(&tx.id)?;
+                    evicted += 1;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        self.db.flush()?;
+        Ok(evicted)
+    }
+}
+
+/// Checks `tx`, which must not be a coinbase, for a block at `height`.
+fn validate(tx: &Transaction, utxo_set: &UTXOSet, height: u64) -> Result<i32> {
+    if tx.is_coinbase() {
+        return Err(Error::InvalidTransaction(RejectedTransaction {
+            tx_id: hex::encode(&tx.id),
+            reason: TxRejectReason::CoinbaseOutsideBlock,
+        }));
+    }
+    utxo_set.validate_transactions(std::slice::from_ref(tx), height).map_err(|e| match e {
+        Error::InvalidBlock(mut e) if e.rejected.len() == 1 => Error::InvalidTransaction(e.rejected.remove(0)),
+        e => e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_chain::BlockChain;
+    use crate::chain_params::TestDir;
+    use crate::engine::EngineSpec;
+    use crate::script::Script;
+    use crate::transaction::{TXInput, TXOutput};
+    use crate::wallet::Wallet;
+
+    /// A chain of `len` blocks paying their coinbases to `wallet`, spendable at once.
+    fn new_utxo_set(wallet: &Wallet, len: usize) -> UTXOSet {
+        let address = wallet.get_address().unwrap();
+        let mut bc = BlockChain::create_blockchain(&address, &EngineSpec::ProofOfWork).unwrap();
+        bc.set_coinbase_maturity(0);
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        for _ in 1..len {
+            let height = utxo_set.block_chain().next_height().unwrap();
+            let coinbase = utxo_set.coinbase_tx(&address, height, 0).unwrap();
+            utxo_set.mine_block(vec![coinbase]).unwrap();
+        }
+        utxo_set
+    }
+
+    fn coinbase_at(utxo_set: &UTXOSet, height: u64) -> Transaction {
+        utxo_set.block_chain().get_block_by_height(height).unwrap().unwrap().transaction[0].clone()
+    }
+
+    /// A transaction from `wallet` spending output 0 of `prev` and paying `fee`.
+    fn spend(utxo_set: &UTXOSet, prev: &Transaction, wallet: &Wallet, fee: i32) -> Transaction {
+        let mut prev_id = [0u8; 32];
+        prev_id.copy_from_slice(&prev.id);
+        let to = Wallet::new().unwrap().get_address().unwrap();
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput { tx_id: prev_id, vout: 0, script_sig: Script::new() }],
+            vout: vec![TXOutput::new(prev.vout[0].value - fee, &to).unwrap()],
+        };
+        tx.set_id();
+        tx.sign(&wallet.private_key, &utxo_set.find_prev_outputs(&tx).unwrap()).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_reject_coinbase() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let utxo_set = new_utxo_set(&wallet, 1);
+        let mempool = Mempool::open().unwrap();
+
+        let coinbase = utxo_set.coinbase_tx(&wallet.get_address().unwrap(), 1, 0).unwrap();
+        match mempool.add(coinbase.clone(), &utxo_set) {
+            Err(Error::InvalidTransaction(rejected)) => assert_eq!(rejected, RejectedTransaction {
+                tx_id: hex::encode(&coinbase.id),
+                reason: TxRejectReason::CoinbaseOutsideBlock,
+            }),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(mempool.transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_conflicting_transactions() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let utxo_set = new_utxo_set(&wallet, 1);
+        let mempool = Mempool::open().unwrap();
+        let genesis = coinbase_at(&utxo_set, 0);
+
+        let first = spend(&utxo_set, &genesis, &wallet, 1);
+        let second = spend(&utxo_set, &genesis, &wallet, 2);
+        mempool.add(first.clone(), &utxo_set).unwrap();
+        // Offering the same transaction again is not a conflict
+        mempool.add(first.clone(), &utxo_set).unwrap();
+        match mempool.add(second.clone(), &utxo_set) {
+            Err(Error::TransactionConflict { tx_id, pooled }) =>
+                assert_eq!((tx_id, pooled), (hex::encode(&second.id), hex::encode(&first.id))),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(mempool.transactions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_evict_after_block() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let mut utxo_set = new_utxo_set(&wallet, 3);
+        let mempool = Mempool::open().unwrap();
+        let address = wallet.get_address().unwrap();
+
+        let mined = spend(&utxo_set, &coinbase_at(&utxo_set, 0), &wallet, 1);
+        let double_spent = spend(&utxo_set, &coinbase_at(&utxo_set, 1), &wallet, 1);
+        let kept = spend(&utxo_set, &coinbase_at(&utxo_set, 2), &wallet, 1);
+        for tx in [&mined, &double_spent, &kept] {
+            mempool.add(tx.clone(), &utxo_set).unwrap();
+        }
+
+        // A block mining one pooled transaction and spending the input of another
+        let height = utxo_set.block_chain().next_height().unwrap();
+        let coinbase = utxo_set.coinbase_tx(&address, height, 3).unwrap();
+        let other = spend(&utxo_set, &coinbase_at(&utxo_set, 1), &wallet, 2);
+        let block = utxo_set.mine_block(vec![coinbase, mined.clone(), other]).unwrap();
+
+        assert_eq!(mempool.evict(&block, &utxo_set).unwrap(), 2);
+        let ids: Vec<Vec<u8>> = mempool.transactions().unwrap().into_iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, vec![kept.id]);
+        assert_eq!(mempool.evict_invalid(&utxo_set).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_block_transactions_fees() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let mut utxo_set = new_utxo_set(&wallet, 2);
+        let mempool = Mempool::open().unwrap();
+        let miner = Wallet::new().unwrap();
+        let address = miner.get_address().unwrap();
+
+        mempool.add(spend(&utxo_set, &coinbase_at(&utxo_set, 0), &wallet, 1), &utxo_set).unwrap();
+        mempool.add(spend(&utxo_set, &coinbase_at(&utxo_set, 1), &wallet, 2), &utxo_set).unwrap();
+        let transactions = mempool.block_transactions(&utxo_set, &address).unwrap();
+        assert_eq!(transactions.len(), 3);
+        let subsidy = utxo_set.block_chain().chain_params().block_subsidy(2);
+        assert_eq!(transactions[0].vout.iter().map(|out| out.value).sum::<i32>(), subsidy + 3);
+
+        let block = utxo_set.mine_block(transactions).unwrap();
+        assert_eq!(mempool.evict(&block, &utxo_set).unwrap(), 2);
+        assert_eq!(utxo_set.balance(&miner.hash_pub_key().unwrap()).unwrap().confirmed, subsidy + 3);
+    }
+}
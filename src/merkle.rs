@@ -0,0 +1,158 @@
+use serde::{ Serialize, Deserialize };
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut data = left.to_vec();
+    data.extend_from_slice(right);
+    openssl::sha::sha256(&data)
+}
+
+fn leaf(tx_id: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    let len = tx_id.len().min(32);
+    hash[..len].copy_from_slice(&tx_id[..len]);
+    hash
+}
+
+/// Merkle root of the transaction ids, duplicating the last node of odd levels.
+pub fn merkle_root<T: AsRef<[u8]>>(tx_ids: &[T]) -> [u8; 32] {
+    merkle_tree(tx_ids).0
+}
+
+/// Whether two sibling nodes of the tree are equal. Duplicating the last
+/// node of odd levels lets such a list, say `[a, b, c, c]`, have the root of
+/// a shorter one, `[a, b, c]`, so a block committing to it is refused.
+pub fn is_mutated<T: AsRef<[u8]>>(tx_ids: &[T]) -> bool {
+    merkle_tree(tx_ids).1
+}
+
+fn merkle_tree<T: AsRef<[u8]>>(tx_ids: &[T]) -> ([u8; 32], bool) {
+    let mut level: Vec<[u8; 32]> = tx_ids.iter().map(|id| leaf(id.as_ref())).collect();
+    if level.is_empty() {
+        return ([0u8; 32], false);
+    }
+
+    let mut mutated = false;
+    while level.len() > 1 {
+        mutated |= level.chunks(2).any(|pair| pair.len() == 2 && pair[0] == pair[1]);
+        level = level.chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+    }
+    (level[0], mutated)
+}
+
+/// Proof that a transaction id is a leaf of a Merkle tree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub tx_id: Vec<u8>,
+    /// Position of the transaction in the block.
+    pub index: usize,
+    /// Sibling hashes from the leaf level up to the root.
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MerkleProof {
+
+    pub fn new<T: AsRef<[u8]>>(tx_ids: &[T], index: usize) -> Option<Self> {
+        if index >= tx_ids.len() {
+            return None;
+        }
+
+        let mut level: Vec<[u8; 32]> = tx_ids.iter().map(|id| leaf(id.as_ref())).collect();
+        let mut siblings = Vec::new();
+        let mut pos = index;
+        while level.len() > 1 {
+            let sibling = if pos.is_multiple_of(2) {
+                *level.get(pos + 1).unwrap_or(&level[pos])
+            } else {
+                level[pos - 1]
+            };
+            siblings.push(sibling);
+
+            level = level.chunks(2)
+                .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+                .collect();
+            pos /= 2;
+        }
+
+        Some(MerkleProof {
+            tx_id: tx_ids[index].as_ref().to_vec(),
+            index,
+            siblings,
+        })
+    }
+
+    pub fn verify(&self, merkle_root: &[u8; 32]) -> bool {
+        let mut hash = leaf(&self.tx_id);
+        let mut pos = self.index;
+        for sibling in &self.siblings {
+            hash = if pos.is_multiple_of(2) {
+                hash_pair(&hash, sibling)
+            } else {
+                hash_pair(sibling, &hash)
+            };
+            pos /= 2;
+        }
+        pos == 0 && hash == *merkle_root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(n: u8) -> Vec<Vec<u8>> {
+        (0..n).map(|i| openssl::sha::sha256(&[i]).to_vec()).collect()
+    }
+
+    #[test]
+    fn test_merkle_root_single_and_pair() {
+        let ids = ids(2);
+        assert_eq!(merkle_root(&ids[..1]), leaf(&ids[0]));
+        assert_eq!(merkle_root(&ids), hash_pair(&leaf(&ids[0]), &leaf(&ids[1])));
+    }
+
+    #[test]
+    fn test_merkle_proof_every_leaf() {
+        for n in 1..10 {
+            let ids = ids(n);
+            let root = merkle_root(&ids);
+            for index in 0..ids.len() {
+                let proof = MerkleProof::new(&ids, index).unwrap();
+                assert!(proof.verify(&root));
+            }
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_rejects_tampering() {
+        let ids = ids(5);
+        let root = merkle_root(&ids);
+        let mut proof = MerkleProof::new(&ids, 3).unwrap();
+        proof.index = 2;
+        assert!(!proof.verify(&root));
+
+        let mut proof = MerkleProof::new(&ids, 3).unwrap();
+        proof.tx_id = ids[4].clone();
+        assert!(!proof.verify(&root));
+        assert!(MerkleProof::new(&ids, 5).is_none());
+    }
+
+    #[test]
+    fn test_duplicated_last_leaf() {
+        let ids = ids(3);
+        let mut duplicated = ids.clone();
+        duplicated.push(ids[2].clone());
+        assert_eq!(merkle_root(&duplicated), merkle_root(&ids));
+        assert!(is_mutated(&duplicated));
+        assert!(!is_mutated(&ids));
+
+        // The same one level up: [a, b, c, d, e, f] against [a, b, c, d, e, f, e, f]
+        let ids = self::ids(6);
+        let mut duplicated = ids.clone();
+        duplicated.extend_from_slice(&ids[4..]);
+        assert_eq!(merkle_root(&duplicated), merkle_root(&ids));
+        assert!(is_mutated(&duplicated));
+        assert!(!is_mutated(&ids));
+    }
+}
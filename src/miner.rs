@@ -0,0 +1,202 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::block::{roll_extra_nonce, BlockHeader};
+use crate::consensus::HeaderHasher;
+use crate::transaction::Transaction;
+
+/// Nonces a worker tries between two looks at the cancellation flag.
+const NONCE_BATCH: u32 = 1 << 14;
+
+/// Searches for proof of work on several threads at once.
+///
+/// Clones share their state, so a clone handed to another thread can
+/// `cancel` the search or read its hashrate while it runs.
+#[derive(Clone)]
+pub struct Miner {
+    threads: usize,
+    cancelled: Arc<AtomicBool>,
+    hashes: Arc<AtomicU64>,
+    hashrate: Arc<AtomicU64>,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        Miner::new(1)
+    }
+}
+
+impl Miner {
+
+    /// A miner splitting the nonce space across `threads` workers, at least one.
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            threads: threads.max(1),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            hashes: Arc::new(AtomicU64::new(0)),
+            hashrate: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Stops the running search, and any later one until `reset` is called.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Hashes computed since the miner was created.
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    /// Hashes per second of the last finished search.
+    pub fn hashrate(&self) -> u64 {
+        self.hashrate.load(Ordering::Relaxed)
+    }
+
+    /// Suffix reporting the hashrate for messages, empty when the engine
+    /// sealed without hashing.
+    pub(crate) fn hashrate_note(&self) -> String {
+        match self.hashrate() {
+            0 => String::new(),
+            hashrate => format!(" at {} hashes/s", hashrate),
+        }
+    }
+
+    /// Finds a nonce for `header`, which commits to `transaction`, and returns
+    /// the hash it seals, or `None` when cancelled.
+    ///
+    /// Whenever the nonce space is exhausted the extra nonce of the coinbase is
+    /// bumped, changing the merkle root, or the time stamp when there is no coinbase.
+    pub fn mine(&self, header: &mut BlockHeader, transaction: &mut [Transaction]) -> Option<[u8; 32]> {
+        let start = Instant::now();
+        let hashes_before = self.hashes();
+        let found = loop {
+            if self.is_cancelled() {
+                break None;
+            }
+            if let Some((nonce, hash)) = self.search(header) {
+                header.nonce = nonce;
+                break Some(hash);
+            }
+            if self.is_cancelled() {
+                break None;
+            }
+            roll_extra_nonce(header, transaction);
+        };
+
+        let elapsed = start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            let rate = (self.hashes() - hashes_before) as f64 / elapsed;
+            self.hashrate.store(rate as u64, Ordering::Relaxed);
+        }
+        found
+    }
+
+    /// Splits the nonce space of `header` across the workers, stopping them all
+    /// as soon as one finds a hash meeting the target.
+    fn search(&self, header: &BlockHeader) -> Option<(u32, [u8; 32])> {
+        let hasher = HeaderHasher::new(header);
+        let span = (u32::MAX as u64 + 1) / self.threads as u64;
+        let done = AtomicBool::new(false);
+        let found = Mutex::new(None);
+
+        thread::scope(|scope| {
+            for worker in 0..self.threads as u64 {
+                let first = worker * span;
+                let last = if worker + 1 == self.threads as u64 {
+                    u32::MAX as u64
+                } else {
+                    first + span - 1
+                };
+                let mut hasher = hasher.clone();
+                let (done, found) = (&done, &found);
+                scope.spawn(move || {
+                    let mut start = first;
+                    while start <= last && !done.load(Ordering::Relaxed) && !self.is_cancelled() {
+                        let end = last.min(start + NONCE_BATCH as u64 - 1);
+                        let result = hasher.search(start as u32..=end as u32);
+                        let tried = match result {
+                            Some((nonce, _)) => nonce as u64 - start + 1,
+                            None => end - start + 1,
+                        };
+                        self.hashes.fetch_add(tried, Ordering::Relaxed);
+                        if let Some(result) = result {
+                            found.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert(result);
+                            done.store(true, Ordering::Relaxed);
+                        }
+                        start = end + 1;
+                    }
+                });
+            }
+        });
+
+        found.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::ProofOfWork;
+
+    fn header() -> BlockHeader {
+        BlockHeader {
+            height: 1,
+            time_stamp: 1_600_000_000,
+            pre_block_hash: [0x11; 32],
+            merkle_root: [0x22; 32],
+            bits: 0x1f010000,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn test_threads_find_valid_nonce() {
+        for threads in &[1, 4] {
+            let miner = Miner::new(*threads);
+            let mut header = header();
+            let hash = miner.mine(&mut header, &mut []).unwrap();
+            assert_eq!(hash, header.pow_hash());
+            assert!(header.is_valid_pow());
+            assert!(miner.hashes() > 0);
+        }
+    }
+
+    #[test]
+    fn test_cancel() {
+        let miner = Miner::new(2);
+        let mut header = header();
+        // A target no hash can meet
+        header.bits = 0x03000001;
+
+        let handle = miner.clone();
+        let canceller = thread::spawn(move || {
+            while handle.hashes() == 0 {
+                thread::yield_now();
+            }
+            handle.cancel();
+        });
+        assert_eq!(miner.mine(&mut header, &mut []), None);
+        canceller.join().unwrap();
+
+        // Stays cancelled until reset
+        assert_eq!(miner.mine(&mut header, &mut []), None);
+        miner.reset();
+        header.bits = 0x1f010000;
+        assert!(miner.mine(&mut header, &mut []).is_some());
+    }
+}
@@ -0,0 +1,492 @@
+//! Locking and unlocking scripts, and the stack machine running them.
+//!
+//! An output is locked by a script (`script_pubkey`) and the input spending
+//! it supplies another (`script_sig`) that may only push data. The unlocking
+//! script runs first, then the locking script on the same stack; the input is
+//! valid when neither fails and the top of the stack is true. The opcodes are
+//! a subset of Bitcoin's with the same byte values.
+
+use std::fmt;
+
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+use serde::{ Serialize, Deserialize };
+
+use crate::utils::{PUB_KEY_HASH_LEN, Utils};
+
+/// Pushes an empty item, which is false.
+pub const OP_0: u8 = 0x00;
+/// Pushes the next byte's count of bytes, for pushes over 75 bytes.
+pub const OP_PUSHDATA1: u8 = 0x4c;
+/// Pushes the next two bytes' count of bytes, little-endian.
+pub const OP_PUSHDATA2: u8 = 0x4d;
+/// `OP_1` to `OP_16` push the numbers 1 to 16.
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+/// Fails the script, marking an output that only carries data.
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+
+/// Pushes up to this many bytes take a single opcode, the count itself.
+const MAX_DIRECT_PUSH: usize = 75;
+const MAX_SCRIPT_LEN: usize = 10_000;
+const MAX_PUSH_LEN: usize = 520;
+const MAX_STACK_LEN: usize = 1_000;
+const MAX_MULTISIG_KEYS: usize = 20;
+/// Numbers on the stack take up to this many bytes.
+const MAX_NUMBER_LEN: usize = 5;
+
+/// Why a script failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    /// A push running past the end of the script.
+    Truncated,
+    UnknownOpcode(u8),
+    /// An unlocking script doing more than pushing data.
+    NotPushOnly,
+    /// A script, push or stack over the size limits.
+    TooLarge,
+    StackUnderflow,
+    InvalidNumber,
+    EqualVerifyFailed,
+    /// The key or signature counts of a CHECKMULTISIG are out of range.
+    InvalidMultisig,
+    /// Spent before the block at height `until`.
+    Locked { until: u64 },
+    /// The output only carries data and cannot be spent.
+    Unspendable,
+    /// The scripts ran to an empty or false stack top, e.g. on a bad signature.
+    EvalFalse,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Truncated => write!(f, "push past the end of the script"),
+            ScriptError::UnknownOpcode(op) => write!(f, "unknown opcode {:#04x}", op),
+            ScriptError::NotPushOnly => write!(f, "unlocking script does more than push data"),
+            ScriptError::TooLarge => write!(f, "script, push or stack too large"),
+            ScriptError::StackUnderflow => write!(f, "stack underflow"),
+            ScriptError::InvalidNumber => write!(f, "invalid number"),
+            ScriptError::EqualVerifyFailed => write!(f, "EQUALVERIFY failed"),
+            ScriptError::InvalidMultisig => write!(f, "invalid CHECKMULTISIG counts"),
+            ScriptError::Locked { until } => write!(f, "locked until height {}", until),
+            ScriptError::Unspendable => write!(f, "output is unspendable"),
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// A step of a script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction<'a> {
+    Push(&'a [u8]),
+    Op(u8),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Script(Vec<u8>);
+
+impl Script {
+
+    pub fn new() -> Self {
+        Script(Vec::new())
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Script(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn push_opcode(mut self, op: u8) -> Self {
+        self.0.push(op);
+        self
+    }
+
+    /// Appends the shortest push of `data`.
+    pub fn push_slice(mut self, data: &[u8]) -> Self {
+        match data.len() {
+            len if len <= MAX_DIRECT_PUSH => self.0.push(len as u8),
+            len if len <= u8::MAX as usize => self.0.extend_from_slice(&[OP_PUSHDATA1, len as u8]),
+            len => {
+                self.0.push(OP_PUSHDATA2);
+                self.0.extend_from_slice(&(len as u16).to_le_bytes());
+            },
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    /// Appends the shortest push of the number `n`.
+    pub fn push_int(self, n: i64) -> Self {
+        match n {
+            0 => self.push_opcode(OP_0),
+            1..=16 => self.push_opcode(OP_1 + n as u8 - 1),
+            n => self.push_slice(&encode_number(n)),
+        }
+    }
+
+    /// Pays to the holder of the key hashing to `pub_key_hash`: DUP HASH160
+    /// <pub_key_hash> EQUALVERIFY CHECKSIG, unlocked by <signature> <public key>.
+    pub fn pay_to_pub_key_hash(pub_key_hash: &[u8]) -> Self {
+        Script::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(pub_key_hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    /// Pays to any `required` of `pub_keys`, unlocked by their signatures in
+    /// the order of the keys.
+    pub fn multisig(required: usize, pub_keys: &[Vec<u8>]) -> Self {
+        let script = pub_keys.iter().fold(Script::new().push_int(required as i64),
+                                          |script, key| script.push_slice(key));
+        script.push_int(pub_keys.len() as i64).push_opcode(OP_CHECKMULTISIG)
+    }
+
+    /// Pays to the holder of the key hashing to `pub_key_hash` from the block
+    /// at `height` on.
+    pub fn timelock(height: u64, pub_key_hash: &[u8]) -> Self {
+        let lock = Script::new()
+            .push_int(height as i64)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP);
+        let mut script = lock.0;
+        script.extend_from_slice(Script::pay_to_pub_key_hash(pub_key_hash).as_bytes());
+        Script(script)
+    }
+
+    /// An unspendable output recording `data`.
+    pub fn data(data: &[u8]) -> Self {
+        Script::new().push_opcode(OP_RETURN).push_slice(data)
+    }
+
+    /// The public key hash paid by a plain pay-to-public-key-hash script.
+    pub fn pub_key_hash(&self) -> Option<&[u8]> {
+        let script = &self.0;
+        let is_p2pkh = script.len() == PUB_KEY_HASH_LEN + 5
+            && script[..3] == [OP_DUP, OP_HASH160, PUB_KEY_HASH_LEN as u8]
+            && script[PUB_KEY_HASH_LEN + 3..] == [OP_EQUALVERIFY, OP_CHECKSIG];
+        if is_p2pkh {
+            Some(&script[3..PUB_KEY_HASH_LEN + 3])
+        } else {
+            None
+        }
+    }
+
+    pub fn is_unspendable(&self) -> bool {
+        self.0.first() == Some(&OP_RETURN)
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions { script: &self.0, pos: 0 }
+    }
+
+    /// Whether the script only pushes data and small numbers.
+    pub fn is_push_only(&self) -> bool {
+        self.instructions().all(|instruction| match instruction {
+            Ok(Instruction::Push(_)) => true,
+            Ok(Instruction::Op(op)) => (OP_1..=OP_16).contains(&op),
+            Err(_) => false,
+        })
+    }
+
+    /// The data pushed by a script that does nothing else.
+    pub fn pushes(&self) -> Option<Vec<&[u8]>> {
+        self.instructions()
+            .map(|instruction| match instruction {
+                Ok(Instruction::Push(data)) => Some(data),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub struct Instructions<'a> {
+    script: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Instructions<'a> {
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ScriptError> {
+        let bytes = self.script.get(self.pos..self.pos + len).ok_or(ScriptError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, ScriptError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let op = *self.script.get(self.pos)?;
+        self.pos += 1;
+        let len = match op {
+            0..=0x4b => op as usize,
+            OP_PUSHDATA1 => match self.take(1) {
+                Ok(len) => len[0] as usize,
+                Err(e) => return Some(Err(e)),
+            },
+            OP_PUSHDATA2 => match self.take(2) {
+                Ok(len) => u16::from_le_bytes([len[0], len[1]]) as usize,
+                Err(e) => return Some(Err(e)),
+            },
+            op => return Some(Ok(Instruction::Op(op))),
+        };
+        let item = self.take(len).map(Instruction::Push);
+        if item.is_err() {
+            self.pos = self.script.len();
+        }
+        Some(item)
+    }
+}
+
+/// Little-endian magnitude with the sign in the top bit of the last byte, as
+/// short as possible.
+fn encode_number(n: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut magnitude = n.unsigned_abs();
+    while magnitude > 0 {
+        bytes.push((magnitude & 0xff) as u8);
+        magnitude >>= 8;
+    }
+    match bytes.last() {
+        Some(last) if last & 0x80 != 0 => bytes.push(if n < 0 { 0x80 } else { 0 }),
+        Some(_) if n < 0 => *bytes.last_mut().unwrap() |= 0x80,
+        _ => {},
+    }
+    bytes
+}
+
+fn decode_number(bytes: &[u8]) -> Result<i64, ScriptError> {
+    if bytes.len() > MAX_NUMBER_LEN {
+        return Err(ScriptError::InvalidNumber);
+    }
+    let last = match bytes.last() {
+        Some(last) => *last,
+        None => return Ok(0),
+    };
+    let magnitude = bytes.iter().rev()
+        .fold(0i64, |acc, byte| (acc << 8) | *byte as i64) & !(0x80 << (8 * (bytes.len() - 1)));
+    Ok(if last & 0x80 != 0 { -magnitude } else { magnitude })
+}
+
+fn is_true(item: &[u8]) -> bool {
+    match item.split_last() {
+        // Zero, or negative zero
+        Some((last, rest)) => rest.iter().any(|byte| *byte != 0) || (*last != 0 && *last != 0x80),
+        None => false,
+    }
+}
+
+/// What signatures and timelocks of the scripts of an input are checked against.
+pub struct ScriptContext<'a> {
+    /// Hash the signatures sign.
+    pub sighash: &'a [u8],
+    /// Height of the block spending the output. Transactions have no lock
+    /// time, so CHECKLOCKTIMEVERIFY compares with it instead.
+    pub height: u64,
+}
+
+impl<'a> ScriptContext<'a> {
+
+    fn check_signature(&self, signature: &[u8], pub_key: &[u8]) -> bool {
+        let curve = match EcGroup::from_curve_name(Nid::SECP256K1) {
+            Ok(curve) => curve,
+            Err(_) => return false,
+        };
+        let mut ctx = match BigNumContext::new() {
+            Ok(ctx) => ctx,
+            Err(_) => return false,
+        };
+        EcPoint::from_bytes(&curve, pub_key, &mut ctx)
+            .and_then(|point| EcKey::from_public_key(&curve, &point))
+            .and_then(|key| EcdsaSig::from_der(signature)?.verify(self.sighash, &key))
+            .unwrap_or(false)
+    }
+}
+
+type Stack = Vec<Vec<u8>>;
+
+fn pop(stack: &mut Stack) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::StackUnderflow)
+}
+
+fn pop_count(stack: &mut Stack, max: usize) -> Result<usize, ScriptError> {
+    let n = decode_number(&pop(stack)?)?;
+    if n < 0 || n as usize > max {
+        return Err(ScriptError::InvalidMultisig);
+    }
+    Ok(n as usize)
+}
+
+fn push_bool(stack: &mut Stack, value: bool) {
+    stack.push(if value { vec![1] } else { vec![] });
+}
+
+/// Runs `script` on `stack`.
+fn execute(script: &Script, stack: &mut Stack, context: &ScriptContext<'_>) -> Result<(), ScriptError> {
+    if script.as_bytes().len() > MAX_SCRIPT_LEN {
+        return Err(ScriptError::TooLarge);
+    }
+    for instruction in script.instructions() {
+        match instruction? {
+            Instruction::Push(data) if data.len() > MAX_PUSH_LEN => return Err(ScriptError::TooLarge),
+            Instruction::Push(data) => stack.push(data.to_vec()),
+            Instruction::Op(op) if (OP_1..=OP_16).contains(&op) => stack.push(encode_number((op - OP_1 + 1) as i64)),
+            Instruction::Op(OP_RETURN) => return Err(ScriptError::Unspendable),
+            Instruction::Op(OP_DROP) => {
+                pop(stack)?;
+            },
+            Instruction::Op(OP_DUP) => {
+                let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                stack.push(top);
+            },
+            Instruction::Op(OP_EQUALVERIFY) => {
+                if pop(stack)? != pop(stack)? {
+                    return Err(ScriptError::EqualVerifyFailed);
+                }
+            },
+            Instruction::Op(OP_HASH160) => {
+                let hash = Utils::hash_pub_key(&pop(stack)?).map_err(|_| ScriptError::EvalFalse)?;
+                stack.push(hash);
+            },
+            Instruction::Op(OP_CHECKSIG) => {
+                let pub_key = pop(stack)?;
+                let signature = pop(stack)?;
+                push_bool(stack, context.check_signature(&signature, &pub_key));
+            },
+            Instruction::Op(OP_CHECKMULTISIG) => {
+                let key_count = pop_count(stack, MAX_MULTISIG_KEYS)?;
+                let mut pub_keys = (0..key_count).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+                pub_keys.reverse();
+                let sig_count = pop_count(stack, key_count)?;
+                let mut signatures = (0..sig_count).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+                signatures.reverse();
+
+                // Each signature matches a later key than the previous one
+                let mut keys = pub_keys.iter();
+                let valid = signatures.iter()
+                    .all(|signature| keys.any(|key| context.check_signature(signature, key)));
+                push_bool(stack, valid);
+            },
+            Instruction::Op(OP_CHECKLOCKTIMEVERIFY) => {
+                let until = decode_number(stack.last().ok_or(ScriptError::StackUnderflow)?)?;
+                if until < 0 {
+                    return Err(ScriptError::InvalidNumber);
+                }
+                if until as u64 > context.height {
+                    return Err(ScriptError::Locked { until: until as u64 });
+                }
+            },
+            Instruction::Op(op) => return Err(ScriptError::UnknownOpcode(op)),
+        }
+        if stack.len() > MAX_STACK_LEN {
+            return Err(ScriptError::TooLarge);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `script_sig` unlocks `script_pubkey`.
+pub fn verify_script(script_sig: &Script, script_pubkey: &Script, context: &ScriptContext<'_>)
+    -> Result<(), ScriptError>
+{
+    if !script_sig.is_push_only() {
+        return Err(ScriptError::NotPushOnly);
+    }
+    let mut stack = Stack::new();
+    execute(script_sig, &mut stack, context)?;
+    execute(script_pubkey, &mut stack, context)?;
+    match stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbers() {
+        for (n, bytes) in &[(0i64, vec![]), (1, vec![1]), (127, vec![0x7f]), (128, vec![0x80, 0]),
+                            (-1, vec![0x81]), (-128, vec![0x80, 0x80]), (500_000, vec![0x20, 0xa1, 0x07])] {
+            assert_eq!(encode_number(*n), *bytes);
+            assert_eq!(decode_number(bytes), Ok(*n));
+        }
+        assert_eq!(decode_number(&[1; 6]), Err(ScriptError::InvalidNumber));
+        assert!(!is_true(&[0, 0x80]) && is_true(&[0, 1]));
+
+        assert_eq!(Script::new().push_int(16).as_bytes(), &[OP_16]);
+        assert_eq!(Script::new().push_int(17).as_bytes(), &[1, 17]);
+    }
+
+    #[test]
+    fn test_instructions() {
+        let data = vec![7u8; 300];
+        let script = Script::new().push_slice(&[]).push_slice(&data[..80]).push_slice(&data).push_opcode(OP_DUP);
+        assert_eq!(&script.as_bytes()[..3], &[OP_0, OP_PUSHDATA1, 80]);
+        let instructions: Vec<_> = script.instructions().collect::<Result<_, _>>().unwrap();
+        assert_eq!(instructions, vec![Instruction::Push(&[]), Instruction::Push(&data[..80]),
+                                      Instruction::Push(&data), Instruction::Op(OP_DUP)]);
+        assert_eq!(script.pushes(), None);
+
+        let truncated = Script::from_bytes(vec![OP_PUSHDATA2, 5, 0, 1]);
+        assert_eq!(truncated.instructions().collect::<Vec<_>>(), vec![Err(ScriptError::Truncated)]);
+
+        let pub_key_hash = [0xab; 20];
+        assert_eq!(Script::pay_to_pub_key_hash(&pub_key_hash).pub_key_hash(), Some(&pub_key_hash[..]));
+        assert_eq!(Script::timelock(10, &pub_key_hash).pub_key_hash(), None);
+        assert!(Script::data(b"hello").is_unspendable());
+    }
+
+    #[test]
+    fn test_execute() {
+        let context = ScriptContext { sighash: &[0; 32], height: 100 };
+        let pub_key = vec![2; 33];
+        let pub_key_hash = Utils::hash_pub_key(&pub_key).unwrap();
+
+        // Bad signature
+        let script_sig = Script::new().push_slice(&[0x30]).push_slice(&pub_key);
+        assert_eq!(verify_script(&script_sig, &Script::pay_to_pub_key_hash(&pub_key_hash), &context),
+                   Err(ScriptError::EvalFalse));
+        assert_eq!(verify_script(&script_sig, &Script::pay_to_pub_key_hash(&[0; 20]), &context),
+                   Err(ScriptError::EqualVerifyFailed));
+        assert_eq!(verify_script(&script_sig, &Script::timelock(101, &pub_key_hash), &context),
+                   Err(ScriptError::Locked { until: 101 }));
+        assert_eq!(verify_script(&script_sig, &Script::timelock(100, &pub_key_hash), &context),
+                   Err(ScriptError::EvalFalse));
+
+        assert_eq!(verify_script(&Script::new(), &Script::data(b"hello"), &context), Err(ScriptError::Unspendable));
+        assert_eq!(verify_script(&Script::new().push_opcode(OP_DUP), &Script::new().push_int(1), &context),
+                   Err(ScriptError::NotPushOnly));
+        assert_eq!(verify_script(&Script::new(), &Script::new().push_opcode(OP_DUP), &context),
+                   Err(ScriptError::StackUnderflow));
+        assert_eq!(verify_script(&Script::new(), &Script::new().push_opcode(0xff), &context),
+                   Err(ScriptError::UnknownOpcode(0xff)));
+        assert_eq!(verify_script(&Script::new().push_int(2), &Script::new().push_opcode(OP_DROP), &context),
+                   Err(ScriptError::EvalFalse));
+
+        // 0 of 2 needs no signature, 3 of 2 is invalid
+        let keys = vec![pub_key.clone(), pub_key];
+        assert_eq!(verify_script(&Script::new(), &Script::multisig(0, &keys), &context), Ok(()));
+        assert_eq!(verify_script(&Script::new(), &Script::multisig(3, &keys), &context),
+                   Err(ScriptError::InvalidMultisig));
+    }
+}
@@ -0,0 +1,668 @@
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use serde::{ Serialize, Deserialize };
+
+use crate::block::{Block, BlockHeader};
+use crate::block_chain::{BlockChain, ChainEvent};
+use crate::encoding::*;
+use crate::engine::ConsensusEngine;
+use crate::error::{Error, Result};
+use crate::mempool::Mempool;
+use crate::miner::Miner;
+use crate::transaction::Transaction;
+use crate::utxo::UTXOSet;
+
+const NODE_VERSION: u32 = 1;
+/// Largest message a peer may send, so that a peer cannot exhaust our memory.
+const MAX_MESSAGE_LEN: u64 = 32 * 1024 * 1024;
+/// Connections handled at once; further peers are turned away until one closes.
+const MAX_CONNECTIONS: usize = 64;
+/// How long a peer may take to send its message.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most block hashes in the inventory answering `GetBlocks`.
+const MAX_INV_ITEMS: usize = 500;
+/// How long a mining node waits for a chain to build on or for its turn.
+const MINING_IDLE: Duration = Duration::from_secs(1);
+
+/// Engine of the chain, and the header and transactions of the next block to seal.
+type BlockTemplate = (Arc<dyn ConsensusEngine>, BlockHeader, Vec<Transaction>);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum InvKind {
+    Block,
+    Tx,
+}
+
+/// Messages exchanged between nodes, one per TCP connection.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Message {
+    Version { addr_from: String, version: u32, best_height: Option<u64> },
+    /// Asks for the blocks after the newest `locator` hash the peer has on
+    /// its main chain, see `BlockChain::block_locator`.
+    GetBlocks { addr_from: String, locator: Vec<Vec<u8>> },
+    Inv { addr_from: String, kind: InvKind, items: Vec<Vec<u8>> },
+    GetData { addr_from: String, kind: InvKind, id: Vec<u8> },
+    Block { addr_from: String, block: Block },
+    Tx { addr_from: String, transaction: Transaction },
+}
+
+impl Encode for InvKind {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(match self {
+            InvKind::Block => 0,
+            InvKind::Tx => 1,
+        });
+    }
+}
+
+impl Decode for InvKind {
+    fn decode(reader: &mut Reader<'_>) -> std::result::Result<Self, DecodeError> {
+        match reader.read_u8()? {
+            0 => Ok(InvKind::Block),
+            1 => Ok(InvKind::Tx),
+            _ => Err(DecodeError::InvalidValue("inventory kind")),
+        }
+    }
+}
+
+/// A version byte, then a tag byte for the message type, then its fields in order.
+impl Encode for Message {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(ENCODING_VERSION);
+        match self {
+            Message::Version { addr_from, version, best_height } => {
+                buf.push(0);
+                write_string(buf, addr_from);
+                write_varint(buf, *version as u64);
+                match best_height {
+                    Some(height) => {
+                        buf.push(1);
+                        write_varint(buf, *height);
+                    },
+                    None => buf.push(0),
+                }
+            },
+            Message::GetBlocks { addr_from, locator } => {
+                buf.push(1);
+                write_string(buf, addr_from);
+                write_varint(buf, locator.len() as u64);
+                for hash in locator {
+                    write_var_bytes(buf, hash);
+                }
+            },
+            Message::Inv { addr_from, kind, items } => {
+                buf.push(2);
+                write_string(buf, addr_from);
+                kind.encode(buf);
+                write_varint(buf, items.len() as u64);
+                for item in items {
+                    write_var_bytes(buf, item);
+                }
+            },
+            Message::GetData { addr_from, kind, id } => {
+                buf.push(3);
+                write_string(buf, addr_from);
+                kind.encode(buf);
+                write_var_bytes(buf, id);
+            },
+            Message::Block { addr_from, block } => {
+                buf.push(4);
+                write_string(buf, addr_from);
+                block.encode(buf);
+            },
+            Message::Tx { addr_from, transaction } => {
+                buf.push(5);
+                write_string(buf, addr_from);
+                transaction.encode(buf);
+            },
+        }
+    }
+}
+
+impl Decode for Message {
+    fn decode(reader: &mut Reader<'_>) -> std::result::Result<Self, DecodeError> {
+        reader.read_version()?;
+        let tag = reader.read_u8()?;
+        let addr_from = reader.read_string()?;
+        match tag {
+            0 => {
+                let version = reader.read_varint()?;
+                if version > u32::MAX as u64 {
+                    return Err(DecodeError::InvalidValue("node version"));
+                }
+                let best_height = match reader.read_u8()? {
+                    0 => None,
+                    1 => Some(reader.read_varint()?),
+                    _ => return Err(DecodeError::InvalidValue("best height")),
+                };
+                Ok(Message::Version { addr_from, version: version as u32, best_height })
+            },
+            1 => Ok(Message::GetBlocks { addr_from, locator: read_items(reader)? }),
+            2 => {
+                let kind = InvKind::decode(reader)?;
+                Ok(Message::Inv { addr_from, kind, items: read_items(reader)? })
+            },
+            3 => Ok(Message::GetData {
+                addr_from,
+                kind: InvKind::decode(reader)?,
+                id: reader.read_var_bytes()?,
+            }),
+            4 => Ok(Message::Block { addr_from, block: Block::decode(reader)? }),
+            5 => Ok(Message::Tx { addr_from, transaction: Transaction::decode(reader)? }),
+            _ => Err(DecodeError::InvalidValue("message type")),
+        }
+    }
+}
+
+/// A count, then that many byte strings.
+fn read_items(reader: &mut Reader<'_>) -> std::result::Result<Vec<Vec<u8>>, DecodeError> {
+    let count = reader.read_len()?;
+    let mut items = Vec::new();
+    for _ in 0..count {
+        items.push(reader.read_var_bytes()?);
+    }
+    Ok(items)
+}
+
+/// Reads the message a peer sends before closing its side, refusing one
+/// longer than `max_len` bytes.
+fn read_message<R: Read>(reader: R, max_len: u64) -> Result<Message> {
+    let mut data = Vec::new();
+    reader.take(max_len + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > max_len {
+        return Err(Error::MessageTooLarge(max_len));
+    }
+    Ok(Message::from_bytes(&data)?)
+}
+
+pub fn send_message(addr: &str, message: &Message) -> Result<()> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&message.to_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+/// One of the `MAX_CONNECTIONS` connections handled at once, given back when dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+            (open < MAX_CONNECTIONS).then_some(open + 1)
+        }).ok()?;
+        Some(ConnectionSlot(connections.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct NodeState {
+    utxo_set: UTXOSet,
+    peers: HashSet<String>,
+    blocks_in_transit: VecDeque<Vec<u8>>,
+    /// The inventory being downloaded was full, the peer has more blocks.
+    more_blocks: bool,
+    mempool: Mempool,
+}
+
+/// A peer-to-peer node serving and syncing the local chain.
+#[derive(Clone)]
+pub struct Node {
+    addr: String,
+    state: Arc<Mutex<NodeState>>,
+    miner: Miner,
+    /// Address rewarded by the blocks this node mines, if it mines.
+    mining_address: Option<String>,
+}
+
+impl Node {
+
+    /// A node listening on `host`, which it also gives peers to reach it.
+    pub fn new(host: &str, port: u16, peers: Vec<String>) -> Result<Self> {
+        let addr = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let utxo_set = UTXOSet::new(BlockChain::load()?)?;
+        let mempool = Mempool::open()?;
+        mempool.evict_invalid(&utxo_set)?;
+        let peers = peers.into_iter().filter(|peer| *peer != addr).collect();
+
+        Ok(Node {
+            addr,
+            state: Arc::new(Mutex::new(NodeState {
+                utxo_set,
+                peers,
+                blocks_in_transit: VecDeque::new(),
+                more_blocks: false,
+                mempool,
+            })),
+            miner: Miner::default(),
+            mining_address: None,
+        })
+    }
+
+    /// Makes the node mine blocks rewarding `address` with `miner` while it runs.
+    pub fn with_miner(mut self, address: String, miner: Miner) -> Self {
+        self.mining_address = Some(address);
+        self.miner = miner;
+        self
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Listens for peers until the listener fails, after greeting the known peers.
+    pub fn run(&self) -> Result<()> {
+        let listener = TcpListener::bind(&self.addr)?;
+        info!("Node listening on {}", self.addr);
+
+        let version = self.version_message(&self.lock())?;
+        let peers: Vec<String> = self.lock().peers.iter().cloned().collect();
+        for peer in peers {
+            self.send(&peer, &version);
+        }
+
+        if let Some(address) = self.mining_address.clone() {
+            let node = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = node.mine_blocks(&address) {
+                    error!("mining stopped: {}", e);
+                }
+            });
+        }
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let slot = match ConnectionSlot::take(&connections) {
+                Some(slot) => slot,
+                None => {
+                    warn!("Refused a connection from {:?}, {} are open", stream.peer_addr().ok(), MAX_CONNECTIONS);
+                    continue;
+                },
+            };
+            let node = self.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                if let Err(e) = node.handle_connection(stream) {
+                    error!("{}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Mines blocks on top of the tip, starting over whenever the tip or the
+    /// pool changes, until an error stops it.
+    fn mine_blocks(&self, address: &str) -> Result<()> {
+        info!("Mining with {} threads to {}", self.miner.threads(), address);
+        loop {
+            let (engine, header, transactions) = match self.block_template(address)? {
+                Some(template) => template,
+                None => {
+                    thread::sleep(MINING_IDLE);
+                    continue;
+                },
+            };
+            let block = match engine.seal(header, transactions, &self.miner) {
+                Ok(Some(block)) => block,
+                Ok(None) => continue,
+                // Another signer's turn, wait for their block
+                Err(Error::NotSigner { .. }) => {
+                    thread::sleep(MINING_IDLE);
+                    continue;
+                },
+                // Our turn, but the unlock of the wallet timed out
+                Err(e @ Error::WalletLocked) => {
+                    warn!("Cannot sign the next block: {}", e);
+                    thread::sleep(MINING_IDLE);
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+
+            let mut outgoing = Vec::new();
+            {
+                let mut state = self.lock();
+                match state.utxo_set.add_block(&block) {
+                    Ok(ChainEvent::Connected { height, .. }) => {
+                        info!("Mined block {} at height {}{}",
+                              block.cur_block_hash(), height, self.miner.hashrate_note());
+                        state.mempool.evict(&block, &state.utxo_set)?;
+                        self.relay(&state, &self.addr, InvKind::Block, block.cur_block_hash.to_vec(), &mut outgoing);
+                    },
+                    Ok(_) => {},
+                    // Drop what made the template invalid and try again
+                    Err(e) => {
+                        error!("Mined block {} was rejected: {}", block.cur_block_hash(), e);
+                        if let Error::InvalidBlock(rejected) = &e {
+                            state.mempool.evict_rejected(rejected)?;
+                        }
+                        state.mempool.evict_invalid(&state.utxo_set)?;
+                        drop(state);
+                        thread::sleep(MINING_IDLE);
+                    },
+                }
+            }
+            for (addr, message) in outgoing {
+                self.send(&addr, &message);
+            }
+        }
+    }
+
+    /// The engine and the next block to seal, or `None` until there is a chain
+    /// to extend and the block would not be dated ahead of the clock.
+    ///
+    /// The miner is reset while the state is locked, so that any block or
+    /// transaction handled after the template was taken cancels it.
+    fn block_template(&self, address: &str) -> Result<Option<BlockTemplate>> {
+        let state = self.lock();
+        let chain = state.utxo_set.block_chain();
+        if chain.best_height()?.is_none() {
+            return Ok(None);
+        }
+        self.miner.reset();
+        let transactions = state.mempool.block_transactions(&state.utxo_set, address)?;
+        let header = chain.header_template(&transactions)?;
+        if header.time_stamp > chain.clock().now() {
+            return Ok(None);
+        }
+        Ok(Some((chain.engine(), header, transactions)))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, NodeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send(&self, addr: &str, message: &Message) {
+        if let Err(e) = send_message(addr, message) {
+            debug!("{} is not available: {}", addr, e);
+            self.lock().peers.remove(addr);
+        }
+    }
+
+    fn version_message(&self, state: &NodeState) -> Result<Message> {
+        Ok(Message::Version {
+            addr_from: self.addr.clone(),
+            version: NODE_VERSION,
+            best_height: state.utxo_set.block_chain().best_height()?,
+        })
+    }
+
+    /// Asks for the blocks the peer has past our main chain.
+    fn get_blocks(&self, state: &NodeState) -> Result<Message> {
+        let locator = state.utxo_set.block_chain().block_locator()?;
+        Ok(Message::GetBlocks {
+            addr_from: self.addr.clone(),
+            locator: locator.iter().map(|hash| hash.to_vec()).collect(),
+        })
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let message = read_message(stream, MAX_MESSAGE_LEN)?;
+
+        // Reply after releasing the state, peers may call back into this node.
+        let outgoing = self.handle_message(&mut self.lock(), message)?;
+        for (addr, message) in outgoing {
+            self.send(&addr, &message);
+        }
+        Ok(())
+    }
+
+    fn handle_message(&self, state: &mut NodeState, message: Message) -> Result<Vec<(String, Message)>> {
+        let mut outgoing = Vec::new();
+        match message {
+            Message::Version { addr_from, version: _, best_height } => {
+                if addr_from != self.addr {
+                    state.peers.insert(addr_from.clone());
+                }
+                let my_height = state.utxo_set.block_chain().best_height()?;
+                if best_height > my_height {
+                    outgoing.push((addr_from, self.get_blocks(state)?));
+                } else if best_height < my_height {
+                    outgoing.push((addr_from, self.version_message(state)?));
+                }
+            },
+            Message::GetBlocks { addr_from, locator } => {
+                let locator: Vec<[u8; 32]> = locator.iter()
+                    .filter_map(|hash| hash.as_slice().try_into().ok())
+                    .collect();
+                let items = state.utxo_set.block_chain().hashes_after(&locator, MAX_INV_ITEMS)?
+                    .iter().map(|hash| hash.to_vec()).collect();
+                outgoing.push((addr_from, Message::Inv {
+                    addr_from: self.addr.clone(),
+                    kind: InvKind::Block,
+                    items,
+                }));
+            },
+            Message::Inv { addr_from, kind: InvKind::Block, items } => {
+                // Inventories list the oldest block first, the order to apply them in.
+                state.more_blocks = items.len() >= MAX_INV_ITEMS;
+                let mut missing = VecDeque::new();
+                for hash in items {
+                    if !state.utxo_set.block_chain().has_block(&hash)? {
+                        missing.push_back(hash);
+                    }
+                }
+                state.blocks_in_transit = missing;
+                if let Some(id) = state.blocks_in_transit.pop_front() {
+                    outgoing.push((addr_from, self.get_data(InvKind::Block, id)));
+                }
+            },
+            Message::Inv { addr_from, kind: InvKind::Tx, items } => {
+                for id in items {
+                    if !state.mempool.contains(&id)? {
+                        outgoing.push((addr_from.clone(), self.get_data(InvKind::Tx, id)));
+                    }
+                }
+            },
+            Message::GetData { addr_from, kind: InvKind::Block, id } => {
+                let block = state.utxo_set.block_chain().get_block(&id)?;
+                outgoing.push((addr_from, Message::Block { addr_from: self.addr.clone(), block }));
+            },
+            Message::GetData { addr_from, kind: InvKind::Tx, id } => {
+                if let Some(transaction) = state.mempool.get(&id)? {
+                    outgoing.push((addr_from, Message::Tx {
+                        addr_from: self.addr.clone(),
+                        transaction,
+                    }));
+                }
+            },
+            Message::Block { addr_from, block } => {
+                self.handle_block(state, addr_from, block, &mut outgoing)?;
+            },
+            Message::Tx { addr_from, transaction } => {
+                self.handle_tx(state, addr_from, transaction, &mut outgoing)?;
+            },
+        }
+        Ok(outgoing)
+    }
+
+    fn handle_block(&self, state: &mut NodeState, addr_from: String, block: Block,
+                    outgoing: &mut Vec<(String, Message)>) -> Result<()> {
+        if state.utxo_set.block_chain().has_block(&block.cur_block_hash)? {
+            return Ok(());
+        }
+
+        match state.utxo_set.add_block(&block) {
+            Ok(event) => {
+                match &event {
+                    ChainEvent::Connected { .. } => {
+                        info!("Added block {}", block.cur_block_hash());
+                        state.mempool.evict(&block, &state.utxo_set)?;
+                    },
+                    ChainEvent::SideChain { height, .. } => {
+                        info!("Stored side chain block {} at height {}", block.cur_block_hash(), height);
+                    },
+                    ChainEvent::Reorganized { disconnected, connected, .. } => {
+                        info!("Reorganized: {} blocks disconnected, {} connected, new tip {}",
+                              disconnected.len(), connected.len(), block.cur_block_hash());
+                        self.resurrect(state, disconnected)?;
+                        for hash in connected {
+                            let block = state.utxo_set.block_chain().get_block(hash)?;
+                            state.mempool.evict(&block, &state.utxo_set)?;
+                        }
+                    },
+                    ChainEvent::Known { .. } => {},
+                }
+                if let ChainEvent::Connected { .. } | ChainEvent::Reorganized { .. } = event {
+                    self.miner.cancel();
+                }
+
+                if let Some(id) = state.blocks_in_transit.pop_front() {
+                    outgoing.push((addr_from, self.get_data(InvKind::Block, id)));
+                } else if std::mem::take(&mut state.more_blocks) {
+                    outgoing.push((addr_from, self.get_blocks(state)?));
+                } else if let ChainEvent::Connected { .. } | ChainEvent::Reorganized { .. } = event {
+                    self.relay(state, &addr_from, InvKind::Block, block.cur_block_hash.to_vec(), outgoing);
+                }
+            },
+            Err(Error::OrphanBlock(_)) => {
+                state.blocks_in_transit.clear();
+                state.more_blocks = false;
+                outgoing.push((addr_from, self.get_blocks(state)?));
+            },
+            Err(e) => {
+                state.blocks_in_transit.clear();
+                state.more_blocks = false;
+                warn!("Rejected block {} from {}: {}", block.cur_block_hash(), addr_from, e);
+            },
+        }
+        Ok(())
+    }
+
+    /// Returns the transactions of blocks dropped from the main chain to the pool.
+    fn resurrect(&self, state: &NodeState, disconnected: &[[u8; 32]]) -> Result<()> {
+        for hash in disconnected {
+            let block = state.utxo_set.block_chain().get_block(hash)?;
+            for tx in block.transaction.into_iter().filter(|tx| !tx.is_coinbase()) {
+                match state.mempool.add(tx, &state.utxo_set) {
+                    Ok(()) | Err(Error::InvalidTransaction(_)) | Err(Error::TransactionConflict { .. }) => {},
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_tx(&self, state: &mut NodeState, addr_from: String, transaction: Transaction,
+                 outgoing: &mut Vec<(String, Message)>) -> Result<()> {
+        let id = hex::encode(&transaction.id);
+        if state.mempool.contains(&transaction.id)? {
+            return Ok(());
+        }
+
+        let tx_id = transaction.id.clone();
+        match state.mempool.add(transaction, &state.utxo_set) {
+            Ok(()) => {
+                info!("Added transaction {}", id);
+                self.miner.cancel();
+                self.relay(state, &addr_from, InvKind::Tx, tx_id, outgoing);
+            },
+            Err(e @ Error::InvalidTransaction(_)) | Err(e @ Error::TransactionConflict { .. }) => {
+                warn!("Rejected transaction {}: {}", id, e);
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    fn relay(&self, state: &NodeState, addr_from: &str, kind: InvKind, id: Vec<u8>,
+             outgoing: &mut Vec<(String, Message)>) {
+        for peer in state.peers.iter().filter(|peer| *peer != addr_from) {
+            outgoing.push((peer.clone(), Message::Inv {
+                addr_from: self.addr.clone(),
+                kind,
+                items: vec![id.clone()],
+            }));
+        }
+    }
+
+    fn get_data(&self, kind: InvKind, id: Vec<u8>) -> Message {
+        Message::GetData {
+            addr_from: self.addr.clone(),
+            kind,
+            id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::Wallet;
+
+    fn messages() -> Vec<Message> {
+        let addr_from = "127.0.0.1:3000".to_string();
+        let address = Wallet::new().unwrap().get_address().unwrap();
+        let transaction = Transaction::new_coinbase_tx(&address, String::new(), 10, 1).unwrap();
+        let block = Block::new_block(vec![transaction.clone()], [7u8; 32], 1, 0x207fffff, 1_600_000_000);
+        vec![
+            Message::Version { addr_from: addr_from.clone(), version: NODE_VERSION, best_height: None },
+            Message::Version { addr_from: addr_from.clone(), version: NODE_VERSION, best_height: Some(300) },
+            Message::GetBlocks { addr_from: addr_from.clone(), locator: vec![vec![1u8; 32], vec![2u8; 32]] },
+            Message::Inv { addr_from: addr_from.clone(), kind: InvKind::Block, items: vec![vec![3u8; 32]] },
+            Message::GetData { addr_from: addr_from.clone(), kind: InvKind::Tx, id: transaction.id.clone() },
+            Message::Block { addr_from: addr_from.clone(), block },
+            Message::Tx { addr_from, transaction },
+        ]
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        for message in messages() {
+            let bytes = message.to_bytes();
+            let decoded = read_message(&bytes[..], MAX_MESSAGE_LEN).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+            assert_eq!(decoded.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_read_bad_message() {
+        for message in messages() {
+            let bytes = message.to_bytes();
+            for len in 0..bytes.len() {
+                assert!(matches!(read_message(&bytes[..len], MAX_MESSAGE_LEN), Err(Error::Decode(_))),
+                        "{:?} cut to {} bytes", message, len);
+            }
+            let len = bytes.len() as u64;
+            assert!(read_message(&bytes[..], len).is_ok());
+            assert!(matches!(read_message(&bytes[..], len - 1), Err(Error::MessageTooLarge(max)) if max == len - 1));
+        }
+
+        // An item count larger than what follows is not trusted
+        let mut bytes = Message::Inv { addr_from: String::new(), kind: InvKind::Block, items: vec![] }.to_bytes();
+        bytes.pop();
+        write_varint(&mut bytes, u64::MAX);
+        assert!(matches!(read_message(&bytes[..], MAX_MESSAGE_LEN), Err(Error::Decode(DecodeError::UnexpectedEnd))));
+    }
+
+    #[test]
+    fn test_connection_slots() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let slots: Vec<ConnectionSlot> = (0..MAX_CONNECTIONS)
+            .map(|_| ConnectionSlot::take(&connections).unwrap())
+            .collect();
+        assert!(ConnectionSlot::take(&connections).is_none());
+        drop(slots);
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+        assert!(ConnectionSlot::take(&connections).is_some());
+    }
+}
@@ -3,74 +3,94 @@ use serde::{ Serialize, Deserialize };
 use openssl::bn::BigNumContext;
 use openssl::ecdsa::EcdsaSig;
 use openssl::ec::*;
-use openssl::pkey::Private;
 
+use crate::error::{Error, Result};
+use crate::script::*;
 use crate::utils::*;
-use crate::block::Block;
-use crate::block_chain::BlockChain;
+use crate::utxo::UTXOSet;
 use crate::wallet::*;
-use std::collections::HashMap;
-use openssl::nid::Nid;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     pub(crate) tx_id: [u8;32],
     pub(crate) vout: i32,
-    pub(crate) signature: Vec<u8>,
-    pub(crate) pub_key: Vec<u8>,
-}
-
-impl TXInput {
-
-    pub fn used_by_key(&self, pub_key_hash: &[u8]) -> bool {
-        let lock_hash = Utils::hash_pub_key(&self.pub_key);
-        lock_hash == pub_key_hash
-    }
+    /// Unlocks the spent output; pushes the height, extra nonce and data of a coinbase.
+    pub(crate) script_sig: Script,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
     pub(crate) value: i32,
-    pub_key_hash: Vec<u8>
+    pub(crate) script_pubkey: Script,
 }
 
 impl TXOutput {
 
-    pub fn new(value: i32, address: &str) -> Self {
+    pub fn new(value: i32, address: &str) -> Result<Self> {
         let mut out = TXOutput{
             value,
-            pub_key_hash: vec![],
+            script_pubkey: Script::new(),
         };
-        out.lock(address);
+        out.lock(address)?;
 
-        out
+        Ok(out)
     }
 
-    pub fn lock(&mut self, address: &str) {
-        let address_payload = openssl::base64::decode_block(address).unwrap();
-        let pub_key_hash = &address_payload[1..address_payload.len() - address_checksum_len];
-        self.pub_key_hash = pub_key_hash.to_vec();
+    /// Locks `value` with any script, such as a multisig, a timelock or data.
+    pub fn with_script(value: i32, script_pubkey: Script) -> Self {
+        TXOutput { value, script_pubkey }
     }
 
+    pub fn lock(&mut self, address: &str) -> Result<()> {
+        self.script_pubkey = Script::pay_to_pub_key_hash(&Utils::get_pub_key_hash(address)?);
+        Ok(())
+    }
+
+    /// Whether the output pays to the public key hash `key`.
     pub fn is_locked_with_key(&self, key: &[u8]) -> bool {
-        self.pub_key_hash == key
+        self.script_pubkey.pub_key_hash() == Some(key)
     }
 }
 
-impl ToString for TXOutput {
-    fn to_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
+impl fmt::Display for TXOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", serde_json::to_string(&self).map_err(|_| fmt::Error)?)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Outputs of one transaction, keyed by their index in `vout`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct TXOutputVec {
-    pub(crate) outputs: Vec<TXOutput>
+    pub(crate) outputs: BTreeMap<i32, TXOutput>,
+    /// Height of the block that mined the transaction, when it is a coinbase.
+    #[serde(default)]
+    pub(crate) coinbase_height: Option<u64>,
+}
+
+impl TXOutputVec {
+
+    /// Every output of `tx`.
+    pub fn new(tx: &Transaction) -> Self {
+        TXOutputVec {
+            outputs: tx.vout.iter().enumerate()
+                .map(|(idx, out)| (idx as i32, out.clone()))
+                .collect(),
+            coinbase_height: tx.coinbase_height(),
+        }
+    }
+
+    /// Whether the outputs may be spent in a block at `height`, coinbase
+    /// outputs needing `maturity` blocks on top of their own first.
+    pub fn is_mature(&self, height: u64, maturity: u64) -> bool {
+        self.coinbase_height.is_none_or(|mined| height >= mined + maturity)
+    }
 }
 
-impl ToString for TXOutputVec {
-    fn to_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
+impl fmt::Display for TXOutputVec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", serde_json::to_string(&self).map_err(|_| fmt::Error)?)
     }
 }
 
@@ -87,63 +107,129 @@ impl Transaction {
         self.vin.len() == 1 && self.vin[0].tx_id == [0u8;32] && self.vin[0].vout == -1
     }
 
-    pub fn new_coinbase_tx(to: &str, data: String) -> Self {
+    /// Pays `value`, the block subsidy plus the fees of the block at `height`, to `to`.
+    ///
+    /// A coinbase input spends nothing: its script pushes the big-endian block
+    /// height followed by the big-endian extra nonce, so that every coinbase has
+    /// its own id, then `data`.
+    pub fn new_coinbase_tx(to: &str, data: String, value: i32, height: u64) -> Result<Self> {
         let data = if data.is_empty() {
             format!("Reward to '{}'.", to)
         } else { data };
+        Transaction::new_coinbase_with_data(to, data.into_bytes(), value, height)
+    }
 
+    /// Like `new_coinbase_tx`, with arbitrary bytes as data.
+    pub fn new_coinbase_with_data(to: &str, data: Vec<u8>, value: i32, height: u64) -> Result<Self> {
         let tx_in = TXInput{
             tx_id: [0u8;32],
             vout: -1,
-            signature: vec![],
-            pub_key: data.as_bytes().to_vec()
+            script_sig: coinbase_script(height, 0, &data),
         };
 
-        let tx_out = TXOutput::new(10, to);
+        let tx_out = TXOutput::new(value, to)?;
 
         let mut tx = Transaction {
-            id: vec![0],
+            id: vec![],
             vin: vec![tx_in],
             vout: vec![tx_out]
         };
         tx.set_id();
 
-        tx
+        Ok(tx)
+    }
+
+    /// Block height a coinbase was created for.
+    pub fn coinbase_height(&self) -> Option<u64> {
+        self.coinbase_field(0)
+    }
+
+    pub fn coinbase_extra_nonce(&self) -> Option<u64> {
+        self.coinbase_field(1)
     }
 
-    pub fn new_utxo_transaction(from: &str, to: &str, amount: i32, bc: &BlockChain) -> Option<Self>
+    /// Data recorded by a coinbase after its height and extra nonce.
+    pub fn coinbase_data(&self) -> Option<&[u8]> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        self.vin[0].script_sig.pushes()?.get(1).copied()
+    }
+
+    fn coinbase_field(&self, idx: usize) -> Option<u64> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let pushes = self.vin[0].script_sig.pushes()?;
+        let fields = pushes.first().filter(|fields| fields.len() == 16)?;
+        let mut field = [0u8; 8];
+        field.copy_from_slice(&fields[idx * 8..idx * 8 + 8]);
+        Some(u64::from_be_bytes(field))
+    }
+
+    /// Changes the extra nonce of a coinbase, which gives it a new id.
+    pub fn set_extra_nonce(&mut self, extra_nonce: u64) {
+        if let Some(height) = self.coinbase_height() {
+            let data = self.coinbase_data().unwrap_or_default().to_vec();
+            self.vin[0].script_sig = coinbase_script(height, extra_nonce, &data);
+            self.set_id();
+        }
+    }
+
+    /// Sends `amount` to `to`, leaving `fee` to the miner and returning the
+    /// rest of the spent outputs to `from`, signed with the key `wallets` hold
+    /// for `from`, which must be unlocked if encrypted.
+    pub fn new_utxo_transaction(from: &str, to: &str, amount: i32, fee: i32, utxo_set: &UTXOSet,
+                                wallets: &mut Wallets) -> Result<Self>
     {
+        if amount <= 0 {
+            return Err(Error::InvalidAmount(format!("the amount must be positive, not {}", amount)));
+        }
+        if fee < 0 {
+            return Err(Error::InvalidAmount(format!("the fee must not be negative, not {}", fee)));
+        }
+        let total = amount.checked_add(fee)
+            .ok_or_else(|| Error::InvalidAmount(format!("{} and a fee of {} exceed {}", amount, fee, i32::MAX)))?;
+
         let mut inputs = Vec::<TXInput>::new();
         let mut outputs = Vec::<TXOutput>::new();
 
-        let wallets = Wallets::new();
-        let wallet = wallets.get_wallet(from).unwrap();
-        let pub_key_hash = wallet.hash_pub_key();
-
-        let (acc, valid_outputs) = bc.find_spendable_outputs(
-            &pub_key_hash, amount);
-        if acc < amount {
-            return None;
+        // Fails while the wallet is locked, before any change key is derived
+        wallets.signing_key(from)?;
+        let wallet = wallets.get_wallet(from)
+            .ok_or_else(|| Error::MissingWallet(from.to_string()))?;
+        let pub_key_hash = wallet.hash_pub_key()?;
+
+        let (acc, valid_outputs) = utxo_set.find_spendable_outputs(
+            &pub_key_hash, total)?;
+        if acc < total {
+            return Err(Error::InsufficientFunds {
+                address: from.to_string(),
+                balance: acc,
+                amount: total,
+            });
         }
 
         for (key, value) in valid_outputs {
+            let id = hex::decode(&key).ok().filter(|id| id.len() == 32)
+                .ok_or_else(|| Error::Corrupted(format!("invalid transaction id {}", key)))?;
             let mut tx_id = [0u8; 32];
-            tx_id.copy_from_slice(hex::decode(key).unwrap().as_slice());
+            tx_id.copy_from_slice(&id);
             for out in value {
                 let input = TXInput {
                     tx_id,
                     vout: out,
-                    signature: vec![],
-                    pub_key: wallet.public_key()
+                    script_sig: Script::new(),
                 };
                 inputs.push(input);
             }
         }
 
-        outputs.push(TXOutput::new(amount, to));
+        outputs.push(TXOutput::new(amount, to)?);
 
-        if acc > amount {
-            outputs.push(TXOutput::new(acc - amount, from));
+        if acc > total {
+            let change = wallets.change_address(from)?;
+            outputs.push(TXOutput::new(acc - total, &change)?);
         }
 
         let mut tx = Transaction{
@@ -151,57 +237,73 @@ impl Transaction {
             vin: inputs,
             vout: outputs,
         };
-        tx.set_id();
 
-        bc.sign_transaction(&wallet.private_key, &mut tx);
+        let prev_outputs = utxo_set.find_prev_outputs(&tx)?;
+        tx.sign(wallets.signing_key(from)?, &prev_outputs)?;
 
-        Some(tx)
+        Ok(tx)
     }
 
+    /// Hash of the transaction without its id, which is what the id should be.
     pub fn hash(&self) -> Vec<u8> {
-        let enc = serde_json::to_string(self).unwrap();
-        openssl::sha::sha256(&enc.as_bytes().to_vec()).to_vec()
+        openssl::sha::sha256(&self.contents_to_bytes()).to_vec()
+    }
+
+    /// Whether `id` is the hash of the transaction.
+    pub fn has_valid_id(&self) -> bool {
+        self.id == self.hash()
     }
 
     pub fn set_id(&mut self) {
-        let enc = serde_json::to_string(self).unwrap();
-        self.id = openssl::sha::sha256(&enc.as_bytes().to_vec()).to_vec();
+        self.id = self.hash();
     }
 
     pub fn set_hash(data: Transaction) -> Vec<u8>{
-        let enc = serde_json::to_string(&data).unwrap();
-        openssl::sha::sha256(&enc.as_bytes().to_vec()).to_vec()
+        data.hash()
     }
 
-    pub fn sign(&mut self, priv_key: &[u8], prev_txs: HashMap<String, Transaction>) {
+    /// Unlocks every input with `priv_key`, pushing a signature and the
+    /// public key as pay-to-public-key-hash outputs require, then sets the
+    /// id, which covers them.
+    pub fn sign(&mut self, priv_key: &[u8], prev_outputs: &HashMap<String, TXOutputVec>) -> Result<()> {
         if self.is_coinbase() {
-            return;
+            return Ok(());
         }
 
-        let tx_sign = self.trimmed_copy();
+        let key = EcKey::private_key_from_der(priv_key)?;
+        let mut ctx = BigNumContext::new()?;
+        let pub_key = key.public_key().to_bytes(key.group(), PointConversionForm::COMPRESSED, &mut ctx)?;
 
-        println!("tx_sign: before ::: {:?}", self);
-
-        for (idx, vin) in tx_sign.vin.iter().enumerate() {
-            let prev_tx = prev_txs.get(&hex::encode(vin.tx_id)).unwrap();
-            let mut tx = tx_sign.clone();
-            tx.vin[idx].signature = vec![];
-            tx.vin[idx].pub_key = prev_tx.vout.get(vin.vout as usize).unwrap().pub_key_hash.clone();
-            tx.set_id();
+        for idx in 0..self.vin.len() {
+            let prev_out = self.prev_output(idx, prev_outputs)?;
+            let signature = self.sign_input(idx, &prev_out.script_pubkey, priv_key)?;
+            self.vin[idx].script_sig = Script::new().push_slice(&signature).push_slice(&pub_key);
+        }
+        self.set_id();
+        Ok(())
+    }
 
-            let key = EcKey::private_key_from_der(priv_key).unwrap();
-            let sig = EcdsaSig::sign(&tx.id, &*key).unwrap();
+    /// DER signature with `priv_key` of input `idx`, spending an output
+    /// locked by `script_pubkey`, for scripts needing more than one.
+    pub fn sign_input(&self, idx: usize, script_pubkey: &Script, priv_key: &[u8]) -> Result<Vec<u8>> {
+        let key = EcKey::private_key_from_der(priv_key)?;
+        let sig = EcdsaSig::sign(&self.signature_hash(idx, script_pubkey), &key)?;
+        Ok(sig.to_der()?)
+    }
 
-            self.vin[idx].signature = sig.to_der().unwrap();
-        }
-        println!("tx_sign: before ::: {:?}", self);
+    /// What input `idx` signs: the transaction without unlocking scripts,
+    /// except `script_pubkey`, the locking script it spends, in its place.
+    pub fn signature_hash(&self, idx: usize, script_pubkey: &Script) -> Vec<u8> {
+        let mut tx = self.trimmed_copy();
+        tx.vin[idx].script_sig = script_pubkey.clone();
+        tx.hash()
     }
 
     pub fn trimmed_copy(&self) -> Self {
         let mut inputs = Vec::<TXInput>::new();
 
         self.vin.iter().for_each(|x| {
-            inputs.push(TXInput{ tx_id: x.tx_id, vout: x.vout, signature: vec![], pub_key: vec![]})
+            inputs.push(TXInput{ tx_id: x.tx_id, vout: x.vout, script_sig: Script::new() })
         });
 
 
@@ -212,29 +314,37 @@ impl Transaction {
         }
     }
 
-    pub fn verify(&self, pub_key: &[u8], prev_txs: HashMap<String, Transaction>) -> bool {
-        let tx_copy = self.trimmed_copy();
-        let curve = EcGroup::from_curve_name(Nid::SECP256K1).unwrap();
-        let mut ctx = BigNumContext::new().unwrap();
-        let pkey = EcKey::from_public_key(
-            &*curve,
-            &EcPoint::from_bytes(&*curve, pub_key, &mut *ctx).unwrap()
-        ).unwrap();
-
-        for (idx, vin) in tx_copy.vin.iter().enumerate() {
-            let prev_tx = prev_txs.get(&hex::encode(vin.tx_id)).unwrap();
+    fn prev_output<'a>(&self, idx: usize, prev_outputs: &'a HashMap<String, TXOutputVec>) -> Result<&'a TXOutput> {
+        let vin = &self.vin[idx];
+        let prev_tx_id = hex::encode(vin.tx_id);
+        prev_outputs.get(&prev_tx_id)
+            .and_then(|outs| outs.outputs.get(&vin.vout))
+            .ok_or(Error::TransactionNotFound(prev_tx_id))
+    }
 
-            let mut tx = tx_copy.clone();
-            tx.vin[idx].signature = vec![];
-            tx.vin[idx].pub_key = prev_tx.vout.get(vin.vout as usize).unwrap().clone().pub_key_hash;
-            tx.set_id();
+    /// Runs the unlocking script of input `idx` and the locking script of
+    /// `prev_out`, the output it spends, in a block at `height`.
+    pub fn verify_input(&self, idx: usize, prev_out: &TXOutput, height: u64) -> std::result::Result<(), ScriptError> {
+        let sighash = self.signature_hash(idx, &prev_out.script_pubkey);
+        let context = ScriptContext { sighash: &sighash, height };
+        verify_script(&self.vin[idx].script_sig, &prev_out.script_pubkey, &context)
+    }
 
-            let ecdsa = EcdsaSig::from_der(&vin.signature).unwrap();
-            if !ecdsa.verify(&tx.id, &*pkey).unwrap() {
-                return false
-            }
+    /// Checks that every input unlocks the output it spends, in a block at `height`.
+    pub fn verify(&self, prev_outputs: &HashMap<String, TXOutputVec>, height: u64) -> bool {
+        if self.is_coinbase() {
+            return true;
         }
-        true
+        (0..self.vin.len()).all(|idx| match self.prev_output(idx, prev_outputs) {
+            Ok(prev_out) => self.verify_input(idx, prev_out, height).is_ok(),
+            Err(_) => false,
+        })
     }
 
 }
+
+fn coinbase_script(height: u64, extra_nonce: u64, data: &[u8]) -> Script {
+    let mut fields = height.to_be_bytes().to_vec();
+    fields.extend_from_slice(&extra_nonce.to_be_bytes());
+    Script::new().push_slice(&fields).push_slice(data)
+}
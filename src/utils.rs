@@ -1,45 +1,228 @@
 
+use std::fmt;
+
+use crate::bech32;
+use crate::chain_params::{chain_params, ChainParams};
+use crate::error::{Error, Result};
+
 pub struct Utils;
 
-pub const version: u8 = 0x00;
-pub const address_checksum_len: usize = 4;
+pub const ADDRESS_CHECKSUM_LEN: usize = 4;
+pub const PUB_KEY_HASH_LEN: usize = 20;
+/// Witness version of Bech32 addresses paying to a public key hash.
+const WITNESS_VERSION: u8 = 0;
+
+/// Why a string is not an address of the running network. Positions count
+/// characters from 0.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressError {
+    /// Not shaped like any address encoding.
+    Malformed(String),
+    InvalidCharacter { position: usize, character: char },
+    /// For Bech32, `typos` locates the characters likely mistyped when there
+    /// are one or two.
+    BadChecksum { typos: Vec<usize> },
+    WrongNetwork { found: String, expected: String },
+    /// A payload of `length` bytes rather than a public key hash.
+    WrongLength { length: usize },
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Malformed(why) => write!(f, "{}", why),
+            AddressError::InvalidCharacter { position, character } =>
+                write!(f, "invalid character '{}' at position {}", character, position),
+            AddressError::BadChecksum { typos } if typos.is_empty() => write!(f, "bad checksum"),
+            AddressError::BadChecksum { typos } if typos.len() == 1 =>
+                write!(f, "bad checksum, check the character at position {}", typos[0]),
+            AddressError::BadChecksum { typos } => {
+                let positions: Vec<String> = typos.iter().map(|position| position.to_string()).collect();
+                write!(f, "bad checksum, check the characters at positions {}", positions.join(" and "))
+            },
+            AddressError::WrongNetwork { found, expected } =>
+                write!(f, "address of {} instead of {}", found, expected),
+            AddressError::WrongLength { length } =>
+                write!(f, "payload of {} bytes instead of {}", length, PUB_KEY_HASH_LEN),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
 
 impl Utils {
     pub fn check_sum(data: &[u8]) -> Vec<u8> {
         let hash1 = openssl::sha::sha256(data);
         let hash2 = openssl::sha::sha256(&hash1);
-        hash2[..address_checksum_len].to_vec()
+        hash2[..ADDRESS_CHECKSUM_LEN].to_vec()
     }
 
-    pub fn get_pub_key_hash(address: &str) -> Vec<u8> {
-        let pub_key_hash = openssl::base64::decode_block(address).unwrap();
-        pub_key_hash[1..pub_key_hash.len() - address_checksum_len].to_vec()
+    pub fn get_pub_key_hash(address: &str) -> Result<Vec<u8>> {
+        Utils::parse_address(address).map_err(|error| Error::InvalidAddress {
+            address: address.to_string(),
+            error,
+        })
     }
 
-    pub fn hash_pub_key(data: &[u8]) -> Vec<u8> {
+    /// Base58Check address of `pub_key_hash` on the running network.
+    pub fn encode_address(pub_key_hash: &[u8]) -> String {
+        let mut payload = vec![ chain_params().address_version ];
+        payload.extend_from_slice(pub_key_hash);
+        let checksum = Utils::check_sum(&payload);
+        payload.extend_from_slice(&checksum);
+        bs58::encode(payload).into_string()
+    }
+
+    /// Bech32 address of `pub_key_hash` on the running network, an
+    /// alternative to the Base58Check one.
+    pub fn encode_bech32_address(pub_key_hash: &[u8]) -> String {
+        let mut data = vec![ WITNESS_VERSION ];
+        data.extend(bech32::to_base32(pub_key_hash));
+        bech32::encode(&chain_params().bech32_hrp, &data)
+    }
+
+    /// The Base58Check form of `address`, which may be a Bech32 or a legacy
+    /// base64 one.
+    pub fn canonical_address(address: &str) -> Result<String> {
+        Ok(Utils::encode_address(&Utils::get_pub_key_hash(address)?))
+    }
+
+    pub fn hash_pub_key(data: &[u8]) -> Result<Vec<u8>> {
         let hash_sha256 = openssl::sha::sha256(data);
         let hash_ripemd160 = openssl::hash::hash(
-            openssl::hash::MessageDigest::ripemd160(), &hash_sha256).unwrap();
-        hash_ripemd160.to_vec()
+            openssl::hash::MessageDigest::ripemd160(), &hash_sha256)?;
+        Ok(hash_ripemd160.to_vec())
     }
 
-    pub fn validate_address(address: &str) -> bool {
-        if let Ok(address_payload) = openssl::base64::decode_block(address) {
-            if address_payload.len() < address_checksum_len {
-                return false;
-            }
-
-            let checksum = &address_payload[address_payload.len() - address_checksum_len..];
+    /// Checks that `address` is well formed and belongs to the running network.
+    pub fn validate_address(address: &str) -> Result<()> {
+        Utils::get_pub_key_hash(address).map(|_| ())
+    }
 
-            let mut data = vec![ address_payload[0] ];
-            let pub_key_hash = address_payload[1..address_payload.len() - address_checksum_len].to_vec();
-            data.extend_from_slice(&pub_key_hash);
+    /// Public key hash of `address`, in Base58Check, Bech32 or, as written
+    /// before both, base64.
+    fn parse_address(address: &str) -> std::result::Result<Vec<u8>, AddressError> {
+        let lowercase = address.to_ascii_lowercase();
+        let pub_key_hash = if address.ends_with('=') {
+            let payload = openssl::base64::decode_block(address)
+                .map_err(|_| AddressError::Malformed("invalid base64".to_string()))?;
+            Utils::check_payload(&payload)?
+        } else if ChainParams::presets().iter().any(|params| lowercase.starts_with(&format!("{}1", params.bech32_hrp))) {
+            Utils::parse_bech32(address)?
+        } else {
+            let payload = bs58::decode(address).into_vec().map_err(|e| match e {
+                bs58::decode::Error::InvalidCharacter { character, index } =>
+                    AddressError::InvalidCharacter { position: index, character },
+                e => AddressError::Malformed(e.to_string()),
+            })?;
+            Utils::check_payload(&payload)?
+        };
+        if pub_key_hash.len() != PUB_KEY_HASH_LEN {
+            return Err(AddressError::WrongLength { length: pub_key_hash.len() });
+        }
+        Ok(pub_key_hash)
+    }
 
-            let target_checksum = Utils::check_sum(&data);
+    /// The hash in `payload`, a version byte of the running network, the
+    /// hash and its checksum.
+    fn check_payload(payload: &[u8]) -> std::result::Result<Vec<u8>, AddressError> {
+        if payload.len() < ADDRESS_CHECKSUM_LEN + 1 {
+            return Err(AddressError::WrongLength { length: payload.len().saturating_sub(ADDRESS_CHECKSUM_LEN + 1) });
+        }
+        let (data, checksum) = payload.split_at(payload.len() - ADDRESS_CHECKSUM_LEN);
+        if checksum != Utils::check_sum(data).as_slice() {
+            return Err(AddressError::BadChecksum { typos: vec![] });
+        }
+        let params = chain_params();
+        if data[0] != params.address_version {
+            return Err(Utils::wrong_network(|other| other.address_version == data[0],
+                                            &format!("version {:#04x}", data[0])));
+        }
+        Ok(data[1..].to_vec())
+    }
 
-            return checksum == target_checksum.as_slice();
+    fn parse_bech32(address: &str) -> std::result::Result<Vec<u8>, AddressError> {
+        let (hrp, data) = bech32::decode(address)?;
+        if hrp != chain_params().bech32_hrp {
+            return Err(Utils::wrong_network(|other| other.bech32_hrp == hrp, &format!("prefix '{}'", hrp)));
+        }
+        match data.split_first() {
+            Some((version, program)) if *version == WITNESS_VERSION => bech32::from_base32(program)
+                .ok_or_else(|| AddressError::Malformed("invalid padding".to_string())),
+            Some((version, _)) => Err(AddressError::Malformed(format!("unsupported witness version {}", version))),
+            None => Err(AddressError::WrongLength { length: 0 }),
         }
-        false
+    }
+
+    /// An address of another network, the first preset that `matches`, or
+    /// an unknown one described by `what`.
+    fn wrong_network<F: Fn(&ChainParams) -> bool>(matches: F, what: &str) -> AddressError {
+        let found = ChainParams::presets().into_iter().find(|params| matches(params))
+            .map_or_else(|| format!("an unknown network ({})", what), |params| params.network);
+        AddressError::WrongNetwork { found, expected: chain_params().network.clone() }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base58check_address() {
+        // Mainnet
+        let pub_key_hash = hex::decode("010966776006953d5567439e5e39f86a0d273bee").unwrap();
+        let address = Utils::encode_address(&pub_key_hash);
+        assert_eq!(address, "16UwLL9Risc3QfPqBUvKofHmBQ7wMtjvM");
+        assert_eq!(Utils::get_pub_key_hash(&address).unwrap(), pub_key_hash);
+        assert_eq!(Utils::get_pub_key_hash("1111111111111111111114oLvT2").unwrap(), vec![0u8; 20]);
+
+        // Legacy base64 of the same payload
+        let legacy = "AAEJZndgBpU9VWdDnl45+GoNJzvu1hln9g==";
+        assert!(Utils::validate_address(legacy).is_ok());
+        assert_eq!(Utils::canonical_address(legacy).unwrap(), address);
+
+        let error = |address| match Utils::validate_address(address) {
+            Err(Error::InvalidAddress { error, .. }) => error,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(error("16UwLL9Risc3QfPqBUvKofHmBQ7wMtjvN"), AddressError::BadChecksum { typos: vec![] });
+        assert_eq!(error("16UwLL9Risc3QfPqBUvKofHmBQ7wMtjv0"),
+                   AddressError::InvalidCharacter { position: 32, character: '0' });
+        assert_eq!(error("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"), AddressError::WrongNetwork {
+            found: "testnet".to_string(),
+            expected: "mainnet".to_string(),
+        });
+        assert_eq!(error("3EktnHQD7RiAE6uzMj2ZifT9YgRrkSgzQX"), AddressError::WrongNetwork {
+            found: "an unknown network (version 0x05)".to_string(),
+            expected: "mainnet".to_string(),
+        });
+        assert_eq!(error("1Wh4bh"), AddressError::WrongLength { length: 0 });
+        assert_eq!(error(""), AddressError::WrongLength { length: 0 });
+        assert_eq!(error("AAEJZndgBpU9VWdDnl45+GoNJzvu1hln9w=="), AddressError::BadChecksum { typos: vec![] });
+    }
+
+    #[test]
+    fn test_bech32_address() {
+        // Mainnet
+        let pub_key_hash = hex::decode("751e76e8199196d454941c45d1b3a323f1433bd6").unwrap();
+        let address = Utils::encode_bech32_address(&pub_key_hash);
+        assert_eq!(address, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+        assert_eq!(Utils::get_pub_key_hash(&address.to_uppercase()).unwrap(), pub_key_hash);
+        assert_eq!(Utils::canonical_address(&address).unwrap(), Utils::encode_address(&pub_key_hash));
+
+        let error = |address| match Utils::validate_address(address) {
+            Err(Error::InvalidAddress { error, .. }) => error,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(error("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5"), AddressError::BadChecksum { typos: vec![41] });
+        assert_eq!(error("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"), AddressError::WrongNetwork {
+            found: "testnet".to_string(),
+            expected: "mainnet".to_string(),
+        });
+        // P2WSH program of 32 bytes
+        assert_eq!(error("bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3"),
+                   AddressError::WrongLength { length: 32 });
+        assert!(matches!(error("bc1zw508d6qejxtdg4y5r3zarvaryvqyzf3du"), AddressError::Malformed(_)));
+        assert!(matches!(error("bc1qw508D6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"), AddressError::Malformed(_)));
+    }
+}
@@ -1,66 +1,583 @@
+use crate::block::Block;
+use crate::block_chain::{BlockChain, ChainEvent};
+use crate::chain_params::open_database;
+use crate::error::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use crate::transaction::*;
+use crate::validation::*;
+use sled::Transactional;
 
-use crate::block_chain::BlockChain;
-use std::collections::HashMap;
-use serde::export::Option::Some;
-use crate::transaction::TXOutputVec;
+const CHAINSTATE_DB: &str = "chain_state.db";
+const CHAINSTATE_META: &str = "meta";
+const CHAINSTATE_UNDO: &str = "undo";
 
-const chainstate_db: &str = "chain_state.db";
+/// Unspent outputs of one transaction before a block changed them, `None`
+/// when there were none.
+type UndoData = HashMap<String, Option<TXOutputVec>>;
 
+/// Coins of one address in the unspent outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Balance {
+    /// Everything mined on the main chain.
+    pub confirmed: i32,
+    /// Coinbase outputs that cannot be spent yet.
+    pub immature: i32,
+    pub spendable: i32,
+}
+
+/// Unspent transaction outputs of the main chain, keyed by transaction id.
 pub struct UTXOSet {
     block_chain: BlockChain,
     db: sled::Db,
+    meta: sled::Tree,
+    /// `UndoData` of every connected block, keyed by block hash.
+    undo: sled::Tree,
 }
 
 impl UTXOSet {
 
-    pub fn new() -> Option<Self> {
-        if std::path::Path::new(chainstate_db).exists() {
-            println!("ChainState already exists.");
-            return None;
+    /// Opens the chainstate next to `block_chain`, rebuilding it when it
+    /// does not match the tip of the chain.
+    pub fn new(block_chain: BlockChain) -> Result<Self> {
+        let db = open_database(CHAINSTATE_DB)?;
+        let meta = db.open_tree(CHAINSTATE_META)?;
+        let undo = db.open_tree(CHAINSTATE_UNDO)?;
+
+        let utxo_set = UTXOSet {
+            block_chain,
+            db,
+            meta,
+            undo,
+        };
+        let tip = utxo_set.meta.get("tip")?;
+        if tip.as_ref().map(|tip| tip.as_ref()) != Some(&utxo_set.block_chain.tip[..]) {
+            utxo_set.reindex()?;
         }
+        Ok(utxo_set)
+    }
+
+    pub fn block_chain(&self) -> &BlockChain {
+        &self.block_chain
+    }
 
-        let db = sled::open(chainstate_db).unwrap();
-        let block_chain = BlockChain::new_block_chain()?;
+    pub fn reindex(&self) -> Result<()> {
+        let utxo = self.block_chain.find_all_utxo()?;
 
-        Some(UTXOSet {
-            block_chain,
-            db
+        self.db.clear()?;
+        for (txid, outs) in utxo {
+            self.db.insert(txid, &outs.to_string()[..])?;
+        }
+        self.meta.insert("tip", &self.block_chain.tip)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Validates `transactions` against the set, mines them into a block
+    /// and applies it.
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        self.validate_block_transactions(&transactions, self.block_chain.next_height()?)?;
+
+        let block = self.block_chain.push_block(transactions)?;
+        self.update(&block)?;
+        Ok(block)
+    }
+
+    /// Validates a block received from a peer and stores it, switching the main
+    /// chain to its branch when that branch has the most work.
+    pub fn add_block(&mut self, block: &Block) -> Result<ChainEvent> {
+        let hash = block.cur_block_hash;
+        if self.block_chain.has_block(&hash)? {
+            return Ok(ChainEvent::Known { hash });
+        }
+        self.block_chain.check_block(block)?;
+
+        if block.header.pre_block_hash == self.block_chain.tip {
+            self.validate_block_transactions(&block.transaction, block.header.height)?;
+            self.block_chain.store_block(block)?;
+            self.update(block)?;
+            return Ok(ChainEvent::Connected { hash, height: block.header.height });
+        }
+
+        let index = self.block_chain.insert_block(block)?;
+        if index.chain_work() <= self.block_chain.chain_work()? {
+            return Ok(ChainEvent::SideChain { hash, height: index.height });
+        }
+        self.reorganize(&hash)
+    }
+
+    /// Makes the stored branch ending at `tip` the main chain. If one of its
+    /// blocks does not connect, it is marked invalid and the old chain restored.
+    fn reorganize(&mut self, tip: &[u8; 32]) -> Result<ChainEvent> {
+        let branch = self.block_chain.branch(tip)?;
+        let fork = branch.first().map_or(*tip, |block| block.header.pre_block_hash);
+        let fork_height = self.block_chain.block_index(&fork)?.map(|index| index.height);
+
+        let mut disconnected = Vec::new();
+        while self.block_chain.tip != fork {
+            disconnected.push(self.disconnect_tip()?);
+        }
+
+        let mut connected = Vec::new();
+        for block in &branch {
+            if let Err(e) = self.connect_block(block) {
+                if let Error::InvalidBlock(_) = e {
+                    self.block_chain.mark_invalid(&block.cur_block_hash)?;
+                }
+                while self.block_chain.tip != fork {
+                    self.disconnect_tip()?;
+                }
+                for block in disconnected.iter().rev() {
+                    self.connect_block(block)?;
+                }
+                return Err(e);
+            }
+            connected.push(block.cur_block_hash);
+        }
+
+        Ok(ChainEvent::Reorganized {
+            fork_height,
+            disconnected: disconnected.iter().map(|block| block.cur_block_hash).collect(),
+            connected,
         })
     }
 
-    pub fn reindex(&self) {
-        let utxo = self.block_chain.find_all_utxo();
+    /// Validates the transactions of the stored `block`, a child of the tip,
+    /// and connects it.
+    fn connect_block(&mut self, block: &Block) -> Result<()> {
+        self.validate_block_transactions(&block.transaction, block.header.height)?;
+        self.block_chain.connect_block(block)?;
+        self.update(block)
+    }
+
+    /// Moves the tip back to its parent, restoring the outputs the tip block spent.
+    fn disconnect_tip(&mut self) -> Result<Block> {
+        let hash = self.block_chain.tip;
+        let undo: Option<UndoData> = match self.undo.get(hash)? {
+            Some(data) => Some(serde_json::from_slice(&data)?),
+            None => None,
+        };
+        let block = self.block_chain.disconnect_tip()?;
 
-        for (txid, outs) in utxo {
-            self.db.insert(txid, &outs.to_string()[..]);
+        // Chainstates built by a reindex have no undo data for older blocks.
+        let undo = match undo {
+            Some(undo) => undo,
+            None => {
+                self.reindex()?;
+                return Ok(block);
+            },
+        };
+
+        let utxo_tree: &sled::Tree = &self.db;
+        let res: sled::transaction::TransactionResult<()> = (utxo_tree, &self.meta, &self.undo)
+            .transaction(|(utxo, meta, undo_tree)| {
+                for (tx_id, outs) in &undo {
+                    match outs {
+                        Some(outs) => { utxo.insert(tx_id.as_bytes(), outs.to_string().as_bytes())?; },
+                        None => { utxo.remove(tx_id.as_bytes())?; },
+                    }
+                }
+                meta.insert("tip", &block.header.pre_block_hash[..])?;
+                undo_tree.remove(&hash[..])?;
+                Ok(())
+            });
+        res?;
+        self.db.flush()?;
+        Ok(block)
+    }
+
+    /// Checks `transactions`, to be mined at `height`, against the set and
+    /// returns the fees they pay.
+    pub fn validate_transactions(&self, transactions: &[Transaction], height: u64) -> Result<i32> {
+        let mut utxo = HashMap::<String, TXOutputVec>::new();
+        let mut known_txs = HashSet::<[u8; 32]>::new();
+        // Outputs still unspent under the id of a new transaction make it a duplicate.
+        for tx in transactions {
+            let tx_id = hex::encode(&tx.id);
+            if let Some(outs) = self.get(&tx_id)? {
+                utxo.insert(tx_id, outs);
+            }
         }
+        for vin in transactions.iter().flat_map(|tx| tx.vin.iter()) {
+            let tx_id = hex::encode(vin.tx_id);
+            if utxo.contains_key(&tx_id) {
+                continue;
+            }
+            if let Some(outs) = self.get(&tx_id)? {
+                utxo.insert(tx_id, outs);
+            } else if self.block_chain.find_transaction(&vin.tx_id)?.is_some() {
+                known_txs.insert(vin.tx_id);
+            }
+        }
+        Ok(check_transactions(transactions, &utxo, height, self.block_chain.coinbase_maturity(),
+                              |tx_id| known_txs.contains(tx_id))?)
+    }
+
+    /// Coinbase of a block at `height` paying `address` the subsidy and `fees`.
+    pub fn coinbase_tx(&self, address: &str, height: u64, fees: i32) -> Result<Transaction> {
+        let reward = self.block_chain.chain_params().block_subsidy(height)
+            .checked_add(fees)
+            .ok_or(Error::ValueOverflow)?;
+        Transaction::new_coinbase_tx(address, String::new(), reward, height)
     }
 
-    pub fn find_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32) -> (i32, HashMap::<String,Vec<i32>>) {
+    /// Checks the transactions of a block at `height`, coinbase value included.
+    pub fn validate_block_transactions(&self, transactions: &[Transaction], height: u64) -> Result<()> {
+        let fees = self.validate_transactions(transactions, height)?;
+        check_coinbase(transactions, height, self.block_chain.chain_params().block_subsidy(height), fees)?;
+        Ok(())
+    }
+
+    /// Removes the outputs spent by `block` and adds the ones it creates,
+    /// in a single atomic write along with the data needed to undo it.
+    pub fn update(&self, block: &Block) -> Result<()> {
+        let mut changes = HashMap::<String, TXOutputVec>::new();
+        for tx in &block.transaction {
+            for vin in tx.vin.iter().filter(|_| !tx.is_coinbase()) {
+                let tx_id = hex::encode(vin.tx_id);
+                if !changes.contains_key(&tx_id) {
+                    let outs = self.get(&tx_id)?.unwrap_or_default();
+                    changes.insert(tx_id.clone(), outs);
+                }
+                if let Some(outs) = changes.get_mut(&tx_id) {
+                    outs.outputs.remove(&vin.vout);
+                }
+            }
+
+            changes.insert(hex::encode(&tx.id), TXOutputVec::new(tx));
+        }
 
-        let mut acc = 0;
+        let mut undo = UndoData::new();
+        for tx_id in changes.keys() {
+            undo.insert(tx_id.clone(), self.get(tx_id)?);
+        }
+        let undo = serde_json::to_vec(&undo)?;
+
+        let changes: Vec<(String, Option<String>)> = changes.into_iter()
+            .map(|(tx_id, outs)| {
+                let value = if outs.outputs.is_empty() { None } else { Some(outs.to_string()) };
+                (tx_id, value)
+            })
+            .collect();
+
+        let utxo_tree: &sled::Tree = &self.db;
+        let res: sled::transaction::TransactionResult<()> = (utxo_tree, &self.meta, &self.undo)
+            .transaction(|(utxo, meta, undo_tree)| {
+                for (tx_id, value) in &changes {
+                    match value {
+                        Some(value) => { utxo.insert(tx_id.as_bytes(), value.as_bytes())?; },
+                        None => { utxo.remove(tx_id.as_bytes())?; },
+                    }
+                }
+                meta.insert("tip", &block.cur_block_hash[..])?;
+                undo_tree.insert(&block.cur_block_hash[..], undo.clone())?;
+                Ok(())
+            });
+        res?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn get(&self, tx_id: &str) -> Result<Option<TXOutputVec>> {
+        match self.db.get(tx_id)? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>> {
+        let mut utxo = Vec::<TXOutput>::new();
+        for kv in self.db.iter() {
+            let (_, v) = kv?;
+            let outs: TXOutputVec = serde_json::from_slice(&v)?;
+            for out in outs.outputs.values() {
+                if out.is_locked_with_key(pub_key_hash) {
+                    utxo.push(out.clone());
+                }
+            }
+        }
+        Ok(utxo)
+    }
+
+    /// Confirmed, immature and spendable coins locked with `pub_key_hash`,
+    /// as of the next block.
+    pub fn balance(&self, pub_key_hash: &[u8]) -> Result<Balance> {
+        let height = self.block_chain.next_height()?;
+        let maturity = self.block_chain.coinbase_maturity();
+        let mut balance = Balance::default();
+        for kv in self.db.iter() {
+            let (_, v) = kv?;
+            let outs: TXOutputVec = serde_json::from_slice(&v)?;
+            let value = outs.outputs.values()
+                .filter(|out| out.is_locked_with_key(pub_key_hash))
+                .try_fold(0i32, |acc, out| acc.checked_add(out.value))
+                .ok_or(Error::ValueOverflow)?;
+
+            let add = |total: i32| total.checked_add(value).ok_or(Error::ValueOverflow);
+            balance.confirmed = add(balance.confirmed)?;
+            if outs.is_mature(height, maturity) {
+                balance.spendable = add(balance.spendable)?;
+            } else {
+                balance.immature = add(balance.immature)?;
+            }
+        }
+        Ok(balance)
+    }
+
+    pub fn count_transactions(&self) -> Result<usize> {
+        Ok(self.db.len())
+    }
+
+    pub fn find_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32) -> Result<(i32, HashMap::<String,Vec<i32>>)> {
+
+        let height = self.block_chain.next_height()?;
+        let maturity = self.block_chain.coinbase_maturity();
+        let mut acc = 0i32;
         let mut unspent_outputs: HashMap::<String,Vec<i32>> = HashMap::new();
 
-        let mut iter = self.db.iter();
-        while let Ok((k, v)) = iter.next().unwrap() {
-            let txid = hex::encode(k);
-            let out: TXOutputVec = serde_json::from_slice(&v).unwrap();
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            let txid = String::from_utf8_lossy(&k).to_string();
+            let out: TXOutputVec = serde_json::from_slice(&v)?;
+            if !out.is_mature(height, maturity) {
+                continue;
+            }
             let mut unspent_vec = Vec::<i32>::new();
-            for (out_idx, out) in out.outputs.iter().enumerate() {
+            for (out_idx, out) in out.outputs.iter() {
                 if out.is_locked_with_key(pub_key_hash) && acc < amount {
-                    acc += out.value;
-                    unspent_vec.push(out_idx as i32);
+                    acc = acc.checked_add(out.value).ok_or(Error::ValueOverflow)?;
+                    unspent_vec.push(*out_idx);
                     if acc >= amount {
                         break;
                     }
                 }
             }
-            unspent_outputs.insert(txid, unspent_vec);
+            if !unspent_vec.is_empty() {
+                unspent_outputs.insert(txid, unspent_vec);
+            }
             if acc >= amount {
                 break;
             }
         }
-        (acc, unspent_outputs)
+        Ok((acc, unspent_outputs))
+    }
+
+    /// Outputs spent by the inputs of `tx`, as needed for signing it.
+    pub fn find_prev_outputs(&self, tx: &Transaction) -> Result<HashMap<String, TXOutputVec>> {
+        let mut prev_outputs = HashMap::<String, TXOutputVec>::new();
+        for vin in &tx.vin {
+            let tx_id = hex::encode(vin.tx_id);
+            if let Some(outs) = self.get(&tx_id)? {
+                prev_outputs.insert(tx_id, outs);
+            }
+        }
+        Ok(prev_outputs)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_chain::TipStatus;
+    use crate::chain_params::TestDir;
+    use crate::engine::EngineSpec;
+    use crate::script::Script;
+    use crate::wallet::Wallet;
+
+    /// A chain paying its genesis reward to `wallet`, with coinbases spendable at once.
+    fn new_utxo_set(wallet: &Wallet) -> UTXOSet {
+        let mut bc = BlockChain::create_blockchain(&wallet.get_address().unwrap(), &EngineSpec::ProofOfWork).unwrap();
+        bc.set_coinbase_maturity(0);
+        UTXOSet::new(bc).unwrap()
+    }
+
+    /// Mines a block spending output 0 of `prev`, locked to `wallet`, with a fee of 1.
+    fn mine_spend(utxo_set: &mut UTXOSet, prev: &Transaction, wallet: &Wallet) -> Block {
+        let mut prev_id = [0u8; 32];
+        prev_id.copy_from_slice(&prev.id);
+        let to = Wallet::new().unwrap().get_address().unwrap();
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput { tx_id: prev_id, vout: 0, script_sig: Script::new() }],
+            vout: vec![TXOutput::new(prev.vout[0].value - 1, &to).unwrap()],
+        };
+        tx.set_id();
+        tx.sign(&wallet.private_key, &utxo_set.find_prev_outputs(&tx).unwrap()).unwrap();
+
+        let height = utxo_set.block_chain().next_height().unwrap();
+        let coinbase = utxo_set.coinbase_tx(&wallet.get_address().unwrap(), height, 1).unwrap();
+        utxo_set.mine_block(vec![coinbase, tx]).unwrap()
+    }
+
+    /// Every entry of the chainstate and the tip it was built for.
+    fn snapshot(utxo_set: &UTXOSet) -> (Vec<(sled::IVec, sled::IVec)>, Option<sled::IVec>) {
+        let entries = utxo_set.db.iter().collect::<sled::Result<_>>().unwrap();
+        (entries, utxo_set.meta.get("tip").unwrap())
+    }
+
+    fn genesis_coinbase(utxo_set: &UTXOSet) -> Transaction {
+        utxo_set.block_chain().get_block_by_height(0).unwrap().unwrap().transaction[0].clone()
+    }
+
+    #[test]
+    fn test_update_matches_reindex() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let mut utxo_set = new_utxo_set(&wallet);
+        let genesis = genesis_coinbase(&utxo_set);
+        let block = mine_spend(&mut utxo_set, &genesis, &wallet);
+        mine_spend(&mut utxo_set, &block.transaction[0], &wallet);
+
+        let updated = snapshot(&utxo_set);
+        assert!(utxo_set.get(&hex::encode(&genesis.id)).unwrap().is_none());
+        utxo_set.reindex().unwrap();
+        assert_eq!(snapshot(&utxo_set), updated);
+    }
+
+    #[test]
+    fn test_disconnect_tip_restores_spent_outputs() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let mut utxo_set = new_utxo_set(&wallet);
+        let genesis = genesis_coinbase(&utxo_set);
+        let before = snapshot(&utxo_set);
+
+        let block = mine_spend(&mut utxo_set, &genesis, &wallet);
+        assert!(utxo_set.undo.contains_key(block.cur_block_hash).unwrap());
+        assert_eq!(utxo_set.disconnect_tip().unwrap().cur_block_hash, block.cur_block_hash);
+        assert_eq!(snapshot(&utxo_set), before);
+        assert!(utxo_set.get(&hex::encode(&genesis.id)).unwrap().is_some());
+        assert!(!utxo_set.undo.contains_key(block.cur_block_hash).unwrap());
+    }
+
+    #[test]
+    fn test_reindex_on_stale_tip() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let mut utxo_set = new_utxo_set(&wallet);
+        let genesis = genesis_coinbase(&utxo_set);
+        mine_spend(&mut utxo_set, &genesis, &wallet);
+        let expected = snapshot(&utxo_set);
+
+        // A chainstate left behind by the chain is rebuilt on opening
+        utxo_set.db.clear().unwrap();
+        utxo_set.meta.insert("tip", &[0u8; 32][..]).unwrap();
+        drop(utxo_set);
+        let utxo_set = UTXOSet::new(BlockChain::new_block_chain().unwrap()).unwrap();
+        assert_eq!(snapshot(&utxo_set), expected);
+
+        // One that claims the current tip is trusted as is
+        utxo_set.db.clear().unwrap();
+        drop(utxo_set);
+        let utxo_set = UTXOSet::new(BlockChain::new_block_chain().unwrap()).unwrap();
+        assert_eq!(utxo_set.count_transactions().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_balance_overflow() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let utxo_set = new_utxo_set(&wallet);
+        let pub_key_hash = wallet.hash_pub_key().unwrap();
+        let subsidy = utxo_set.block_chain().chain_params().block_subsidy(0);
+        assert_eq!(utxo_set.balance(&pub_key_hash).unwrap().confirmed, subsidy);
+
+        let output = TXOutput::new(i32::MAX, &wallet.get_address().unwrap()).unwrap();
+        // Keyed to come after the genesis coinbase
+        let tx = Transaction { id: vec![0xffu8; 32], vin: vec![], vout: vec![output] };
+        utxo_set.db.insert(hex::encode(&tx.id), &TXOutputVec::new(&tx).to_string()[..]).unwrap();
+        assert!(matches!(utxo_set.balance(&pub_key_hash), Err(Error::ValueOverflow)));
+        assert!(matches!(utxo_set.find_spendable_outputs(&pub_key_hash, i32::MAX), Err(Error::ValueOverflow)));
+    }
+
+    /// A block on `parent` paying `claimed` to a new wallet, as a peer would send it.
+    fn side_block(utxo_set: &UTXOSet, parent: &[u8; 32], claimed: i32) -> Block {
+        let bc = utxo_set.block_chain();
+        let next = bc.next_header_on(parent).unwrap();
+        let address = Wallet::new().unwrap().get_address().unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&address, String::new(), claimed, next.height).unwrap();
+        Block::new_block(vec![coinbase], *parent, next.height, next.bits, next.time_stamp(bc.clock().now()))
+    }
+
+    fn tip_statuses(utxo_set: &UTXOSet) -> Vec<(u64, u64, TipStatus)> {
+        utxo_set.block_chain().chain_tips().unwrap().iter()
+            .map(|tip| (tip.height, tip.branch_len, tip.status))
+            .collect()
+    }
+
+    #[test]
+    fn test_reorganize_to_more_work() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let mut utxo_set = new_utxo_set(&wallet);
+        let subsidy = utxo_set.block_chain().chain_params().block_subsidy(1);
+        let genesis = utxo_set.block_chain().tip;
+        let genesis_tx = genesis_coinbase(&utxo_set);
+        let main_1 = mine_spend(&mut utxo_set, &genesis_tx, &wallet);
+        let main_2 = mine_spend(&mut utxo_set, &main_1.transaction[0], &wallet);
+
+        let side_1 = side_block(&utxo_set, &genesis, subsidy);
+        assert_eq!(utxo_set.add_block(&side_1).unwrap(), ChainEvent::SideChain { hash: side_1.cur_block_hash, height: 1 });
+        assert_eq!(utxo_set.add_block(&side_1).unwrap(), ChainEvent::Known { hash: side_1.cur_block_hash });
+        let side_2 = side_block(&utxo_set, &side_1.cur_block_hash, subsidy);
+        assert_eq!(utxo_set.add_block(&side_2).unwrap(), ChainEvent::SideChain { hash: side_2.cur_block_hash, height: 2 });
+        assert_eq!(utxo_set.block_chain().tip, main_2.cur_block_hash);
+        assert_eq!(tip_statuses(&utxo_set), vec![(2, 0, TipStatus::Active), (2, 2, TipStatus::Fork)]);
+        let main_state = snapshot(&utxo_set);
+
+        let side_3 = side_block(&utxo_set, &side_2.cur_block_hash, subsidy);
+        assert_eq!(utxo_set.add_block(&side_3).unwrap(), ChainEvent::Reorganized {
+            fork_height: Some(0),
+            disconnected: vec![main_2.cur_block_hash, main_1.cur_block_hash],
+            connected: vec![side_1.cur_block_hash, side_2.cur_block_hash, side_3.cur_block_hash],
+        });
+        assert_eq!(utxo_set.block_chain().tip, side_3.cur_block_hash);
+        assert_eq!(tip_statuses(&utxo_set), vec![(3, 0, TipStatus::Active), (2, 2, TipStatus::Fork)]);
+        // The genesis output spent on the old branch is back
+        assert!(utxo_set.get(&hex::encode(&genesis_tx.id)).unwrap().is_some());
+        let side_state = snapshot(&utxo_set);
+        assert_ne!(side_state, main_state);
+        utxo_set.reindex().unwrap();
+        assert_eq!(snapshot(&utxo_set), side_state);
+    }
+
+    #[test]
+    fn test_failed_reorganization_rolls_back() {
+        let _dir = TestDir::new();
+        let wallet = Wallet::new().unwrap();
+        let mut utxo_set = new_utxo_set(&wallet);
+        let subsidy = utxo_set.block_chain().chain_params().block_subsidy(1);
+        let genesis = utxo_set.block_chain().tip;
+        let genesis_tx = genesis_coinbase(&utxo_set);
+        let main_1 = mine_spend(&mut utxo_set, &genesis_tx, &wallet);
+        mine_spend(&mut utxo_set, &main_1.transaction[0], &wallet);
+        let main_tip = utxo_set.block_chain().tip;
+        let main_state = snapshot(&utxo_set);
+
+        // The second block of the branch claims more than its subsidy
+        let side_1 = side_block(&utxo_set, &genesis, subsidy);
+        utxo_set.add_block(&side_1).unwrap();
+        let bad = side_block(&utxo_set, &side_1.cur_block_hash, subsidy + 1);
+        utxo_set.add_block(&bad).unwrap();
+        let side_3 = side_block(&utxo_set, &bad.cur_block_hash, subsidy);
+        match utxo_set.add_block(&side_3) {
+            Err(Error::InvalidBlock(e)) => assert!(matches!(e.rejected[0].reason, TxRejectReason::ExcessCoinbase { .. })),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert_eq!(utxo_set.block_chain().tip, main_tip);
+        assert_eq!(snapshot(&utxo_set), main_state);
+        assert_eq!(tip_statuses(&utxo_set), vec![(2, 0, TipStatus::Active), (3, 3, TipStatus::Invalid)]);
+        let bc = utxo_set.block_chain();
+        assert!(!bc.block_index(&side_1.cur_block_hash).unwrap().unwrap().invalid);
+        assert!(bc.block_index(&bad.cur_block_hash).unwrap().unwrap().invalid);
+        assert!(bc.block_index(&side_3.cur_block_hash).unwrap().unwrap().invalid);
+
+        let side_4 = side_block(&utxo_set, &side_3.cur_block_hash, subsidy);
+        match utxo_set.add_block(&side_4) {
+            Err(Error::InvalidChain(ChainValidationError::InvalidBlock { reason, .. })) =>
+                assert_eq!(reason, BlockRejectReason::InvalidAncestor),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!utxo_set.block_chain().has_block(&side_4.cur_block_hash).unwrap());
+    }
+}
@@ -0,0 +1,789 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::block::Block;
+use crate::consensus::{max_time_stamp, NextHeader, ProofOfWork};
+use crate::engine::ConsensusEngine;
+use crate::merkle::is_mutated;
+use crate::script::ScriptError;
+use crate::transaction::*;
+
+/// Why a single transaction was refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxRejectReason {
+    /// The id is not the hash of the transaction.
+    IdMismatch,
+    /// A coinbase anywhere but first in a block, including on its own.
+    CoinbaseOutsideBlock,
+    /// The block does not start with a coinbase.
+    MissingCoinbase,
+    MissingInput { tx_id: String, vout: i32 },
+    SpentInput { tx_id: String, vout: i32 },
+    DuplicateInput { tx_id: String, vout: i32 },
+    /// The unlocking script of an input does not satisfy the output it spends.
+    ScriptFailed { tx_id: String, vout: i32, error: ScriptError },
+    NegativeOutput { value: i32 },
+    /// The inputs or the outputs add up to more than an `i32` holds.
+    ValueOverflow,
+    Overspend { input: i32, output: i32 },
+    /// The coinbase pays more than the subsidy and fees of its block.
+    ExcessCoinbase { claimed: i32, allowed: i32 },
+    /// The coinbase does not carry the height of its block.
+    BadCoinbaseHeight { expected: u64, found: Option<u64> },
+    /// A transaction with the same id still has unspent outputs.
+    DuplicateTransaction,
+    /// Spends a coinbase output before the block at `mature_at`.
+    ImmatureCoinbase { tx_id: String, vout: i32, mature_at: u64 },
+}
+
+impl fmt::Display for TxRejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxRejectReason::IdMismatch => write!(f, "id is not the hash of the transaction"),
+            TxRejectReason::CoinbaseOutsideBlock => write!(f, "a coinbase is only valid first in a block"),
+            TxRejectReason::MissingCoinbase => write!(f, "the block does not start with a coinbase"),
+            TxRejectReason::MissingInput { tx_id, vout } =>
+                write!(f, "input {}:{} references a missing output", tx_id, vout),
+            TxRejectReason::SpentInput { tx_id, vout } =>
+                write!(f, "input {}:{} is already spent", tx_id, vout),
+            TxRejectReason::DuplicateInput { tx_id, vout } =>
+                write!(f, "input {}:{} is spent twice in the block", tx_id, vout),
+            TxRejectReason::ScriptFailed { tx_id, vout, error } =>
+                write!(f, "input {}:{} fails its script: {}", tx_id, vout, error),
+            TxRejectReason::NegativeOutput { value } =>
+                write!(f, "output value {} is negative", value),
+            TxRejectReason::ValueOverflow =>
+                write!(f, "values add up to more than {}", i32::MAX),
+            TxRejectReason::Overspend { input, output } =>
+                write!(f, "outputs {} exceed inputs {}", output, input),
+            TxRejectReason::ExcessCoinbase { claimed, allowed } =>
+                write!(f, "coinbase claims {}, only {} allowed", claimed, allowed),
+            TxRejectReason::BadCoinbaseHeight { expected, found: Some(found) } =>
+                write!(f, "coinbase height is {}, expected {}", found, expected),
+            TxRejectReason::BadCoinbaseHeight { expected, found: None } =>
+                write!(f, "coinbase has no height, expected {}", expected),
+            TxRejectReason::DuplicateTransaction =>
+                write!(f, "a transaction with the same id has unspent outputs"),
+            TxRejectReason::ImmatureCoinbase { tx_id, vout, mature_at } =>
+                write!(f, "input {}:{} spends a coinbase not mature until height {}", tx_id, vout, mature_at),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectedTransaction {
+    pub tx_id: String,
+    pub reason: TxRejectReason,
+}
+
+/// Every transaction of a candidate block that failed validation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockValidationError {
+    pub rejected: Vec<RejectedTransaction>,
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} invalid transaction(s)", self.rejected.len())?;
+        for tx in &self.rejected {
+            write!(f, "\n  {}: {}", tx.tx_id, tx.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BlockValidationError {}
+
+/// Checks `transactions`, to be mined at `height`, against the unspent outputs
+/// `utxo` and returns their total fees. Coinbase outputs need `maturity`
+/// blocks on top of their own. `tx_exists` tells a missing output apart from
+/// an already spent one.
+pub fn check_transactions<F>(transactions: &[Transaction],
+                             utxo: &HashMap<String, TXOutputVec>,
+                             height: u64,
+                             maturity: u64,
+                             tx_exists: F) -> Result<i32, BlockValidationError>
+    where F: Fn(&[u8; 32]) -> bool
+{
+    let mut rejected = Vec::new();
+    let mut spent_in_block = HashSet::<(String, i32)>::new();
+    let mut ids_in_block = HashSet::<&[u8]>::new();
+    let mut fees = 0i32;
+
+    for tx in transactions {
+        if !ids_in_block.insert(&tx.id) {
+            rejected.push(RejectedTransaction {
+                tx_id: hex::encode(&tx.id),
+                reason: TxRejectReason::DuplicateTransaction,
+            });
+            continue;
+        }
+        match check_transaction(tx, utxo, height, maturity, &tx_exists, &mut spent_in_block)
+            .and_then(|fee| fees.checked_add(fee).ok_or(TxRejectReason::ValueOverflow)) {
+            Ok(total) => fees = total,
+            Err(reason) => rejected.push(RejectedTransaction {
+                tx_id: hex::encode(&tx.id),
+                reason,
+            }),
+        }
+    }
+
+    if rejected.is_empty() {
+        Ok(fees)
+    } else {
+        Err(BlockValidationError { rejected })
+    }
+}
+
+/// Checks that a block at `height` whose other transactions pay `fees`
+/// starts with its only coinbase, and that the coinbase carries that height
+/// and claims no more than `subsidy` and those fees.
+pub fn check_coinbase(transactions: &[Transaction], height: u64, subsidy: i32, fees: i32)
+                      -> Result<(), BlockValidationError> {
+    let reject = |tx_id: String, reason| Err(BlockValidationError {
+        rejected: vec![RejectedTransaction { tx_id, reason }],
+    });
+
+    let coinbase = match transactions.first() {
+        Some(tx) if tx.is_coinbase() => tx,
+        first => {
+            let tx_id = first.map(|tx| hex::encode(&tx.id)).unwrap_or_default();
+            return reject(tx_id, TxRejectReason::MissingCoinbase);
+        }
+    };
+    let rejected: Vec<RejectedTransaction> = transactions[1..].iter()
+        .filter(|tx| tx.is_coinbase())
+        .map(|tx| RejectedTransaction {
+            tx_id: hex::encode(&tx.id),
+            reason: TxRejectReason::CoinbaseOutsideBlock,
+        })
+        .collect();
+    if !rejected.is_empty() {
+        return Err(BlockValidationError { rejected });
+    }
+
+    let tx_id = hex::encode(&coinbase.id);
+    if coinbase.coinbase_height() != Some(height) {
+        let found = coinbase.coinbase_height();
+        return reject(tx_id, TxRejectReason::BadCoinbaseHeight { expected: height, found });
+    }
+    let allowed = subsidy.checked_add(fees);
+    let claimed = coinbase.vout.iter()
+        .try_fold(0i32, |acc, out| acc.checked_add(out.value));
+    let reason = match (claimed, allowed) {
+        (Some(claimed), Some(allowed)) if claimed <= allowed => return Ok(()),
+        (Some(claimed), Some(allowed)) => TxRejectReason::ExcessCoinbase { claimed, allowed },
+        _ => TxRejectReason::ValueOverflow,
+    };
+    reject(tx_id, reason)
+}
+
+fn check_transaction<F>(tx: &Transaction,
+                        utxo: &HashMap<String, TXOutputVec>,
+                        height: u64,
+                        maturity: u64,
+                        tx_exists: &F,
+                        spent_in_block: &mut HashSet<(String, i32)>) -> Result<i32, TxRejectReason>
+    where F: Fn(&[u8; 32]) -> bool
+{
+    if !tx.has_valid_id() {
+        return Err(TxRejectReason::IdMismatch);
+    }
+    if let Some(out) = tx.vout.iter().find(|out| out.value < 0) {
+        return Err(TxRejectReason::NegativeOutput { value: out.value });
+    }
+    if utxo.contains_key(&hex::encode(&tx.id)) {
+        return Err(TxRejectReason::DuplicateTransaction);
+    }
+
+    if tx.is_coinbase() {
+        return Ok(0);
+    }
+
+    let mut input = 0i32;
+    let mut claimed = Vec::new();
+
+    for (idx, vin) in tx.vin.iter().enumerate() {
+        let tx_id = hex::encode(vin.tx_id);
+        let outpoint = (tx_id.clone(), vin.vout);
+
+        if spent_in_block.contains(&outpoint) || claimed.contains(&outpoint) {
+            return Err(TxRejectReason::DuplicateInput { tx_id, vout: vin.vout });
+        }
+
+        let outs = utxo.get(&tx_id);
+        let out = match outs.and_then(|outs| outs.outputs.get(&vin.vout)) {
+            Some(out) => out,
+            None if tx_exists(&vin.tx_id) => {
+                return Err(TxRejectReason::SpentInput { tx_id, vout: vin.vout });
+            }
+            None => return Err(TxRejectReason::MissingInput { tx_id, vout: vin.vout }),
+        };
+
+        if let Err(error) = tx.verify_input(idx, out, height) {
+            return Err(TxRejectReason::ScriptFailed { tx_id, vout: vin.vout, error });
+        }
+        if let Some(outs) = outs.filter(|outs| !outs.is_mature(height, maturity)) {
+            let mature_at = outs.coinbase_height.unwrap_or(0) + maturity;
+            return Err(TxRejectReason::ImmatureCoinbase { tx_id, vout: vin.vout, mature_at });
+        }
+
+        input = input.checked_add(out.value).ok_or(TxRejectReason::ValueOverflow)?;
+        claimed.push(outpoint);
+    }
+
+    let output = tx.vout.iter()
+        .try_fold(0i32, |acc, x| acc.checked_add(x.value))
+        .ok_or(TxRejectReason::ValueOverflow)?;
+    if output > input {
+        return Err(TxRejectReason::Overspend { input, output });
+    }
+
+    spent_in_block.extend(claimed);
+    Ok(input - output)
+}
+
+/// Why a stored block failed full-chain validation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockRejectReason {
+    HashMismatch { stored: String, computed: String },
+    BrokenLink { expected: String, found: String },
+    BadTarget { expected: u32, found: u32 },
+    InsufficientWork { bits: u32 },
+    /// The seal is not what the consensus engine requires.
+    BadSeal,
+    MerkleRootMismatch,
+    /// The transaction with id `tx_id` does not hash to it.
+    TransactionIdMismatch { tx_id: String },
+    /// The transaction list repeats a subtree, which leaves the merkle root unchanged.
+    MutatedMerkleTree,
+    BadHeight { expected: u64, found: u64 },
+    /// Not dated after the median time of the previous blocks.
+    TimeTooOld { time_stamp: u64, median_time_past: u64 },
+    /// Dated too far ahead of the local clock.
+    TimeTooNew { time_stamp: u64, max: u64 },
+    /// The height index does not point to this block.
+    IndexMismatch,
+    /// Builds on a block that failed to connect.
+    InvalidAncestor,
+    Transactions(BlockValidationError),
+}
+
+impl fmt::Display for BlockRejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockRejectReason::HashMismatch { stored, computed } =>
+                write!(f, "stored hash {} does not match computed hash {}", stored, computed),
+            BlockRejectReason::BrokenLink { expected, found } =>
+                write!(f, "previous hash is {}, expected {}", found, expected),
+            BlockRejectReason::BadTarget { expected, found } =>
+                write!(f, "target is {:#010x}, expected {:#010x}", found, expected),
+            BlockRejectReason::InsufficientWork { bits } =>
+                write!(f, "hash does not meet target {:#010x}", bits),
+            BlockRejectReason::BadSeal =>
+                write!(f, "seal is missing or not made by the expected signer"),
+            BlockRejectReason::MerkleRootMismatch =>
+                write!(f, "merkle root does not match the transactions"),
+            BlockRejectReason::TransactionIdMismatch { tx_id } =>
+                write!(f, "transaction {} does not hash to its id", tx_id),
+            BlockRejectReason::MutatedMerkleTree =>
+                write!(f, "transactions repeat a subtree of the merkle tree"),
+            BlockRejectReason::BadHeight { expected, found } =>
+                write!(f, "height is {}, expected {}", found, expected),
+            BlockRejectReason::TimeTooOld { time_stamp, median_time_past } =>
+                write!(f, "time {} is not after the median time past {}", time_stamp, median_time_past),
+            BlockRejectReason::TimeTooNew { time_stamp, max } =>
+                write!(f, "time {} is after the latest allowed time {}", time_stamp, max),
+            BlockRejectReason::IndexMismatch =>
+                write!(f, "height index does not point to this block"),
+            BlockRejectReason::InvalidAncestor =>
+                write!(f, "builds on an invalid block"),
+            BlockRejectReason::Transactions(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainValidationError {
+    /// A block referenced by the chain is missing or cannot be decoded.
+    Unreadable { hash: String },
+    InvalidBlock { height: u64, hash: String, reason: BlockRejectReason },
+}
+
+impl fmt::Display for ChainValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainValidationError::Unreadable { hash } =>
+                write!(f, "block {} is missing or corrupted", hash),
+            ChainValidationError::InvalidBlock { height, hash, reason } =>
+                write!(f, "block {} at height {} is invalid: {}", hash, height, reason),
+        }
+    }
+}
+
+impl std::error::Error for ChainValidationError {}
+
+/// Checks the seal, linkage, height, target, time, transaction ids and merkle
+/// root of `block`, stored under `stored_hash` on top of `pre_block_hash`,
+/// against what the chain `expected`, the rules of `engine` and the local
+/// clock reading `now`.
+pub fn check_block(block: &Block, stored_hash: &[u8; 32], pre_block_hash: &[u8; 32],
+                   expected: &NextHeader, engine: &dyn ConsensusEngine, now: u64)
+                   -> Result<(), BlockRejectReason> {
+    let computed = block.header.pow_hash();
+    if computed != block.cur_block_hash || computed != *stored_hash {
+        return Err(BlockRejectReason::HashMismatch {
+            stored: hex::encode(stored_hash),
+            computed: hex::encode(computed),
+        });
+    }
+    if block.header.pre_block_hash != *pre_block_hash {
+        return Err(BlockRejectReason::BrokenLink {
+            expected: hex::encode(pre_block_hash),
+            found: block.pre_block_hash(),
+        });
+    }
+    if block.header.height != expected.height {
+        return Err(BlockRejectReason::BadHeight {
+            expected: expected.height,
+            found: block.header.height,
+        });
+    }
+    if block.header.bits != expected.bits {
+        return Err(BlockRejectReason::BadTarget {
+            expected: expected.bits,
+            found: block.header.bits,
+        });
+    }
+    engine.verify_seal(block)?;
+    if block.header.time_stamp <= expected.median_time_past {
+        return Err(BlockRejectReason::TimeTooOld {
+            time_stamp: block.header.time_stamp,
+            median_time_past: expected.median_time_past,
+        });
+    }
+    if block.header.time_stamp > max_time_stamp(now) {
+        return Err(BlockRejectReason::TimeTooNew {
+            time_stamp: block.header.time_stamp,
+            max: max_time_stamp(now),
+        });
+    }
+    if let Some(tx) = block.transaction.iter().find(|tx| !tx.has_valid_id()) {
+        return Err(BlockRejectReason::TransactionIdMismatch { tx_id: hex::encode(&tx.id) });
+    }
+    if block.header.merkle_root != Block::hash_transactions(&block.transaction) {
+        return Err(BlockRejectReason::MerkleRootMismatch);
+    }
+    let tx_ids: Vec<&[u8]> = block.transaction.iter().map(|tx| &tx.id[..]).collect();
+    if is_mutated(&tx_ids) {
+        return Err(BlockRejectReason::MutatedMerkleTree);
+    }
+    Ok(())
+}
+
+/// Spends the inputs and adds the outputs of `transactions` to `utxo`.
+pub fn apply_transactions(utxo: &mut HashMap<String, TXOutputVec>, transactions: &[Transaction]) {
+    for tx in transactions {
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                let tx_id = hex::encode(vin.tx_id);
+                if let Some(outs) = utxo.get_mut(&tx_id) {
+                    outs.outputs.remove(&vin.vout);
+                    if outs.outputs.is_empty() {
+                        utxo.remove(&tx_id);
+                    }
+                }
+            }
+        }
+
+        utxo.insert(hex::encode(&tx.id), TXOutputVec::new(tx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::block::BlockHeader;
+    use crate::consensus::{MAX_FUTURE_DRIFT, Clock, RetargetParams};
+    use crate::engine::ProofOfWorkEngine;
+    use crate::script::Script;
+    use crate::wallet::Wallet;
+
+    /// Clock moving `step` seconds forward on every reading.
+    struct StepClock {
+        time: AtomicU64,
+        step: u64,
+    }
+
+    impl Clock for StepClock {
+        fn now(&self) -> u64 {
+            self.time.fetch_add(self.step, Ordering::SeqCst)
+        }
+    }
+
+    fn mine_chain(clock: &dyn Clock, len: usize) -> Vec<Block> {
+        let address = Wallet::new().unwrap().get_address().unwrap();
+        let params = RetargetParams::default();
+        let mut chain: Vec<Block> = Vec::new();
+        for i in 0..len {
+            let recent: Vec<BlockHeader> = chain.iter().map(|block| block.header.clone()).collect();
+            let next = NextHeader::new(&ProofOfWorkEngine, &params, &recent, None);
+            let coinbase = Transaction::new_coinbase_tx(&address, String::new(), 10, i as u64).unwrap();
+            let pre_block_hash = chain.last().map_or([0u8; 32], |block| block.cur_block_hash);
+            chain.push(Block::new_block(vec![coinbase], pre_block_hash, next.height, next.bits,
+                                        next.time_stamp(clock.now())));
+        }
+        chain
+    }
+
+    fn expected(chain: &[Block]) -> NextHeader {
+        let recent: Vec<BlockHeader> = chain.iter().map(|block| block.header.clone()).collect();
+        NextHeader::new(&ProofOfWorkEngine, &RetargetParams::default(), &recent, None)
+    }
+
+    #[test]
+    fn test_block_time_stamps() {
+        let clock = StepClock { time: AtomicU64::new(1_600_000_000), step: 600 };
+        let chain = mine_chain(&clock, 3);
+        let times: Vec<u64> = chain.iter().map(|block| block.time_stamp()).collect();
+        assert_eq!(times, vec![1_600_000_000, 1_600_000_600, 1_600_001_200]);
+
+        let now = 1_600_001_200;
+        for (height, block) in chain.iter().enumerate() {
+            let pre_block_hash = if height == 0 { [0u8; 32] } else { chain[height - 1].cur_block_hash };
+            let next = expected(&chain[..height]);
+            assert_eq!(check_block(block, &block.cur_block_hash, &pre_block_hash, &next, &ProofOfWorkEngine, now),
+                       Ok(()));
+        }
+
+        // Too far ahead of a lagging local clock
+        let last = &chain[2];
+        let next = expected(&chain[..2]);
+        let now = last.time_stamp() - MAX_FUTURE_DRIFT - 1;
+        assert_eq!(check_block(last, &last.cur_block_hash, &chain[1].cur_block_hash, &next,
+                               &ProofOfWorkEngine, now),
+                   Err(BlockRejectReason::TimeTooNew {
+                       time_stamp: last.time_stamp(),
+                       max: last.time_stamp() - 1,
+                   }));
+    }
+
+    #[test]
+    fn test_output_overflow() {
+        let wallet = Wallet::new().unwrap();
+        let address = wallet.get_address().unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&address, String::new(), 10, 0).unwrap();
+        let mut outs = TXOutputVec::default();
+        outs.outputs.insert(0, coinbase.vout[0].clone());
+        let mut utxo = HashMap::new();
+        utxo.insert(hex::encode(&coinbase.id), outs);
+
+        // Wraps around to 10 without the check
+        let mut tx_id = [0u8; 32];
+        tx_id.copy_from_slice(&coinbase.id);
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput { tx_id, vout: 0, script_sig: Script::new() }],
+            vout: vec![TXOutput::new(i32::MAX, &address).unwrap(),
+                       TXOutput::new(i32::MAX, &address).unwrap(),
+                       TXOutput::new(12, &address).unwrap()],
+        };
+        tx.set_id();
+        tx.sign(&wallet.private_key, &utxo).unwrap();
+
+        let rejected = check_transactions(&[tx], &utxo, 1, 0, |_| false).unwrap_err().rejected;
+        assert_eq!(rejected[0].reason, TxRejectReason::ValueOverflow);
+    }
+
+    #[test]
+    fn test_tampered_transactions() {
+        let clock = StepClock { time: AtomicU64::new(1_600_000_000), step: 600 };
+        let chain = mine_chain(&clock, 1);
+        let next = expected(&[]);
+        let check = |block: &Block| check_block(block, &block.cur_block_hash, &[0u8; 32], &next,
+                                                &ProofOfWorkEngine, 1_600_000_000);
+        assert_eq!(check(&chain[0]), Ok(()));
+
+        // Redirecting the reward changes the hash of the coinbase
+        let mut block = chain[0].clone();
+        let thief = Wallet::new().unwrap().get_address().unwrap();
+        block.transaction[0].vout[0] = TXOutput::new(10, &thief).unwrap();
+        assert_eq!(check(&block), Err(BlockRejectReason::TransactionIdMismatch {
+            tx_id: hex::encode(&chain[0].transaction[0].id),
+        }));
+        block.transaction[0].set_id();
+        assert_eq!(check(&block), Err(BlockRejectReason::MerkleRootMismatch));
+
+        // Repeating the last of an odd number of transactions keeps the merkle root
+        let mut transactions = chain[0].transaction.clone();
+        for extra_nonce in 1..3 {
+            let mut tx = transactions[0].clone();
+            tx.set_extra_nonce(extra_nonce);
+            transactions.push(tx);
+        }
+        let block = Block::new_block(transactions, [0u8; 32], next.height, next.bits, chain[0].time_stamp());
+        assert_eq!(check(&block), Ok(()));
+        let mut mutated = block.clone();
+        mutated.transaction.push(block.transaction[2].clone());
+        assert_eq!(check(&mutated), Err(BlockRejectReason::MutatedMerkleTree));
+    }
+
+    #[test]
+    fn test_coinbase_value() {
+        let address = Wallet::new().unwrap().get_address().unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&address, String::new(), 13, 0).unwrap();
+        let txs = vec![coinbase];
+
+        assert_eq!(check_coinbase(&txs, 0, 10, 3), Ok(()));
+        assert_eq!(check_coinbase(&txs, 0, 10, 2), Err(BlockValidationError {
+            rejected: vec![RejectedTransaction {
+                tx_id: hex::encode(&txs[0].id),
+                reason: TxRejectReason::ExcessCoinbase { claimed: 13, allowed: 12 },
+            }],
+        }));
+
+        let mut overflowing = txs[0].clone();
+        overflowing.vout.push(overflowing.vout[0].clone());
+        overflowing.vout[0].value = i32::MAX;
+        overflowing.set_id();
+        let rejected = check_coinbase(&[overflowing], 0, 10, 3).unwrap_err().rejected;
+        assert_eq!(rejected[0].reason, TxRejectReason::ValueOverflow);
+        let rejected = check_coinbase(&txs, 0, i32::MAX, 3).unwrap_err().rejected;
+        assert_eq!(rejected[0].reason, TxRejectReason::ValueOverflow);
+    }
+
+    #[test]
+    fn test_duplicate_coinbase() {
+        let address = Wallet::new().unwrap().get_address().unwrap();
+        let first = Transaction::new_coinbase_tx(&address, String::new(), 10, 1).unwrap();
+        let second = Transaction::new_coinbase_tx(&address, String::new(), 10, 2).unwrap();
+        assert_ne!(first.id, second.id);
+        assert_eq!(second.coinbase_height(), Some(2));
+
+        let mut rolled = second.clone();
+        rolled.set_extra_nonce(7);
+        assert_ne!(rolled.id, second.id);
+        assert_eq!((rolled.coinbase_height(), rolled.coinbase_extra_nonce()), (Some(2), Some(7)));
+
+        // The same coinbase again while its output is unspent
+        let mut utxo = HashMap::new();
+        apply_transactions(&mut utxo, std::slice::from_ref(&first));
+        let rejected = check_transactions(std::slice::from_ref(&first), &utxo, 2, 0, |_| false).unwrap_err().rejected;
+        assert_eq!(rejected[0].reason, TxRejectReason::DuplicateTransaction);
+        assert_eq!(check_transactions(std::slice::from_ref(&second), &utxo, 2, 0, |_| false), Ok(0));
+
+        let rejected = check_coinbase(&[second], 1, 10, 0).unwrap_err().rejected;
+        assert_eq!(rejected[0].reason, TxRejectReason::BadCoinbaseHeight { expected: 1, found: Some(2) });
+    }
+
+    #[test]
+    fn test_coinbase_position() {
+        let address = Wallet::new().unwrap().get_address().unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&address, String::new(), 10, 1).unwrap();
+        let other = Transaction::new_coinbase_tx(&address, String::new(), 0, 1).unwrap();
+        let mut utxo = HashMap::new();
+        let tx = fund(&mut utxo, Script::new());
+
+        let rejected = check_coinbase(&[], 1, 10, 0).unwrap_err().rejected;
+        assert_eq!(rejected, vec![RejectedTransaction { tx_id: String::new(), reason: TxRejectReason::MissingCoinbase }]);
+        let rejected = check_coinbase(&[tx.clone(), coinbase.clone()], 1, 10, 0).unwrap_err().rejected;
+        assert_eq!(rejected, vec![RejectedTransaction {
+            tx_id: hex::encode(&tx.id),
+            reason: TxRejectReason::MissingCoinbase,
+        }]);
+        let rejected = check_coinbase(&[coinbase.clone(), tx.clone(), other.clone()], 1, 10, 0).unwrap_err().rejected;
+        assert_eq!(rejected, vec![RejectedTransaction {
+            tx_id: hex::encode(&other.id),
+            reason: TxRejectReason::CoinbaseOutsideBlock,
+        }]);
+        assert_eq!(check_coinbase(&[coinbase, tx], 1, 10, 0), Ok(()));
+    }
+
+    /// Why `transactions` are rejected as a block at height 1 over `utxo`.
+    fn rejection(transactions: &[Transaction], utxo: &HashMap<String, TXOutputVec>) -> TxRejectReason {
+        let rejected = check_transactions(transactions, utxo, 1, 0, |_| true).unwrap_err().rejected;
+        assert_eq!(rejected.len(), 1);
+        rejected[0].reason.clone()
+    }
+
+    #[test]
+    fn test_rejected_inputs() {
+        let wallet = Wallet::new().unwrap();
+        let mut utxo = HashMap::new();
+        let funding = fund(&mut utxo, Script::pay_to_pub_key_hash(&wallet.hash_pub_key().unwrap()));
+        let funding_id = hex::encode(&funding.id);
+        let signed = |value: i32, key: &Wallet| {
+            let mut tx = spend(&funding, value);
+            tx.sign(&key.private_key, &utxo).unwrap();
+            tx
+        };
+
+        let tx = signed(11, &wallet);
+        assert_eq!(rejection(std::slice::from_ref(&tx), &utxo), TxRejectReason::Overspend { input: 10, output: 11 });
+        let tx = signed(10, &Wallet::new().unwrap());
+        assert_eq!(rejection(std::slice::from_ref(&tx), &utxo), TxRejectReason::ScriptFailed {
+            tx_id: funding_id.clone(),
+            vout: 0,
+            error: ScriptError::EqualVerifyFailed,
+        });
+
+        let (first, second) = (signed(10, &wallet), signed(9, &wallet));
+        assert_eq!(check_transactions(std::slice::from_ref(&first), &utxo, 1, 0, |_| true), Ok(0));
+        assert_eq!(rejection(&[first.clone(), second.clone()], &utxo), TxRejectReason::DuplicateInput {
+            tx_id: funding_id.clone(),
+            vout: 0,
+        });
+        let mut twice = first.clone();
+        twice.vin.push(twice.vin[0].clone());
+        twice.sign(&wallet.private_key, &utxo).unwrap();
+        assert_eq!(rejection(&[twice], &utxo), TxRejectReason::DuplicateInput { tx_id: funding_id.clone(), vout: 0 });
+
+        // Once the output is gone it is spent if its transaction is known, missing otherwise
+        apply_transactions(&mut utxo, std::slice::from_ref(&first));
+        assert_eq!(rejection(std::slice::from_ref(&second), &utxo), TxRejectReason::SpentInput {
+            tx_id: funding_id.clone(),
+            vout: 0,
+        });
+        let rejected = check_transactions(std::slice::from_ref(&second), &utxo, 1, 0, |_| false).unwrap_err().rejected;
+        assert_eq!(rejected[0].reason, TxRejectReason::MissingInput { tx_id: funding_id, vout: 0 });
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let wallet = Wallet::new().unwrap();
+        let address = wallet.get_address().unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&address, String::new(), 10, 0).unwrap();
+        let mut utxo = HashMap::new();
+        apply_transactions(&mut utxo, std::slice::from_ref(&coinbase));
+
+        let mut coinbase_id = [0u8; 32];
+        coinbase_id.copy_from_slice(&coinbase.id);
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput { tx_id: coinbase_id, vout: 0, script_sig: Script::new() }],
+            vout: vec![TXOutput::new(10, &address).unwrap()],
+        };
+        tx.set_id();
+        tx.sign(&wallet.private_key, &utxo).unwrap();
+
+        let rejected = check_transactions(&[tx.clone()], &utxo, 9, 10, |_| false).unwrap_err().rejected;
+        assert_eq!(rejected[0].reason, TxRejectReason::ImmatureCoinbase {
+            tx_id: hex::encode(coinbase_id),
+            vout: 0,
+            mature_at: 10,
+        });
+        assert_eq!(check_transactions(&[tx], &utxo, 10, 10, |_| false), Ok(0));
+    }
+
+    /// A transaction spending output 0 of `prev` and paying `value` to a new wallet.
+    fn spend(prev: &Transaction, value: i32) -> Transaction {
+        let mut prev_id = [0u8; 32];
+        prev_id.copy_from_slice(&prev.id);
+        let address = Wallet::new().unwrap().get_address().unwrap();
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput { tx_id: prev_id, vout: 0, script_sig: Script::new() }],
+            vout: vec![TXOutput::new(value, &address).unwrap()],
+        };
+        tx.set_id();
+        tx
+    }
+
+    /// A transaction paying `script_pubkey` from a matured coinbase, applied to `utxo`.
+    fn fund(utxo: &mut HashMap<String, TXOutputVec>, script_pubkey: Script) -> Transaction {
+        let wallet = Wallet::new().unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&wallet.get_address().unwrap(), String::new(), 10, 0).unwrap();
+        apply_transactions(utxo, std::slice::from_ref(&coinbase));
+        let mut tx = spend(&coinbase, 0);
+        tx.vout = vec![TXOutput::with_script(10, script_pubkey)];
+        tx.set_id();
+        tx.sign(&wallet.private_key, utxo).unwrap();
+        assert_eq!(check_transactions(std::slice::from_ref(&tx), utxo, 1, 0, |_| false), Ok(0));
+        apply_transactions(utxo, std::slice::from_ref(&tx));
+        tx
+    }
+
+    /// What the scripts of `tx`, with its id updated, fail with.
+    fn script_error(tx: &Transaction, utxo: &HashMap<String, TXOutputVec>, height: u64) -> Option<ScriptError> {
+        let mut tx = tx.clone();
+        tx.set_id();
+        match check_transactions(std::slice::from_ref(&tx), utxo, height, 0, |_| false) {
+            Err(e) => match &e.rejected[0].reason {
+                TxRejectReason::ScriptFailed { error, .. } => Some(error.clone()),
+                reason => panic!("unexpected rejection: {}", reason),
+            },
+            Ok(_) => None,
+        }
+    }
+
+    #[test]
+    fn test_multisig_spend() {
+        let keys: Vec<Wallet> = (0..3).map(|_| Wallet::new().unwrap()).collect();
+        let pub_keys: Vec<Vec<u8>> = keys.iter().map(Wallet::public_key).collect();
+        let mut utxo = HashMap::new();
+        let funding = fund(&mut utxo, Script::multisig(2, &pub_keys));
+        let script_pubkey = &funding.vout[0].script_pubkey;
+
+        let mut tx = spend(&funding, 10);
+        let sign = |tx: &Transaction, i: usize| tx.sign_input(0, script_pubkey, &keys[i].private_key).unwrap();
+        let (first, third) = (sign(&tx, 0), sign(&tx, 2));
+
+        tx.vin[0].script_sig = Script::new().push_slice(&first);
+        assert_eq!(script_error(&tx, &utxo, 1), Some(ScriptError::StackUnderflow));
+        // Signatures out of the order of the keys
+        tx.vin[0].script_sig = Script::new().push_slice(&third).push_slice(&first);
+        assert_eq!(script_error(&tx, &utxo, 1), Some(ScriptError::EvalFalse));
+        tx.vin[0].script_sig = Script::new().push_slice(&first).push_slice(&third);
+        assert_eq!(script_error(&tx, &utxo, 1), None);
+
+        // A signature does not carry over to other outputs
+        tx.vout[0].value = 9;
+        assert_eq!(script_error(&tx, &utxo, 1), Some(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn test_timelock_spend() {
+        let wallet = Wallet::new().unwrap();
+        let mut utxo = HashMap::new();
+        let funding = fund(&mut utxo, Script::timelock(5, &wallet.hash_pub_key().unwrap()));
+
+        let mut tx = spend(&funding, 10);
+        tx.sign(&wallet.private_key, &utxo).unwrap();
+        assert_eq!(script_error(&tx, &utxo, 4), Some(ScriptError::Locked { until: 5 }));
+        assert_eq!(script_error(&tx, &utxo, 5), None);
+
+        // Someone else's key
+        tx.sign(&Wallet::new().unwrap().private_key, &utxo).unwrap();
+        assert_eq!(script_error(&tx, &utxo, 5), Some(ScriptError::EqualVerifyFailed));
+    }
+
+    #[test]
+    fn test_data_output() {
+        let mut utxo = HashMap::new();
+        let funding = fund(&mut utxo, Script::data(b"hello"));
+        assert!(funding.vout[0].script_pubkey.is_unspendable());
+
+        let tx = spend(&funding, 10);
+        assert_eq!(script_error(&tx, &utxo, 1), Some(ScriptError::Unspendable));
+    }
+
+    #[test]
+    fn test_block_before_median_time_past() {
+        let clock = StepClock { time: AtomicU64::new(1_600_000_000), step: 600 };
+        let chain = mine_chain(&clock, 3);
+        let next = expected(&chain);
+        assert_eq!(next.median_time_past, 1_600_000_600);
+
+        let coinbase = chain[0].transaction[0].clone();
+        let block = Block::new_block(vec![coinbase], chain[2].cur_block_hash, next.height, next.bits,
+                                     next.median_time_past);
+        assert_eq!(check_block(&block, &block.cur_block_hash, &chain[2].cur_block_hash, &next,
+                               &ProofOfWorkEngine, 1_600_001_800),
+                   Err(BlockRejectReason::TimeTooOld {
+                       time_stamp: next.median_time_past,
+                       median_time_past: next.median_time_past,
+                   }));
+    }
+}
@@ -1,62 +1,110 @@
 
 use std::collections::HashMap;
 use std::fs::*;
-use std::io::{Write, BufWriter, BufReader};
+use std::io::{BufWriter, BufReader};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use openssl::bn::BigNumContext;
+use openssl::bn::{BigNum, BigNumContext};
 use openssl::ec::*;
-use openssl::pkey::{Private, Public};
 use openssl::nid::Nid;
-use openssl::sha::Sha256;
+use openssl::symm::Cipher;
 use serde::{
     Deserialize, Serialize,
 };
+use zeroize::{Zeroize, Zeroizing};
 
+use crate::chain_params::{chain_params, data_path, selected_wallet};
+use crate::error::{Error, Result};
+use crate::hd::*;
 use crate::utils::*;
-use std::path::Path;
 
-const wallet_file: &str = "wallet.dat";
+const WALLET_FILE: &str = "wallet.dat";
 
-#[derive(Serialize, Deserialize)]
+/// Default cost of deriving the key of an encrypted wallet, as log2 of the scrypt N.
+pub const DEFAULT_KDF_COST: u8 = 15;
+/// Costs a wallet may be encrypted with, from fast to slow.
+pub const KDF_COSTS: RangeInclusive<u8> = 10..=20;
+/// scrypt block size r and parallelism p.
+const KDF_BLOCK_SIZE: u64 = 8;
+const KDF_PARALLELISM: u64 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Number of unused keys in a row after which restoring an HD wallet stops
+/// looking for used ones.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+/// Chains of an HD account: addresses given out to receive coins, and
+/// addresses the change of payments returns to.
+const RECEIVE_CHAIN: u32 = 0;
+const CHANGE_CHAIN: u32 = 1;
+
+/// Keys of the wallet files unlocked by the process and until when they may
+/// be used, so that wallets loaded later are unlocked too.
+static UNLOCKED_KEYS: Mutex<Vec<UnlockedKey>> = Mutex::new(Vec::new());
+
+struct UnlockedKey {
+    salt: Vec<u8>,
+    key: Zeroizing<Vec<u8>>,
+    until: Instant,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Wallet {
+    /// DER private key, empty while the wallet is encrypted and locked.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub(crate) private_key: Vec<u8>,
     pub(crate) public_key: Vec<u8>,
 }
 
+impl Drop for Wallet {
+    fn drop(&mut self) {
+        self.private_key.zeroize();
+    }
+}
+
 impl Wallet {
-    pub fn new() -> Self {
-        let curve = EcGroup::from_curve_name( Nid::SECP256K1).unwrap();
+    pub fn new() -> Result<Self> {
+        let curve = EcGroup::from_curve_name( Nid::SECP256K1)?;
 
-        let key = EcKey::generate(&*curve).unwrap();
-        let private_key = key.private_key_to_der().unwrap();
+        let key = EcKey::generate(&curve)?;
+        let private_key = key.private_key_to_der()?;
 
-        let pub_key = EcKey::from_public_key(&*curve, key.public_key()).unwrap();
+        let pub_key = EcKey::from_public_key(&curve, key.public_key())?;
         let pub_key = pub_key.public_key();
 
-        let mut ctx = BigNumContext::new().unwrap();
-        let public_key = pub_key.to_bytes(&*curve, PointConversionForm::COMPRESSED, &mut ctx).unwrap();
+        let mut ctx = BigNumContext::new()?;
+        let public_key = pub_key.to_bytes(&curve, PointConversionForm::COMPRESSED, &mut ctx)?;
 
-        Wallet {
+        Ok(Wallet {
             private_key,
             public_key,
-        }
+        })
     }
 
-    pub fn get_address(&self) -> String {
-        let mut pub_key_hash = Utils::hash_pub_key(&self.public_key);
-        // Add version prefix
-        pub_key_hash.insert(0, version);
+    /// The key pair of the 32-byte private key `secret`.
+    pub fn from_secret(secret: &[u8]) -> Result<Self> {
+        let curve = EcGroup::from_curve_name(Nid::SECP256K1)?;
+        let mut ctx = BigNumContext::new()?;
+        let private_number = BigNum::from_slice(secret)?;
+        let mut public_point = EcPoint::new(&curve)?;
+        public_point.mul_generator2(&curve, &private_number, &mut ctx)?;
+        let key = EcKey::from_private_components(&curve, &private_number, &public_point)?;
 
-        let checksum = Utils::check_sum(&pub_key_hash);
+        Ok(Wallet {
+            private_key: key.private_key_to_der()?,
+            public_key: public_point.to_bytes(&curve, PointConversionForm::COMPRESSED, &mut ctx)?,
+        })
+    }
 
-        let full_payload = checksum.iter().fold(pub_key_hash, |mut acc, x| {
-            acc.push(*x);
-            acc
-        });
-        openssl::base64::encode_block(&full_payload.as_slice())
+    pub fn get_address(&self) -> Result<String> {
+        Ok(Utils::encode_address(&self.hash_pub_key()?))
     }
 
-    pub fn hash_pub_key(&self) -> Vec<u8> {
+    pub fn hash_pub_key(&self) -> Result<Vec<u8>> {
         Utils::hash_pub_key(&self.public_key)
     }
 
@@ -65,30 +113,396 @@ impl Wallet {
     }
 }
 
+/// Seed of a hierarchical deterministic wallet, whose keys are derived along
+/// `m/44'/coin_type'/0'/chain/index`, and the next index of each chain.
+#[derive(Serialize, Deserialize, Clone)]
+struct HdChain {
+    /// Backup words of the seed, empty while the wallet is encrypted and locked.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    mnemonic: String,
+    coin_type: u32,
+    next_receive: u32,
+    next_change: u32,
+}
+
+impl Drop for HdChain {
+    fn drop(&mut self) {
+        self.mnemonic.zeroize();
+    }
+}
+
+/// Key of the first account of the HD wallet of `mnemonic`.
+fn account_key(mnemonic: &str, coin_type: u32) -> Result<ExtendedKey> {
+    ExtendedKey::master(&*mnemonic_seed(mnemonic)?)?
+        .derive_path(&format!("m/{}'/{}'/0'", BIP44_PURPOSE, coin_type))
+}
+
+fn derive_wallet(account: &ExtendedKey, chain: u32, index: u32) -> Result<Wallet> {
+    Wallet::from_secret(account.child(chain)?.child(index)?.secret())
+}
+
+/// What the ciphertext of an encrypted wallet holds.
+#[derive(Serialize, Deserialize, Default)]
+struct Secrets {
+    /// DER private keys by address.
+    keys: HashMap<String, Vec<u8>>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    mnemonic: String,
+}
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        self.keys.values_mut().for_each(Zeroize::zeroize);
+        self.mnemonic.zeroize();
+    }
+}
+
+/// Private keys of an encrypted wallet file, sealed with AES-256-GCM under a
+/// key derived from the passphrase with scrypt.
+#[derive(Serialize, Deserialize, Clone)]
+struct Encryption {
+    salt: Vec<u8>,
+    /// log2 of the scrypt N.
+    cost: u8,
+    nonce: Vec<u8>,
+    tag: Vec<u8>,
+    /// JSON of the `Secrets`.
+    ciphertext: Vec<u8>,
+}
+
+impl Encryption {
+
+    /// Parameters for a new passphrase, with a fresh salt, and the key derived from it.
+    fn new(passphrase: &str, cost: u8) -> Result<(Self, Zeroizing<Vec<u8>>)> {
+        if !KDF_COSTS.contains(&cost) {
+            return Err(Error::Config(format!("the key derivation cost must be between {} and {}",
+                                             KDF_COSTS.start(), KDF_COSTS.end())));
+        }
+        let mut salt = vec![0u8; SALT_LEN];
+        openssl::rand::rand_bytes(&mut salt)?;
+        let encryption = Encryption {
+            salt,
+            cost,
+            nonce: vec![],
+            tag: vec![],
+            ciphertext: vec![],
+        };
+        let key = encryption.derive_key(passphrase)?;
+        Ok((encryption, key))
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
+        let n = 1u64 << self.cost;
+        let max_mem = 128 * KDF_BLOCK_SIZE * (n + KDF_PARALLELISM + 2);
+        let mut key = Zeroizing::new(vec![0u8; 32]);
+        openssl::pkcs5::scrypt(passphrase.as_bytes(), &self.salt, n, KDF_BLOCK_SIZE, KDF_PARALLELISM,
+                               max_mem, &mut key)?;
+        Ok(key)
+    }
+
+    /// The key derivation parameters, authenticated along with the keys.
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = self.salt.clone();
+        aad.push(self.cost);
+        aad
+    }
+
+    fn seal(&mut self, key: &[u8], secrets: &Secrets) -> Result<()> {
+        let plaintext = Zeroizing::new(serde_json::to_vec(secrets)?);
+        let mut nonce = vec![0u8; NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce)?;
+        let mut tag = vec![0u8; TAG_LEN];
+        self.ciphertext = openssl::symm::encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), &self.aad(),
+                                                      &plaintext, &mut tag)?;
+        self.nonce = nonce;
+        self.tag = tag;
+        Ok(())
+    }
+
+    /// The secrets, or `WrongPassphrase` if `key` does not decrypt them.
+    fn open(&self, key: &[u8]) -> Result<Secrets> {
+        let plaintext = openssl::symm::decrypt_aead(Cipher::aes_256_gcm(), key, Some(&self.nonce), &self.aad(),
+                                                    &self.ciphertext, &self.tag)
+            .map(Zeroizing::new)
+            .map_err(|_| Error::WrongPassphrase)?;
+        match serde_json::from_slice(&plaintext) {
+            Ok(secrets) => Ok(secrets),
+            // Sealed before HD wallets, a bare map of the keys
+            Err(_) => Ok(Secrets {
+                keys: serde_json::from_slice(&plaintext)?,
+                mnemonic: String::new(),
+            }),
+        }
+    }
+}
+
+/// The wallets of the wallet file, whose private keys may be encrypted with a
+/// passphrase. An encrypted wallet is locked, its private keys unreadable,
+/// until `unlock` is called and again once the unlock times out or `lock`
+/// is called.
 #[derive(Serialize, Deserialize)]
 pub struct Wallets {
-    wallets: HashMap<String, Wallet>
+    wallets: HashMap<String, Wallet>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<Encryption>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hd: Option<HdChain>,
+    /// Key of `encryption` and until when the private keys may be used.
+    #[serde(skip)]
+    unlocked: Option<(Zeroizing<Vec<u8>>, Instant)>,
 }
 
 impl Wallets {
-    pub fn new() -> Self {
-        let mut wallets = if let Some(wallets) = Wallets::load_from_file() {
+    pub fn new() -> Result<Self> {
+        let wallets = if let Some(wallets) = Wallets::load_from_file()? {
             wallets
         } else {
             Wallets {
-                wallets: HashMap::new()
+                wallets: HashMap::new(),
+                encryption: None,
+                hd: None,
+                unlocked: None,
             }
         };
-        println!("wallets size: {}", wallets.wallets.len());
-        wallets
+        Ok(wallets)
     }
 
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
-        let address = wallet.get_address();
+    /// A new key, the next receive key of an HD wallet or else a random one.
+    pub fn create_wallet(&mut self) -> Result<String> {
+        if self.hd.is_some() {
+            return self.derive_next(RECEIVE_CHAIN);
+        }
+        let wallet = Wallet::new()?;
+        let address = wallet.get_address()?;
         self.wallets.insert(address.clone(), wallet);
-        println!("create a new wallets, its' address: {}; its' size: {}", address, self.wallets.len());
-        address
+        if let Err(e) = self.reseal() {
+            self.wallets.remove(&address);
+            return Err(e);
+        }
+        Ok(address)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// Whether new keys are derived from a seed.
+    pub fn is_hd(&self) -> bool {
+        self.hd.is_some()
+    }
+
+    /// Makes the wallet derive its new keys from a fresh mnemonic of `words`
+    /// words, returned for backup along with the first receive address.
+    pub fn create_hd(&mut self, words: usize) -> Result<(Zeroizing<String>, String)> {
+        let mnemonic = generate_mnemonic(words)?;
+        let address = self.restore_hd(&mnemonic, 0, |_| false)?.remove(0);
+        Ok((mnemonic, address))
+    }
+
+    /// Makes the wallet derive its keys from the mnemonic `phrase` and restores
+    /// the keys of each chain up to the last one whose public key hash
+    /// `is_used`, looking `gap` keys past it. Returns the restored addresses,
+    /// at least the first receive one.
+    pub fn restore_hd<F>(&mut self, phrase: &str, gap: u32, is_used: F) -> Result<Vec<String>>
+        where F: Fn(&[u8]) -> bool
+    {
+        if self.hd.is_some() {
+            return Err(Error::HdWalletExists);
+        }
+        if self.is_locked() {
+            return Err(Error::WalletLocked);
+        }
+        let mnemonic = parse_mnemonic(phrase)?;
+        let coin_type = chain_params().hd_coin_type;
+        let account = account_key(&mnemonic, coin_type)?;
+
+        let mut chains = Vec::new();
+        for chain in &[RECEIVE_CHAIN, CHANGE_CHAIN] {
+            let mut wallets = Vec::new();
+            let mut next: u32 = 0;
+            loop {
+                let end = next.checked_add(gap).filter(|end| *end <= HARDENED).ok_or_else(|| {
+                    Error::InvalidDerivation(format!("a gap of {} keys runs past the last key index", gap))
+                })?;
+                if wallets.len() >= end as usize {
+                    break;
+                }
+                let wallet = derive_wallet(&account, *chain, wallets.len() as u32)?;
+                if is_used(&wallet.hash_pub_key()?) {
+                    next = wallets.len() as u32 + 1;
+                }
+                wallets.push(wallet);
+            }
+            wallets.truncate(next as usize);
+            chains.push(wallets);
+        }
+        if chains[0].is_empty() {
+            chains[0].push(derive_wallet(&account, RECEIVE_CHAIN, 0)?);
+        }
+
+        let mut addresses = Vec::new();
+        for wallet in chains.iter().flatten() {
+            let address = wallet.get_address()?;
+            self.wallets.insert(address.clone(), wallet.clone());
+            addresses.push(address);
+        }
+        self.hd = Some(HdChain {
+            mnemonic: mnemonic.to_string(),
+            coin_type,
+            next_receive: chains[0].len() as u32,
+            next_change: chains[1].len() as u32,
+        });
+        self.reseal()?;
+        Ok(addresses)
+    }
+
+    /// Derives the next key of `chain` of an HD wallet.
+    fn derive_next(&mut self, chain: u32) -> Result<String> {
+        if self.is_locked() {
+            return Err(Error::WalletLocked);
+        }
+        let hd = self.hd.as_mut().ok_or_else(|| Error::InvalidDerivation("the wallet has no seed".to_string()))?;
+        let next = if chain == RECEIVE_CHAIN { &mut hd.next_receive } else { &mut hd.next_change };
+        let wallet = derive_wallet(&account_key(&hd.mnemonic, hd.coin_type)?, chain, *next)?;
+        *next += 1;
+        let address = wallet.get_address()?;
+        self.wallets.insert(address.clone(), wallet);
+        self.reseal()?;
+        Ok(address)
+    }
+
+    /// Address the change of a payment from `from` returns to: a new change
+    /// key of an HD wallet, `from` itself otherwise.
+    pub fn change_address(&mut self, from: &str) -> Result<String> {
+        match self.hd {
+            Some(_) => self.derive_next(CHANGE_CHAIN),
+            None => Ok(from.to_string()),
+        }
+    }
+
+    /// Whether the private keys are unreadable, the wallet being encrypted and
+    /// not unlocked, or unlocked for a time that has run out.
+    pub fn is_locked(&self) -> bool {
+        self.encryption.is_some() && !matches!(&self.unlocked, Some((_, until)) if Instant::now() < *until)
+    }
+
+    /// Encrypts the private keys with a key derived from `passphrase` at `cost`,
+    /// leaving the wallet locked.
+    pub fn encrypt(&mut self, passphrase: &str, cost: u8) -> Result<()> {
+        if self.encryption.is_some() {
+            return Err(Error::WalletEncrypted);
+        }
+        let (encryption, key) = Encryption::new(passphrase, cost)?;
+        self.encryption = Some(encryption);
+        self.unlocked = Some((key, Instant::now()));
+        self.reseal()?;
+        self.lock();
+        Ok(())
+    }
+
+    /// Decrypts the private keys with `passphrase`, for `timeout`. Wallets
+    /// loaded by the process until then are unlocked as well.
+    pub fn unlock(&mut self, passphrase: &str, timeout: Duration) -> Result<()> {
+        let encryption = self.encryption.as_ref().ok_or(Error::WalletNotEncrypted)?;
+        let salt = encryption.salt.clone();
+        let key = encryption.derive_key(passphrase)?;
+        let until = Instant::now() + timeout;
+        self.unlock_with(key.clone(), until)?;
+
+        let mut cache = UNLOCKED_KEYS.lock().unwrap_or_else(|e| e.into_inner());
+        cache.retain(|cached| cached.salt != salt && Instant::now() < cached.until);
+        cache.push(UnlockedKey {
+            salt,
+            key,
+            until,
+        });
+        Ok(())
+    }
+
+    fn unlock_with(&mut self, key: Zeroizing<Vec<u8>>, until: Instant) -> Result<()> {
+        let encryption = self.encryption.as_ref().ok_or(Error::WalletNotEncrypted)?;
+        let mut secrets = encryption.open(&key)?;
+        for (address, private_key) in secrets.keys.iter_mut() {
+            // Sealed before Base58Check addresses, keyed by base64 ones
+            let wallet = Utils::canonical_address(address).ok()
+                .and_then(|address| self.wallets.get_mut(&address));
+            if let Some(wallet) = wallet {
+                wallet.private_key = std::mem::take(private_key);
+            }
+        }
+        if let Some(hd) = self.hd.as_mut() {
+            hd.mnemonic = std::mem::take(&mut secrets.mnemonic);
+        }
+        self.unlocked = Some((key, until));
+        Ok(())
+    }
+
+    /// Wipes the private keys of an encrypted wallet from memory, here and for
+    /// the wallets loaded later in the process.
+    pub fn lock(&mut self) {
+        if self.encryption.is_none() {
+            return;
+        }
+        self.wallets.values_mut().for_each(|wallet| wallet.private_key.zeroize());
+        if let Some(hd) = self.hd.as_mut() {
+            hd.mnemonic.zeroize();
+        }
+        self.unlocked = None;
+        if let Some(encryption) = &self.encryption {
+            UNLOCKED_KEYS.lock().unwrap_or_else(|e| e.into_inner())
+                .retain(|cached| cached.salt != encryption.salt);
+        }
+    }
+
+    /// Re-encrypts the private keys with `new`, after checking `old`.
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<()> {
+        let cost = self.encryption.as_ref().ok_or(Error::WalletNotEncrypted)?.cost;
+        self.unlock(old, Duration::from_secs(0))?;
+        let (encryption, key) = Encryption::new(new, cost)?;
+        self.encryption = Some(encryption);
+        self.unlocked = Some((key, Instant::now()));
+        self.reseal()?;
+        self.lock();
+        Ok(())
+    }
+
+    /// Seals the private keys and the mnemonic again after a change, which an
+    /// encrypted wallet only allows while its key is known.
+    fn reseal(&mut self) -> Result<()> {
+        let encryption = match self.encryption.as_mut() {
+            Some(encryption) => encryption,
+            None => return Ok(()),
+        };
+        let key = match &self.unlocked {
+            Some((key, _)) => key,
+            None => return Err(Error::WalletLocked),
+        };
+        let secrets = Secrets {
+            keys: self.wallets.iter()
+                .map(|(address, wallet)| (address.clone(), wallet.private_key.clone()))
+                .collect(),
+            mnemonic: self.hd.as_ref().map(|hd| hd.mnemonic.clone()).unwrap_or_default(),
+        };
+        encryption.seal(key, &secrets)
+    }
+
+    /// Private key of `address`, to sign with.
+    pub fn signing_key(&self, address: &str) -> Result<&[u8]> {
+        let wallet = self.get_wallet(address)
+            .ok_or_else(|| Error::MissingWallet(address.to_string()))?;
+        if self.is_locked() {
+            return Err(Error::WalletLocked);
+        }
+        Ok(&wallet.private_key)
+    }
+
+    /// Private key matching `public_key`, if a wallet holds it.
+    pub fn signing_key_of(&self, public_key: &[u8]) -> Result<Option<&[u8]>> {
+        match self.wallets.values().find(|wallet| wallet.public_key == public_key) {
+            Some(_) if self.is_locked() => Err(Error::WalletLocked),
+            Some(wallet) => Ok(Some(&wallet.private_key)),
+            None => Ok(None),
+        }
     }
 
     pub fn get_address(&self) -> Vec<String> {
@@ -99,29 +513,228 @@ impl Wallets {
         address
     }
 
+    /// The wallet of `address`, also found by its legacy base64 form.
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
-        self.wallets.get(&address.to_string())
+        match self.wallets.get(address) {
+            Some(wallet) => Some(wallet),
+            None => self.wallets.get(&Utils::canonical_address(address).ok()?),
+        }
+    }
+
+    /// Writes the wallet file through a temporary file moved over it, so
+    /// that a crash halfway leaves the previous file in place.
+    pub fn save_to_file(&self) -> Result<()> {
+        let path = Wallets::path();
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut buf_writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut buf_writer, &self.stored())?;
+        let file = buf_writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// What is written to the wallet file: an encrypted wallet leaves its
+    /// private keys and mnemonic out, they are in `encryption`.
+    fn stored(&self) -> Wallets {
+        let wallets = self.wallets.iter()
+            .map(|(address, wallet)| {
+                let private_key = match self.encryption {
+                    Some(_) => vec![],
+                    None => wallet.private_key.clone(),
+                };
+                (address.clone(), Wallet { private_key, public_key: wallet.public_key.clone() })
+            })
+            .collect();
+        let mut hd = self.hd.clone();
+        if let (Some(hd), Some(_)) = (hd.as_mut(), &self.encryption) {
+            hd.mnemonic.zeroize();
+        }
+        Wallets {
+            wallets,
+            encryption: self.encryption.clone(),
+            hd,
+            unlocked: None,
+        }
     }
 
-    pub fn save_to_file(&self) {
-        let path = Path::new(wallet_file);
-        if path.exists() {
-            std::fs::remove_file(path);
+    /// Keys the wallets by their address, files written before Base58Check
+    /// addresses keying them by base64 ones.
+    fn rekey(&mut self) -> Result<()> {
+        let wallets = std::mem::take(&mut self.wallets);
+        for (_, wallet) in wallets {
+            self.wallets.insert(wallet.get_address()?, wallet);
+        }
+        Ok(())
+    }
+
+    /// Unlocks a freshly loaded wallet if the process unlocked its file before.
+    fn unlock_from_cache(&mut self) -> Result<()> {
+        let encryption = match &self.encryption {
+            Some(encryption) => encryption,
+            None => return Ok(()),
+        };
+        let cached = UNLOCKED_KEYS.lock().unwrap_or_else(|e| e.into_inner()).iter()
+            .find(|cached| cached.salt == encryption.salt && Instant::now() < cached.until)
+            .map(|cached| (cached.key.clone(), cached.until));
+        match cached {
+            Some((key, until)) => self.unlock_with(key, until),
+            None => Ok(()),
         }
-        let file = OpenOptions::new().create(true).write(true).open(wallet_file).unwrap();
-        let mut buf_writer = BufWriter::new(file);
-        serde_json::to_writer(buf_writer, &serde_json::to_value(self).unwrap()).unwrap();
     }
 
-    fn load_from_file() -> Option<Self> {
-        if let Ok(file) = File::open(wallet_file) {
-            let mut buf_reader = BufReader::new(file);
-            if let Ok(res) = serde_json::from_reader(buf_reader) {
-                return Some(res);
+    /// The configured wallet file, `wallet.dat` of the running network by default.
+    pub fn path() -> PathBuf {
+        selected_wallet().map_or_else(|| data_path(WALLET_FILE), data_path)
+    }
+
+    fn load_from_file() -> Result<Option<Self>> {
+        match File::open(Wallets::path()) {
+            Ok(file) => {
+                let buf_reader = BufReader::new(file);
+                let mut wallets: Wallets = serde_json::from_reader(buf_reader)?;
+                wallets.rekey()?;
+                wallets.unlock_from_cache()?;
+                Ok(Some(wallets))
             }
-            None
-        } else {
-            None
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_wallets() -> Wallets {
+        Wallets {
+            wallets: HashMap::new(),
+            encryption: None,
+            hd: None,
+            unlocked: None,
         }
     }
-}
\ No newline at end of file
+
+    /// `wallets` as written to the wallet file and read back.
+    fn reload(wallets: &Wallets) -> (String, Wallets) {
+        let json = serde_json::to_string(&wallets.stored()).unwrap();
+        let mut reloaded: Wallets = serde_json::from_str(&json).unwrap();
+        reloaded.unlock_from_cache().unwrap();
+        (json, reloaded)
+    }
+
+    #[test]
+    fn test_encrypt_wallet() {
+        let mut wallets = empty_wallets();
+        let address = wallets.create_wallet().unwrap();
+        let private_key = wallets.signing_key(&address).unwrap().to_vec();
+
+        wallets.encrypt("correct horse", *KDF_COSTS.start()).unwrap();
+        assert!(wallets.is_locked());
+        assert!(matches!(wallets.encrypt("again", *KDF_COSTS.start()), Err(Error::WalletEncrypted)));
+        assert!(matches!(wallets.create_wallet(), Err(Error::WalletLocked)));
+
+        let (json, mut wallets) = reload(&wallets);
+        assert!(!json.contains("private_key"));
+        assert!(wallets.is_locked());
+        assert!(matches!(wallets.signing_key(&address), Err(Error::WalletLocked)));
+        assert!(matches!(wallets.signing_key_of(&wallets.wallets[&address].public_key.clone()),
+                         Err(Error::WalletLocked)));
+        assert!(matches!(wallets.unlock("wrong horse", Duration::from_secs(60)), Err(Error::WrongPassphrase)));
+
+        wallets.unlock("correct horse", Duration::from_secs(60)).unwrap();
+        assert_eq!(wallets.signing_key(&address).unwrap(), &private_key[..]);
+        let second = wallets.create_wallet().unwrap();
+
+        // Loaded again while the file is unlocked
+        let (_, reloaded) = reload(&wallets);
+        assert_eq!(reloaded.signing_key(&second).unwrap(), wallets.signing_key(&second).unwrap());
+
+        wallets.lock();
+        assert!(matches!(wallets.signing_key(&address), Err(Error::WalletLocked)));
+        let (_, reloaded) = reload(&wallets);
+        assert!(reloaded.is_locked());
+    }
+
+    #[test]
+    fn test_unlock_timeout() {
+        let mut wallets = empty_wallets();
+        let address = wallets.create_wallet().unwrap();
+        wallets.encrypt("old", *KDF_COSTS.start()).unwrap();
+
+        wallets.unlock("old", Duration::from_millis(50)).unwrap();
+        assert!(wallets.signing_key(&address).is_ok());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(matches!(wallets.signing_key(&address), Err(Error::WalletLocked)));
+
+        assert!(matches!(wallets.change_passphrase("new", "newer"), Err(Error::WrongPassphrase)));
+        wallets.change_passphrase("old", "new").unwrap();
+        assert!(wallets.is_locked());
+        assert!(matches!(wallets.unlock("old", Duration::from_secs(60)), Err(Error::WrongPassphrase)));
+        wallets.unlock("new", Duration::from_secs(60)).unwrap();
+        assert!(wallets.signing_key(&address).is_ok());
+
+        assert!(matches!(empty_wallets().unlock("new", Duration::from_secs(60)),
+                         Err(Error::WalletNotEncrypted)));
+        assert!(matches!(Encryption::new("x", KDF_COSTS.end() + 1), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_hd_wallet() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let mut wallets = empty_wallets();
+        let first = wallets.restore_hd(phrase, 0, |_| false).unwrap();
+        assert_eq!(first.len(), 1);
+        // m/44'/0'/0'/0/0
+        assert_eq!(hex::encode(&wallets.wallets[&first[0]].public_key),
+                   "03aaeb52dd7494c361049de67cc680e83ebcbbbdbeb13637d92cd845f70308af5e");
+        let second = wallets.create_wallet().unwrap();
+        let change = wallets.change_address(&second).unwrap();
+        assert!(matches!(wallets.restore_hd(phrase, 20, |_| false), Err(Error::HdWalletExists)));
+
+        let account = account_key(phrase, chain_params().hd_coin_type).unwrap();
+        let used = [
+            derive_wallet(&account, RECEIVE_CHAIN, 5).unwrap().hash_pub_key().unwrap(),
+            derive_wallet(&account, CHANGE_CHAIN, 0).unwrap().hash_pub_key().unwrap(),
+        ];
+        let is_used = |pub_key_hash: &[u8]| used.iter().any(|used| used == pub_key_hash);
+        assert_eq!(empty_wallets().restore_hd(phrase, 5, is_used).unwrap().len(), 2);
+
+        let mut restored = empty_wallets();
+        let addresses = restored.restore_hd(phrase, 6, is_used).unwrap();
+        assert_eq!(addresses.len(), 7);
+        assert!(addresses.contains(&first[0]) && addresses.contains(&second) && addresses.contains(&change));
+        let next_receive = derive_wallet(&account, RECEIVE_CHAIN, 6).unwrap().get_address().unwrap();
+
+        restored.encrypt("correct horse", *KDF_COSTS.start()).unwrap();
+        let (json, mut reloaded) = reload(&restored);
+        assert!(!json.contains("abandon"));
+        assert!(matches!(reloaded.create_wallet(), Err(Error::WalletLocked)));
+        reloaded.unlock("correct horse", Duration::from_secs(60)).unwrap();
+        assert_eq!(reloaded.create_wallet().unwrap(), next_receive);
+        assert!(matches!(empty_wallets().restore_hd("abandon about", 20, is_used), Err(Error::InvalidMnemonic(_))));
+        assert!(matches!(empty_wallets().restore_hd(phrase, u32::MAX, is_used), Err(Error::InvalidDerivation(_))));
+    }
+
+    #[test]
+    fn test_save_to_file() {
+        let _dir = crate::chain_params::TestDir::new();
+        let mut wallets = empty_wallets();
+        let first = wallets.create_wallet().unwrap();
+        wallets.save_to_file().unwrap();
+        let second = wallets.create_wallet().unwrap();
+        wallets.save_to_file().unwrap();
+
+        let entries: Vec<_> = read_dir(Wallets::path().parent().unwrap()).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec![WALLET_FILE]);
+        let loaded = Wallets::load_from_file().unwrap().unwrap();
+        assert_eq!(loaded.signing_key(&first).unwrap(), wallets.signing_key(&first).unwrap());
+        assert_eq!(loaded.signing_key(&second).unwrap(), wallets.signing_key(&second).unwrap());
+    }
+}
@@ -0,0 +1,119 @@
+//! Nodes on localhost ports, each running the command line interface in its
+//! own working directory.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn cli(dir: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_BlockChainRust"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+fn create_wallet(dir: &Path) -> String {
+    let output = cli(dir, &["--create-wallet"]);
+    String::from_utf8_lossy(&output.stdout).lines()
+        .find_map(|line| line.strip_prefix("Your new wallet address: ").map(str::to_string))
+        .unwrap()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// A running node, killed when dropped, and the lines it logs.
+struct Node {
+    child: Child,
+    lines: Receiver<String>,
+    log: Vec<String>,
+}
+
+impl Node {
+
+    fn start(dir: &Path, port: u16, peers: &[u16]) -> Self {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_BlockChainRust"));
+        command.current_dir(dir).args(["start-node", "--port", &port.to_string()]);
+        for peer in peers {
+            command.args(["--peer", &format!("127.0.0.1:{}", peer)]);
+        }
+        let mut child = command.stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap();
+
+        let (sender, lines) = mpsc::channel();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        thread::spawn(move || {
+            for line in stdout.lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut node = Node { child, lines, log: Vec::new() };
+        node.wait_for(1, "Node listening");
+        node
+    }
+
+    /// Waits until `count` logged lines contain `text`.
+    fn wait_for(&mut self, count: usize, text: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        while self.log.iter().filter(|line| line.contains(text)).count() < count {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(left) {
+                Ok(line) => self.log.push(line),
+                Err(_) => panic!("no {} lines with '{}' in:\n{}", count, text, self.log.join("\n")),
+            }
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn node_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("BlockChainRust-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_nodes_sync() {
+    let (first_dir, second_dir) = (node_dir("first"), node_dir("second"));
+    let address = create_wallet(&first_dir);
+    cli(&first_dir, &["create-block-chain", "--address", &address]);
+    for _ in 0..3 {
+        cli(&first_dir, &["mine", "--address", &address]);
+    }
+
+    let first_port = free_port();
+    let mut first = Node::start(&first_dir, first_port, &[]);
+    let mut second = Node::start(&second_dir, free_port(), &[first_port]);
+    second.wait_for(4, "Added block");
+
+    // A message over the size limit is dropped without being read whole
+    if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", first_port)) {
+        let _ = stream.write_all(&vec![0u8; 33 * 1024 * 1024]);
+    }
+    first.wait_for(1, "longer than");
+
+    drop((first, second));
+    let output = cli(&second_dir, &["verify-chain"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("4 blocks verified"));
+
+    std::fs::remove_dir_all(first_dir).unwrap();
+    std::fs::remove_dir_all(second_dir).unwrap();
+}